@@ -0,0 +1,63 @@
+//! Times scanning a heavily fragmented file, comparing
+//! [`scan_chunks`](SparseFile::scan_chunks), which uses FIEMAP where it can,
+//! against seeking from each hole to the next piece of data and back
+//!
+//! Run with `cargo bench --bench fragmented`, prefix it with
+//! `strace -c -e trace=lseek,ioctl` to count the syscalls.
+use drill_press::*;
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+const BLOCK_SIZE: u64 = 4096;
+/// Every other block is data, so this many data segments
+const FRAGMENTS: u64 = 10_000;
+const RUNS: u32 = 20;
+
+/// Builds the layout one seek at a time, two seeks for every segment
+fn scan_by_seeking(file: &mut File, len: u64) -> Vec<Range<u64>> {
+    let mut data = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = file.next_data(offset).expect("seeked") {
+        if start >= len {
+            break;
+        }
+        let end = file.next_hole(start).expect("seeked").unwrap_or(len);
+        data.push(start..end);
+        offset = end;
+    }
+    data
+}
+
+fn time<T>(mut f: impl FnMut() -> T) -> (Duration, T) {
+    let start = Instant::now();
+    let mut result = f();
+    for _ in 1..RUNS {
+        result = f();
+    }
+    (start.elapsed() / RUNS, result)
+}
+
+fn main() {
+    let mut file = tempfile::tempfile().expect("Unable to create a file");
+    for i in 0..FRAGMENTS {
+        file.seek(SeekFrom::Start(2 * i * BLOCK_SIZE))
+            .expect("Unable to seek");
+        file.write_all(&[1; BLOCK_SIZE as usize])
+            .expect("Unable to write");
+    }
+    let len = 2 * FRAGMENTS * BLOCK_SIZE;
+    file.set_len(len).expect("Unable to set length");
+    file.sync_all().expect("Unable to sync");
+
+    let (scanned, segments) = time(|| file.scan_chunks().expect("scanned"));
+    let (seeked, data) = time(|| scan_by_seeking(&mut file, len));
+    let scanned_data: Vec<_> = segments.data().cloned().collect();
+    assert_eq!(scanned_data, data, "the two scans disagree");
+
+    println!("{} data segments", data.len());
+    println!("scan_chunks: {:?} per scan", scanned);
+    println!("seeking:     {:?} per scan", seeked);
+}
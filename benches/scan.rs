@@ -0,0 +1,145 @@
+//! Benchmarks of scanning files of different amounts of fragmentation with
+//! each of the scans, and of the pure functions that build and combine
+//! layouts, to catch regressions
+//!
+//! Run with `cargo bench --bench scan --features test-fixtures`. The scans
+//! report segments per second, and [`scan_mmap`] bytes per second. Criterion
+//! can not count syscalls, run a single scan under
+//! `strace -c -e trace=lseek,ioctl` for that, with
+//! `cargo bench --bench scan --features test-fixtures -- --profile-time 1 'scan_chunks/10000'`.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use drill_press::fixtures::{SparseDescription, BLOCK_SIZE};
+use drill_press::*;
+
+/// How many data segments the files have, from barely fragmented to very
+const FRAGMENTS: [u64; 3] = [10, 1_000, 10_000];
+
+/// A layout of `fragments` data segments of `data_blocks` blocks, with a
+/// hole of `hole_blocks` blocks before each of them and one at the end
+fn fragmented(fragments: u64, data_blocks: u64, hole_blocks: u64) -> SparseDescription {
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    for _ in 0..fragments {
+        let data = offset + hole_blocks * BLOCK_SIZE;
+        let end = data + data_blocks * BLOCK_SIZE;
+        segments.push(Segment::hole(offset, data));
+        segments.push(Segment::data(data, end));
+        offset = end;
+    }
+    segments.push(Segment::hole(offset, offset + hole_blocks * BLOCK_SIZE));
+    SparseDescription::from_segments(segments)
+}
+
+fn scans(c: &mut Criterion) {
+    for (name, hole_blocks) in [("scan_chunks", 1), ("scan_chunks_wide_holes", 64)] {
+        let mut group = c.benchmark_group(name);
+        for fragments in FRAGMENTS {
+            let desc = fragmented(fragments, 1, hole_blocks);
+            let mut file = desc.to_file();
+            let file = file.as_file_mut();
+            group.throughput(Throughput::Elements(desc.segments().len() as u64));
+            group.bench_with_input(BenchmarkId::from_parameter(fragments), &(), |b, ()| {
+                b.iter(|| file.scan_chunks().expect("Unable to scan"))
+            });
+        }
+        group.finish();
+    }
+
+    let mut group = c.benchmark_group("segments");
+    for fragments in FRAGMENTS {
+        let desc = fragmented(fragments, 1, 1);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        group.throughput(Throughput::Elements(desc.segments().len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(fragments), &(), |b, ()| {
+            b.iter(|| file.segments().expect("Unable to scan").count())
+        });
+    }
+    group.finish();
+
+    // Reading is far slower, so only the smaller files
+    let mut group = c.benchmark_group("scan_chunks_by_reading");
+    group.sample_size(10);
+    for fragments in &FRAGMENTS[..2] {
+        let desc = fragmented(*fragments, 1, 1);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        group.throughput(Throughput::Elements(desc.segments().len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(fragments), &(), |b, ()| {
+            b.iter(|| {
+                file.scan_chunks_by_reading(BLOCK_SIZE)
+                    .expect("Unable to scan")
+            })
+        });
+    }
+    group.finish();
+}
+
+fn assembly(c: &mut Criterion) {
+    let mut group = c.benchmark_group("assembly");
+    for fragments in FRAGMENTS {
+        let segments = fragmented(fragments, 2, 1).segments();
+        group.throughput(Throughput::Elements(segments.len() as u64));
+
+        // Every block its own segment, as a read scan might find them
+        let blocks: Vec<_> = segments
+            .iter()
+            .flat_map(|x| {
+                (x.range.start..x.range.end)
+                    .step_by(BLOCK_SIZE as usize)
+                    .map(|start| Segment::new(x.segment_type, start, start + BLOCK_SIZE))
+            })
+            .collect();
+        group.bench_with_input(
+            BenchmarkId::new("coalesce", fragments),
+            &blocks,
+            |b, blocks| b.iter(|| coalesce(black_box(blocks))),
+        );
+
+        // Out of order, as a map built from extents in any order would be
+        let mut shuffled = segments.clone();
+        shuffled.reverse();
+        group.bench_with_input(
+            BenchmarkId::new("collect_map", fragments),
+            &shuffled,
+            |b, shuffled| b.iter(|| black_box(shuffled).iter().cloned().collect::<SparseMap>()),
+        );
+
+        let map = SparseMap::from(segments.clone());
+        let other = map.shift(BLOCK_SIZE as i64).expect("Unable to shift");
+        group.bench_with_input(
+            BenchmarkId::new("union", fragments),
+            &(map, other),
+            |b, (map, other)| b.iter(|| black_box(map).union(black_box(other))),
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("validate", fragments),
+            &segments,
+            |b, segments| {
+                let len = segments.last().map_or(0, |x| x.range.end);
+                b.iter(|| validate(black_box(segments), len))
+            },
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("scan_mmap");
+    for fragments in &FRAGMENTS[..2] {
+        let desc = fragmented(*fragments, 1, 1);
+        let mut contents = vec![0_u8; desc.len() as usize];
+        for range in desc.segments().data() {
+            contents[range.start as usize..range.end as usize].fill(1);
+        }
+        group.throughput(Throughput::Bytes(desc.len()));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(fragments),
+            &contents,
+            |b, contents| b.iter(|| scan_mmap(black_box(contents), BLOCK_SIZE)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scans, assembly);
+criterion_main!(benches);
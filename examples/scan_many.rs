@@ -0,0 +1,24 @@
+//! Scans every file given on the command line at the same time
+//!
+//! Run with `cargo run --example scan_many --features tokio -- <files>`
+use drill_press::*;
+
+use futures::future::join_all;
+use std::env;
+
+#[tokio::main]
+async fn main() {
+    let paths: Vec<String> = env::args().skip(1).collect();
+    let scans = join_all(paths.iter().map(scan_chunks_async)).await;
+    for (path, scan) in paths.iter().zip(scans) {
+        match scan {
+            Ok(segments) => println!(
+                "{}: {} segments, {} bytes allocated",
+                path,
+                segments.len(),
+                allocated_bytes(&segments)
+            ),
+            Err(e) => println!("{}: {}", path, e),
+        }
+    }
+}
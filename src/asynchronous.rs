@@ -0,0 +1,20 @@
+//! Scanning from async code without blocking the tokio runtime
+use super::*;
+
+/// Opens the file at `path` and scans it, like [`scan_path`], on tokio's
+/// blocking thread pool
+///
+/// The syscalls that find the holes can not be made async, so they are run
+/// with [`tokio::task::spawn_blocking`], leaving the runtime free to get on
+/// with other work, such as scanning more files at the same time.
+///
+/// # Errors
+///
+/// Will return `Err` under the same conditions as [`scan_path`]. If the
+/// blocking task panics or is cancelled that is returned as an IO error.
+pub async fn scan_chunks_async(path: impl AsRef<Path>) -> Result<Vec<Segment>, ScanError> {
+    let path = path.as_ref().to_owned();
+    tokio::task::spawn_blocking(move || scan_path(path))
+        .await
+        .map_err(std::io::Error::from)?
+}
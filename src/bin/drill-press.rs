@@ -0,0 +1,55 @@
+use drill_press::*;
+
+use clap::Parser;
+use std::error::Error;
+use std::path::PathBuf;
+
+/// Lists the holes and data in a sparse file
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// The file to scan
+    path: PathBuf,
+    /// Print the segments as JSON instead of a table
+    #[arg(long)]
+    json: bool,
+    /// Only list segments of this type, hole, data or unwritten
+    #[arg(long, value_name = "TYPE")]
+    only: Option<SegmentType>,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let segments = scan_path(&args.path)?;
+    let listed: Vec<&Segment> = segments
+        .iter()
+        .filter(|x| args.only.map_or(true, |only| x.segment_type == only))
+        .collect();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&listed)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<10} {:>20} {:>20} {:>20}",
+        "type", "start", "end", "length"
+    );
+    for segment in listed {
+        println!(
+            "{:<10} {:>20} {:>20} {:>20}",
+            segment.segment_type,
+            segment.start(),
+            segment.range.end,
+            segment.len()
+        );
+    }
+
+    let total = segments.last().map_or(0, |x| x.range.end);
+    println!();
+    println!("total size:      {}", total);
+    println!("allocated bytes: {}", allocated_bytes(&segments));
+    println!("sparsity:        {:.1}%", sparsity(&segments) * 100.0);
+
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! Caching the layouts of files by path, so that asking about the same file
+//! again does not scan it again unless it has changed
+use super::*;
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::hash::{DefaultHasher, Hasher};
+use std::time::SystemTime;
+
+/// Scans files by path, keeping the layout of each one until the file
+/// changes
+///
+/// A file is only scanned again if its modification time or its length is
+/// different from when it was last scanned. That can miss changes:
+///
+/// * Filesystems only keep the modification time so precisely, as coarsely
+///   as two seconds on FAT, so a write soon after a scan may not change it.
+/// * Writes that do not change the length, and set the modification time
+///   back, for example by restoring a backup, are not noticed.
+/// * The file can change between checking it and the layout being used, so
+///   a layout from here is only ever as good as a fresh scan.
+///
+/// For callers that can not live with the first two,
+/// [`set_hash_contents`](CachedScanner::set_hash_contents) also checks a
+/// hash of the whole contents of the file. That reads the whole file every
+/// time it is asked about, which is usually much slower than scanning it
+/// again.
+///
+/// The file is checked before it is scanned, so if it changes during the
+/// scan it is scanned again the next time it is asked about.
+#[derive(Debug, Default)]
+pub struct CachedScanner {
+    entries: HashMap<PathBuf, CachedScan>,
+    hash_contents: bool,
+}
+
+/// A layout, and what the file looked like when it was scanned
+#[derive(Debug)]
+struct CachedScan {
+    stamp: Stamp,
+    segments: Vec<Segment>,
+}
+
+/// What is compared to tell if a file has changed
+#[derive(Debug, PartialEq, Eq)]
+struct Stamp {
+    /// Not every platform keeps modification times
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: Option<u64>,
+}
+
+impl CachedScanner {
+    /// Creates an empty cache, that does not hash the contents of files
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to check a hash of the contents of each file, as well as its
+    /// modification time and length, before using its cached layout
+    ///
+    /// Layouts that were cached without a hash are scanned again when this is
+    /// turned on.
+    pub fn set_hash_contents(&mut self, hash_contents: bool) {
+        self.hash_contents = hash_contents;
+    }
+
+    /// The layout of the file at `path`, scanning it if it has not been
+    /// scanned before or if it has changed since
+    ///
+    /// The file is opened as it is by [`scan_path`].
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::IO)` if the file can not be opened, or its
+    /// metadata or contents can not be read
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks), the cached layout is
+    /// forgotten if the scan fails
+    pub fn get(&mut self, path: impl AsRef<Path>) -> Result<&[Segment], ScanError> {
+        let path = path.as_ref();
+        let mut file = scan_open_options().open(path)?;
+        let metadata = file.metadata()?;
+        let stamp = Stamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            hash: if self.hash_contents {
+                Some(hash_contents(&mut file)?)
+            } else {
+                None
+            },
+        };
+
+        let entry = match self.entries.entry(path.to_owned()) {
+            Entry::Occupied(entry) if entry.get().stamp == stamp => entry.into_mut(),
+            entry => match file.scan_chunks() {
+                Ok(segments) => {
+                    let scan = CachedScan { stamp, segments };
+                    match entry {
+                        Entry::Occupied(mut entry) => {
+                            entry.insert(scan);
+                            entry.into_mut()
+                        }
+                        Entry::Vacant(entry) => entry.insert(scan),
+                    }
+                }
+                Err(e) => {
+                    if let Entry::Occupied(entry) = entry {
+                        entry.remove();
+                    }
+                    return Err(e);
+                }
+            },
+        };
+        Ok(&entry.segments)
+    }
+
+    /// Forgets the layout of the file at `path`
+    pub fn remove(&mut self, path: impl AsRef<Path>) {
+        self.entries.remove(path.as_ref());
+    }
+
+    /// Forgets every layout
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Hashes the whole contents of `file`, putting the seek position back
+fn hash_contents(file: &mut std::fs::File) -> Result<u64, ScanError> {
+    keep_position(file, |file| {
+        file.seek(SeekFrom::Start(0))?;
+        let mut hasher = DefaultHasher::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.write(&buffer[..read]);
+        }
+        Ok(hasher.finish())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::SparseDescription;
+    use std::io::Write;
+
+    #[test]
+    fn rescans_when_changed() {
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![1, 2]);
+        let mut file = desc.to_file();
+        let mut cache = CachedScanner::new();
+        assert_eq!(cache.get(file.path()).expect("scan"), &desc.segments()[..]);
+
+        // Growing the file changes its length
+        file.as_file_mut().seek(SeekFrom::End(0)).unwrap();
+        file.as_file_mut().write_all(&[1; 10]).unwrap();
+        let segments = cache.get(file.path()).expect("scan").to_vec();
+        assert_eq!(segments.last().map(|x| x.range.end), Some(desc.len() + 10));
+
+        // Writing into the hole, and putting the modification time back, is
+        // only caught by hashing
+        let modified = file.as_file().metadata().unwrap().modified().unwrap();
+        file.as_file_mut().seek(SeekFrom::Start(0)).unwrap();
+        file.as_file_mut().write_all(&[1]).unwrap();
+        file.as_file().set_modified(modified).unwrap();
+        assert_eq!(cache.get(file.path()).expect("scan"), &segments[..]);
+        cache.set_hash_contents(true);
+        assert!(cache.get(file.path()).expect("scan")[0].is_data());
+
+        cache.remove(file.path());
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn missing_file() {
+        let dir = tempfile::tempdir().expect("Unable to create a directory");
+        let mut cache = CachedScanner::new();
+        assert!(matches!(
+            cache.get(dir.path().join("missing")),
+            Err(ScanError::IO(_))
+        ));
+    }
+}
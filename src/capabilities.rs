@@ -0,0 +1,156 @@
+//! Caching what each filesystem can do, so that scanning lots of files on the
+//! same device only has to find out once
+use super::*;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// What scanning a file on a particular device is capable of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether the OS can report holes for files on this device, if not
+    /// files on it have to be scanned by reading them
+    ///
+    /// This is always true on macOS, where files on HFS+ are reported as all
+    /// data rather than read, see [`ScanError::UnsupportedFileSystem`].
+    pub reports_holes: bool,
+    /// The block size of the filesystem on the device, that files on it are
+    /// read in if it can not report holes
+    ///
+    /// This is also the granularity of the holes on the device, as a hole can
+    /// only be made of whole blocks, so there is no separate field for it.
+    /// It is what to pass to
+    /// [`restore_sparseness`](SparseFile::restore_sparseness) for files on
+    /// the device.
+    pub block_size: u64,
+}
+
+/// A cache of the [`Capabilities`] of each device that files have been
+/// scanned on
+///
+/// The capabilities of a device are worked out the first time a file on it is
+/// scanned through [`scan_chunks_cached_caps`](SparseFile::scan_chunks_cached_caps),
+/// after that files on the device are either scanned by asking the OS, or
+/// read straight away if it can not report holes, without trying the one
+/// before the other. The cache can be shared between threads.
+#[derive(Debug, Default)]
+pub struct CapabilityCache {
+    devices: Mutex<HashMap<u64, Capabilities>>,
+}
+
+impl CapabilityCache {
+    /// Creates an empty cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The capabilities of a device, if a file on it has been scanned
+    pub fn get(&self, device: u64) -> Option<Capabilities> {
+        self.devices
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&device)
+            .copied()
+    }
+
+    /// Forget everything that has been learnt, for example after filesystems
+    /// have been remounted
+    pub fn clear(&self) {
+        self.devices
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    /// Scans a file that is on `device`, probing the device with `native`
+    /// first if this is the first file on it
+    ///
+    /// `native` is the scan that asks the OS, without falling back to reading
+    /// the file, so that we can tell if the device supports it.
+    #[cfg_attr(
+        not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "macos",
+            windows,
+        )),
+        allow(dead_code)
+    )]
+    pub(crate) fn scan<F: SparseFile + ?Sized>(
+        &self,
+        device: u64,
+        file: &mut F,
+        native: impl FnOnce(&mut F) -> Result<Vec<Segment>, ScanError>,
+    ) -> Result<Vec<Segment>, ScanError> {
+        match self.get(device) {
+            Some(capabilities) if capabilities.reports_holes => return native(file),
+            Some(capabilities) => return file.scan_chunks_by_reading(capabilities.block_size),
+            None => {}
+        }
+
+        let result = native(file);
+        let reports_holes = match &result {
+            Ok(_) => true,
+            Err(ScanError::UnsupportedFileSystem) => false,
+            // Some other problem, we have not learnt anything about the device
+            Err(_) => return result,
+        };
+        let block_size = match file.block_size() {
+            Ok(block_size) => block_size,
+            // The scan worked, it just can not be remembered
+            Err(_) if reports_holes => return result,
+            Err(e) => return Err(e),
+        };
+        self.devices
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                device,
+                Capabilities {
+                    reports_holes,
+                    block_size,
+                },
+            );
+        if !reports_holes {
+            return file.scan_chunks_by_reading(block_size);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::SparseDescription;
+
+    #[test]
+    fn probes_each_device_once() {
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![1, 2]);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        let cache = CapabilityCache::new();
+
+        // The first file on a device is probed with the native scan
+        let segments = cache.scan(1, file, |file| file.scan_chunks());
+        assert_eq!(segments.expect("Unable to scan"), desc.segments());
+        assert!(cache.get(1).expect("device was probed").reports_holes);
+
+        // After that the native scan is used as it is, without checking it
+        let made_up = vec![Segment::data(0, 1)];
+        let segments = cache.scan(1, file, |_| Ok(made_up.clone()));
+        assert_eq!(segments.expect("Unable to scan"), made_up);
+
+        // Devices that can not report holes are read without asking
+        let segments = cache.scan(2, file, |_| Err(ScanError::UnsupportedFileSystem));
+        assert_eq!(segments.expect("Unable to scan"), desc.segments());
+        let segments = cache.scan(2, file, |_| panic!("probed the device again"));
+        assert_eq!(segments.expect("Unable to scan"), desc.segments());
+        assert_eq!(cache.get(2).map(|x| x.block_size), file.block_size().ok());
+
+        // Other errors do not teach us anything
+        let segments = cache.scan(3, file, |_| Err(ScanError::NotSeekable));
+        assert!(matches!(segments, Err(ScanError::NotSeekable)));
+        assert_eq!(cache.get(3), None);
+    }
+}
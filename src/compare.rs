@@ -0,0 +1,112 @@
+//! Comparing two files by their layouts, without reading their holes
+use super::*;
+
+use std::fs::File;
+
+/// How much of each file is compared at a time
+const COMPARE_BUFFER_SIZE: u64 = 1024 * 1024;
+
+/// Scans both files and returns true if they have the same holes and data in
+/// the same places
+///
+/// The layouts are compared as they would be by [`first_divergence`] with
+/// [`Tolerance::Exact`], so neighbouring segments of the same type count as
+/// one, but data and unwritten segments are different. The contents of the
+/// files are not read, so two files with the same layout can still hold
+/// different data, and two files with the same contents, one with its zeros
+/// stored as holes and the other with them written out, have different
+/// layouts. See [`contents_equal_sparse`] to compare the contents.
+///
+/// # Errors
+///
+/// Will return `Err` if scanning either file fails, see
+/// [`scan_chunks`](SparseFile::scan_chunks)
+pub fn layouts_equal(a: &mut File, b: &mut File) -> Result<bool, ScanError> {
+    let a = a.scan_chunks()?;
+    let b = b.scan_chunks()?;
+    Ok(first_divergence(&a, &b, Tolerance::Exact).is_none())
+}
+
+/// Scans both files and returns true if they have the same contents, only
+/// reading the parts where at least one of them has data
+///
+/// Holes read as zeros, so where both files have a hole they are the same
+/// without reading either, and where only one does the other has to be all
+/// zeros there. So a sparse file and a copy of it with the holes written out
+/// as zeros are equal, even though their layouts are not, see
+/// [`layouts_equal`]. Unwritten segments count as data, and are read. The
+/// seek positions of both files are the same afterwards as they were before.
+///
+/// # Errors
+///
+/// Will return `Err` if scanning either file fails, see
+/// [`scan_chunks`](SparseFile::scan_chunks), or if reading either fails
+pub fn contents_equal_sparse(a: &mut File, b: &mut File) -> Result<bool, ScanError> {
+    let a_map = SparseMap::from(a.scan_chunks()?);
+    let b_map = SparseMap::from(b.scan_chunks()?);
+    if a_map.len() != b_map.len() {
+        return Ok(false);
+    }
+
+    let mut a_buf = Vec::new();
+    let mut b_buf = Vec::new();
+    for range in a_map.union(&b_map).data() {
+        let mut start = range.start;
+        while start < range.end {
+            let end = range.end.min(start.saturating_add(COMPARE_BUFFER_SIZE));
+            let piece = Segment::data(start, end);
+            a.read_segment(&piece, &mut a_buf)?;
+            b.read_segment(&piece, &mut b_buf)?;
+            if a_buf != b_buf {
+                return Ok(false);
+            }
+            start = end;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{SparseDescription, BLOCK_SIZE};
+    use quickcheck_macros::quickcheck;
+    use std::io::Write;
+
+    #[test]
+    fn dense_and_sparse_copies() {
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![1, 2, 4]);
+        let mut sparse = desc.to_file();
+        let mut dense = desc.to_dense_file();
+
+        // The same data, stored differently
+        assert!(!layouts_equal(sparse.as_file_mut(), dense.as_file_mut()).unwrap());
+        assert!(contents_equal_sparse(sparse.as_file_mut(), dense.as_file_mut()).unwrap());
+
+        // The same layout, holding different data
+        let mut other = desc.to_file();
+        other
+            .as_file_mut()
+            .seek(SeekFrom::Start(BLOCK_SIZE + 5))
+            .unwrap();
+        other.as_file_mut().write_all(&[2]).unwrap();
+        assert!(layouts_equal(sparse.as_file_mut(), other.as_file_mut()).unwrap());
+        assert!(!contents_equal_sparse(sparse.as_file_mut(), other.as_file_mut()).unwrap());
+
+        // Data written into a hole of the dense copy
+        dense.as_file_mut().seek(SeekFrom::Start(5)).unwrap();
+        dense.as_file_mut().write_all(&[1]).unwrap();
+        assert!(!contents_equal_sparse(sparse.as_file_mut(), dense.as_file_mut()).unwrap());
+    }
+
+    #[quickcheck]
+    fn equal_to_themselves(desc: SparseDescription) -> bool {
+        let mut a = desc.to_file();
+        let mut b = desc.to_file();
+        let mut dense = desc.to_dense_file();
+        let position = a.as_file_mut().stream_position().unwrap();
+        layouts_equal(a.as_file_mut(), b.as_file_mut()).unwrap()
+            && contents_equal_sparse(a.as_file_mut(), dense.as_file_mut()).unwrap()
+            && a.as_file_mut().stream_position().unwrap() == position
+    }
+}
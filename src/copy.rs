@@ -0,0 +1,338 @@
+//! Copying files without filling in their holes
+use super::*;
+
+use std::fs::File;
+use std::io::Write;
+
+/// Options for [`copy_sparse_with`]
+///
+/// The defaults are what [`copy_sparse`] uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CopyOptions {
+    /// How much data, in bytes, to read and write at a time when the kernel
+    /// can not copy it for us, 1 MiB by default
+    ///
+    /// Smaller buffers suit devices short of memory, larger ones suit disks
+    /// that are slow to seek. Zero is treated as one. The buffer is allocated
+    /// as an ordinary `Vec` the first time it is needed, so it is not aligned
+    /// in memory the way files opened for direct I/O, with `O_DIRECT` or
+    /// `FILE_FLAG_NO_BUFFERING`, need. Data segments start on block
+    /// boundaries, so with a size that is a multiple of the
+    /// [`block_size`](SparseFile::block_size) every read and write does too,
+    /// but the end of each segment is only aligned if the segment is.
+    pub buffer_size: usize,
+    /// Drill each hole of the source out of the destination, rather than
+    /// only seeking past it
+    ///
+    /// The destination is truncated and extended first, which leaves it all
+    /// hole on Unix, so this is only needed there if something else is
+    /// writing to it. NTFS only leaves holes in files that are marked as
+    /// sparse, and drilling a hole marks it, so this is on by default on
+    /// Windows and off everywhere else.
+    pub punch_holes: bool,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            buffer_size: 1024 * 1024,
+            punch_holes: cfg!(windows),
+        }
+    }
+}
+
+/// Copies `src` to `dst`, leaving holes in `dst` wherever there are holes in
+/// `src`, so the copy takes up no more space than the original
+///
+/// `dst` is truncated first. Only the data segments of `src` are read and
+/// written, the holes are skipped over. Returns the number of bytes of data
+/// copied. The seek positions of both files are left wherever the copy
+/// finished.
+///
+/// On Linux and Android the whole blocks of each data segment are shared
+/// with [`clone_range`] on filesystems that support it, like btrfs and XFS,
+/// and anything else is copied with `copy_file_range`, so it never has to
+/// pass through this process. Between files where the kernel can not do
+/// that it is read and written through a buffer instead. See
+/// [`copy_sparse_with`] to change how that is done.
+///
+/// # Errors
+///
+/// Will return `Err` if scanning `src` fails, see
+/// [`scan_chunks`](SparseFile::scan_chunks), or if reading or writing fails
+pub fn copy_sparse(src: &mut File, dst: &mut File) -> Result<u64, ScanError> {
+    copy_sparse_with(src, dst, &CopyOptions::default())
+}
+
+/// Copies `src` to `dst` like [`copy_sparse`], with extra [`CopyOptions`]
+///
+/// # Errors
+///
+/// Will return `Err` under the same conditions as [`copy_sparse`], or if
+/// drilling a hole fails when [`punch_holes`](CopyOptions::punch_holes) is
+/// set
+pub fn copy_sparse_with(
+    src: &mut File,
+    dst: &mut File,
+    options: &CopyOptions,
+) -> Result<u64, ScanError> {
+    let segments = src.scan_chunks()?;
+    let len = segments.last().map_or(0, |x| x.range.end);
+
+    // A file that has been extended without writing anything is all hole
+    dst.set_len(0)?;
+    dst.set_len(len)?;
+
+    // Cloning is only tried until the first time it fails
+    let mut clone_block_size = clone_block_size(dst);
+    let mut buffer = Buffer {
+        size: options.buffer_size.max(1),
+        buffer: Vec::new(),
+    };
+    let mut copied = 0;
+    for range in segments.data() {
+        copy_range(
+            src,
+            dst,
+            range.clone(),
+            len,
+            &mut clone_block_size,
+            &mut buffer,
+        )?;
+        copied += range.end - range.start;
+    }
+
+    if options.punch_holes {
+        for range in segments.holes() {
+            dst.drill_hole(range.start, range.end)?;
+        }
+    }
+
+    Ok(copied)
+}
+
+/// The buffer data is copied through, which is only allocated the first time
+/// it is needed
+struct Buffer {
+    size: usize,
+    buffer: Vec<u8>,
+}
+
+/// Recreates a sparse file in `dst` from its layout and its data
+///
+/// `data` gives the contents of the data segments of `map`, one after the
+/// other in order, like the data part of a GNU tar sparse file, see
+/// [`from_gnu_sparse_map`]. `dst` is truncated and set to the length of the
+/// map, then each data segment is written, so everything else is left as a
+/// hole. Unwritten segments are left as holes too, as they read back as
+/// zeros. The seek position of `dst` is left at the end of the last data
+/// segment written.
+/// ```
+/// # use drill_press::*;
+/// # let mut dst = tempfile::tempfile().unwrap();
+/// let map = SparseMap::from(vec![
+///     Segment::hole(0, 8192),
+///     Segment::data(8192, 8197),
+/// ]);
+/// apply_map(&mut dst, &map, &mut &b"hello"[..]).unwrap();
+/// assert_eq!(dst.metadata().unwrap().len(), 8197);
+/// ```
+///
+/// # Errors
+///
+/// Will return `Err(ScanError::IO)` with `ErrorKind::UnexpectedEof` if
+/// `data` runs out before every data segment has been written
+///
+/// Will also return `Err` if writing to `dst`, or reading `data`, fails
+pub fn apply_map(dst: &mut File, map: &SparseMap, data: &mut impl Read) -> Result<(), ScanError> {
+    dst.set_len(0)?;
+    dst.set_len(map.len())?;
+
+    for range in map.data() {
+        dst.seek(SeekFrom::Start(range.start))?;
+        let len = range.end - range.start;
+        if std::io::copy(&mut data.take(len), dst)? < len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+
+    // NTFS only leaves holes in files that are marked as sparse
+    #[cfg(windows)]
+    for range in map.holes() {
+        dst.drill_hole(range.start, range.end)?;
+    }
+
+    Ok(())
+}
+
+/// The block size to clone data from `src` into `dst` in, if the platform
+/// can clone ranges of files at all
+fn clone_block_size(dst: &File) -> Option<u64> {
+    cfg_if::cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "android"))] {
+            dst.block_size().ok().filter(|x| *x > 0)
+        } else {
+            let _ = dst;
+            None
+        }
+    }
+}
+
+/// Copies `range` of `src`, which is `src_len` bytes long, to the same place
+/// in `dst`, leaving both seek positions at the end of it
+///
+/// The whole blocks of the range are cloned while `clone_block_size` is set,
+/// and it is cleared if the filesystem can not do that. As much as possible
+/// of the rest is copied by the kernel, and whatever is left is read and
+/// written through `buffer`.
+fn copy_range(
+    src: &mut File,
+    dst: &mut File,
+    range: Range<u64>,
+    src_len: u64,
+    clone_block_size: &mut Option<u64>,
+    buffer: &mut Buffer,
+) -> Result<(), ScanError> {
+    let mut offset = range.start;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(block_size) = *clone_block_size {
+        use std::os::unix::io::AsFd;
+
+        // Only the last block of the file can be cloned part way through
+        let end = if range.end == src_len {
+            range.end
+        } else {
+            range.end / block_size * block_size
+        };
+        if offset % block_size == 0 && offset < end {
+            let len = end - offset;
+            match sys::clone_range(src.as_fd(), offset, dst.as_fd(), offset, len) {
+                Ok(()) => offset = end,
+                Err(ScanError::UnsupportedFileSystem) => *clone_block_size = None,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = (src_len, clone_block_size);
+
+    // copy_file_range can copy less than it was asked to, so keep going
+    // until it is done, or stops making progress
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    while offset < range.end {
+        use std::os::unix::io::AsFd;
+
+        let len = range.end - offset;
+        match sys::copy_file_range(src.as_fd(), offset, dst.as_fd(), offset, len)? {
+            Some(copied) if copied > 0 => offset += copied,
+            _ => break,
+        }
+    }
+
+    src.seek(SeekFrom::Start(offset))?;
+    dst.seek(SeekFrom::Start(offset))?;
+    if offset < range.end && buffer.buffer.is_empty() {
+        buffer.buffer.resize(buffer.size, 0);
+    }
+    let buffer = &mut buffer.buffer;
+    while offset < range.end {
+        let chunk = (range.end - offset).min(buffer.len() as u64) as usize;
+        src.read_exact(&mut buffer[..chunk])?;
+        dst.write_all(&buffer[..chunk])?;
+        offset += chunk as u64;
+    }
+    Ok(())
+}
+
+/// Makes `len` bytes of `dst`, starting at `dst_offset`, share the extents on
+/// the disk of the same number of bytes of `src` from `src_offset`, rather
+/// than copying the data
+///
+/// This is [`clone_file`] for part of a file, and needs a filesystem that
+/// supports reflinks in the same way. It uses `FICLONERANGE`, so the offsets
+/// and the length have to be multiples of the
+/// [`block_size`](SparseFile::block_size) of the filesystem, apart from a
+/// range that ends at the end of `src`. Cloning nothing does nothing.
+///
+/// # Errors
+///
+/// Will return `Err(ScanError::UnsupportedPlatform)` on platforms other than
+/// Linux and Android
+///
+/// Will return `Err(ScanError::Misaligned)` if the range is not made of
+/// whole blocks, the start and end given are of the range in `src`
+///
+/// Will return `Err(ScanError::UnsupportedFileSystem)` if the filesystem can
+/// not clone files, or `src` and `dst` are on different filesystems
+///
+/// Will also return `Err` if any other I/O error occurs
+pub fn clone_range(
+    src: &File,
+    src_offset: u64,
+    dst: &File,
+    dst_offset: u64,
+    len: u64,
+) -> Result<(), ScanError> {
+    cfg_if::cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "android"))] {
+            use std::os::unix::io::AsFd;
+
+            // A length of zero would clone everything to the end of src
+            if len == 0 {
+                return Ok(());
+            }
+            let end = src_offset.saturating_add(len);
+            let block_size = dst.block_size()?;
+            let at_end = end == src.metadata()?.len();
+            if block_size == 0
+                || src_offset % block_size != 0
+                || dst_offset % block_size != 0
+                || !(len % block_size == 0 || at_end)
+            {
+                return Err(ScanError::Misaligned {
+                    start: src_offset,
+                    end,
+                    block_size,
+                });
+            }
+            sys::clone_range(src.as_fd(), src_offset, dst.as_fd(), dst_offset, len)
+        } else {
+            let _ = (src, src_offset, dst, dst_offset, len);
+            Err(ScanError::UnsupportedPlatform)
+        }
+    }
+}
+
+/// Makes `dst` a clone of `src`, sharing the same extents on the disk rather
+/// than copying any data
+///
+/// This is only possible on filesystems that support reflinks, like btrfs
+/// and XFS, but when it is it is almost instant however big the file is, and
+/// keeps the holes. Whatever was in `dst` before is replaced. When this fails
+/// with `ScanError::UnsupportedFileSystem` use [`copy_sparse`] instead.
+///
+/// This uses `FICLONE` on Linux and Android. macOS can only clone into a new
+/// file with `clonefile`, not into one that is already open, so is not
+/// supported.
+///
+/// # Errors
+///
+/// Will return `Err(ScanError::UnsupportedPlatform)` on platforms other than
+/// Linux and Android
+///
+/// Will return `Err(ScanError::UnsupportedFileSystem)` if the filesystem can
+/// not clone files, or `src` and `dst` are on different filesystems
+///
+/// Will also return `Err` if any other I/O error occurs
+pub fn clone_file(src: &File, dst: &File) -> Result<(), ScanError> {
+    cfg_if::cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "android"))] {
+            crate::unix::clone_file(src, dst)
+        } else {
+            let _ = (src, dst);
+            Err(ScanError::UnsupportedPlatform)
+        }
+    }
+}
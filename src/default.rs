@@ -1,17 +1,27 @@
 //! Default fall back implementation for SparseFile trait on unsupported
 //! platforms
 //!
-//! By defualt this will just error out
+//! This is what is built for every target without a backend, including
+//! `wasm32` and the Unix-likes that have no `SEEK_HOLE`, so that crates
+//! depending on this one still compile there. There is no way to find holes,
+//! so rather than pretending the file is dense everything returns
+//! `ScanError::UnsupportedPlatform`, and the caller can decide whether to
+//! treat the file as one big data segment.
 use super::*;
 
 use std::fs::File;
 
-impl SparseFile for File {
+impl SparseFile for &File {
     fn scan_chunks(&mut self) -> std::result::Result<std::vec::Vec<Segment>, ScanError> {
         Err(ScanError::UnsupportedPlatform)
     }
 
-    fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
+    fn drill_hole(&self, _start: u64, _end: u64) -> Result<(), ScanError> {
         Err(ScanError::UnsupportedPlatform)
     }
 }
+
+/// There is nothing to scan with on this platform
+pub(crate) fn segments(_file: &File, _window: Range<u64>) -> Result<ScanIter<'_>, ScanError> {
+    Err(ScanError::UnsupportedPlatform)
+}
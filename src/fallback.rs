@@ -0,0 +1,265 @@
+//! Portable scanning that reads the whole file and looks for blocks of zeros,
+//! rather than asking the OS where the holes are
+use super::*;
+
+/// The most that is read in one go, blocks bigger than this are read in
+/// pieces
+const READ_BUFFER_SIZE: u64 = 64 * 1024;
+
+/// Reads through `window` of the file a block at a time, treating blocks
+/// that are all zeros as holes and any other block as data
+///
+/// The blocks are counted from the start of the file, so reading starts at
+/// the start of the block `window` starts in, and stops at the end of the
+/// block it ends in, and the segments are then trimmed down to the window.
+/// The last block is shorter if the file is not a whole number of blocks
+/// long. `block_size` must not be zero.
+pub(crate) fn scan_by_reading<R: Read + Seek + ?Sized>(
+    file: &mut R,
+    block_size: u64,
+    window: Range<u64>,
+) -> Result<Vec<Segment>, ScanError> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let end = window.end.min(len);
+    let mut offset = window.start.min(end) / block_size * block_size;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut buffer = read_buffer(block_size.min(len - offset));
+    let mut segments = Vec::new();
+    while offset < end {
+        let chunk = (len - offset).min(block_size);
+        let segment_type = if read_zeros(file, chunk, &mut buffer)? {
+            SegmentType::Hole
+        } else {
+            SegmentType::Data
+        };
+        layout::push_merged(&mut segments, segment_type, offset..offset + chunk);
+        offset += chunk;
+    }
+    Ok(layout::clip(segments, window))
+}
+
+/// Scans the contents of a file that are already in memory, for example
+/// through a memory map, for blocks of zeros
+///
+/// `contents` is treated as the whole file, and split into blocks of
+/// `block_size` bytes from the start. Blocks that are all zeros are holes and
+/// any other block is data, the last block is shorter if `contents` is not a
+/// whole number of blocks long. The segments are the same shape as the
+/// platform backends give, with neighbouring blocks of the same type merged,
+/// and the same as [`scan_chunks_by_reading`](SparseFile::scan_chunks_by_reading)
+/// gives for a file with these contents.
+/// ```
+/// # use drill_press::*;
+/// let mut contents = vec![0_u8; 10];
+/// contents[9] = 1;
+/// assert_eq!(
+///     scan_mmap(&contents, 4),
+///     vec![Segment::hole(0, 8), Segment::data(8, 10)]
+/// );
+/// ```
+///
+/// # Panics
+///
+/// Panics if `block_size` is 0
+pub fn scan_mmap(contents: &[u8], block_size: u64) -> Vec<Segment> {
+    assert!(block_size != 0, "block size must not be zero");
+    let block_size = usize::try_from(block_size).unwrap_or(usize::MAX);
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    for block in contents.chunks(block_size) {
+        let end = offset + block.len() as u64;
+        layout::push_merged(&mut segments, block_type(block), offset..end);
+        offset = end;
+    }
+    segments
+}
+
+/// A block is a hole if it is all zeros
+fn block_type(block: &[u8]) -> SegmentType {
+    if block.iter().all(|x| *x == 0) {
+        SegmentType::Hole
+    } else {
+        SegmentType::Data
+    }
+}
+
+/// A buffer for reading blocks of up to `len` bytes, which is never more than
+/// [`READ_BUFFER_SIZE`] however big the blocks are
+fn read_buffer(len: u64) -> Vec<u8> {
+    vec![0_u8; len.min(READ_BUFFER_SIZE) as usize]
+}
+
+/// Reads the next `len` bytes of the file through `buffer`, a piece at a
+/// time, and returns true if they were all zeros
+fn read_zeros<R: Read + ?Sized>(
+    file: &mut R,
+    mut len: u64,
+    buffer: &mut [u8],
+) -> std::io::Result<bool> {
+    let mut zeros = true;
+    while len > 0 {
+        let piece = len.min(buffer.len() as u64) as usize;
+        file.read_exact(&mut buffer[..piece])?;
+        zeros &= buffer[..piece].iter().all(|x| *x == 0);
+        len -= piece as u64;
+    }
+    Ok(zeros)
+}
+
+/// Reads the whole `block_size` aligned blocks inside each data segment and
+/// returns the runs of them that are all zeros
+///
+/// Partial blocks at the edges of data segments are never part of a run.
+/// `block_size` must not be zero.
+pub(crate) fn zero_runs<R: Read + Seek + ?Sized>(
+    file: &mut R,
+    segments: &[Segment],
+    block_size: u64,
+) -> Result<Vec<Range<u64>>, ScanError> {
+    let mut buffer = read_buffer(block_size);
+    let mut runs = Vec::new();
+    for data in segments.data() {
+        // A block that would start past u64::MAX can not fit in the segment
+        let Some(mut offset) = data.start.div_ceil(block_size).checked_mul(block_size) else {
+            continue;
+        };
+        let mut zeros_start = None;
+        while offset
+            .checked_add(block_size)
+            .is_some_and(|end| end <= data.end)
+        {
+            file.seek(SeekFrom::Start(offset))?;
+            if read_zeros(file, block_size, &mut buffer)? {
+                zeros_start.get_or_insert(offset);
+            } else if let Some(start) = zeros_start.take() {
+                runs.push(start..offset);
+            }
+            offset += block_size;
+        }
+        if let Some(start) = zeros_start {
+            runs.push(start..offset);
+        }
+    }
+    Ok(runs)
+}
+
+/// Scans `window` of the file by reading it, for when the OS reports that
+/// the filesystem can not tell us where the holes are, using the block size
+/// of the filesystem
+#[cfg_attr(
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        windows,
+    )),
+    allow(dead_code)
+)]
+pub(crate) fn read_window<F: SparseFile + ?Sized>(
+    file: &mut F,
+    window: Range<u64>,
+) -> Result<Vec<Segment>, ScanError> {
+    let block_size = file.block_size()?;
+    check_block_size(block_size)?;
+    keep_position(file, |file| scan_by_reading(file, block_size, window))
+}
+
+/// Checks a scan from the OS against a scan done by reading the file
+///
+/// The OS is free to report blocks of zeros as data, but anything it reports
+/// as a hole must read back as zeros. As the read scan only works in whole
+/// blocks only the whole blocks in each hole are checked. Returns the offset
+/// of the first byte of a hole that was not zeros, or the end of the shorter
+/// scan if they do not cover the same length.
+pub(crate) fn first_inconsistency(
+    native: &[Segment],
+    read: &[Segment],
+    block_size: u64,
+) -> Option<u64> {
+    let native_len = native.last().map_or(0, |x| x.range.end);
+    let read_len = read.last().map_or(0, |x| x.range.end);
+    if native_len != read_len {
+        return Some(native_len.min(read_len));
+    }
+
+    let native = layout::with_tolerance(native, Tolerance::Block(block_size));
+    let mut zeros = read.iter().filter(|x| x.is_hole()).peekable();
+    for hole in native.iter().filter(|x| x.is_hole()) {
+        let mut offset = hole.start();
+        while offset < hole.range.end {
+            while zeros.next_if(|zero| zero.range.end <= offset).is_some() {}
+            match zeros.peek() {
+                Some(zero) if zero.start() <= offset => offset = zero.range.end,
+                _ => return Some(offset),
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn reading_finds_zero_blocks() {
+        let mut contents = vec![0_u8; 22];
+        contents[0] = 1;
+        contents[13] = 1;
+        let segments = scan_by_reading(&mut Cursor::new(&contents), 4, 0..u64::MAX).expect("read");
+        assert_eq!(
+            segments,
+            vec![
+                Segment::data(0, 4),
+                Segment::hole(4, 12),
+                Segment::data(12, 16),
+                Segment::hole(16, 22)
+            ]
+        );
+        assert_eq!(scan_mmap(&contents, 4), segments);
+        assert_eq!(scan_mmap(&contents, u64::MAX), vec![Segment::data(0, 22)]);
+        assert_eq!(scan_mmap(&[], 4), vec![]);
+    }
+
+    #[test]
+    fn reading_a_window() {
+        let mut contents = vec![0_u8; 22];
+        contents[0] = 1;
+        contents[13] = 1;
+        let mut file = Cursor::new(&contents);
+        let segments = scan_by_reading(&mut file, 4, 6..14).expect("read");
+        assert_eq!(segments, vec![Segment::hole(6, 12), Segment::data(12, 14)]);
+        // Only the blocks the window touches are read
+        assert_eq!(file.position(), 16);
+        assert_eq!(scan_by_reading(&mut file, 4, 30..40).expect("read"), vec![]);
+    }
+
+    #[test]
+    fn inconsistent_holes() {
+        let read = vec![
+            Segment::data(0, 4),
+            Segment::hole(4, 12),
+            Segment::data(12, 16),
+        ];
+        // reporting zeros as data is fine
+        assert_eq!(first_inconsistency(&[Segment::data(0, 16)], &read, 4), None);
+        // partial blocks at the edge of a hole are not checked
+        let native = vec![
+            Segment::data(0, 2),
+            Segment::hole(2, 12),
+            Segment::data(12, 16),
+        ];
+        assert_eq!(first_inconsistency(&native, &read, 4), None);
+        // but a hole over data is wrong
+        let native = vec![Segment::data(0, 4), Segment::hole(4, 16)];
+        assert_eq!(first_inconsistency(&native, &read, 4), Some(12));
+        // as is a different length
+        assert_eq!(
+            first_inconsistency(&[Segment::data(0, 20)], &read, 4),
+            Some(16)
+        );
+    }
+}
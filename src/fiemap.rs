@@ -0,0 +1,164 @@
+//! `FS_IOC_FIEMAP` based scanning, which asks the filesystem for its extent
+//! map directly so it can tell us where each extent is on the device
+use super::*;
+
+use std::os::unix::io::BorrowedFd;
+
+// from linux/fiemap.h
+const FIEMAP_FLAG_SYNC: u32 = 0x0000_0001;
+const FIEMAP_EXTENT_LAST: u32 = 0x0000_0001;
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x0000_0002;
+const FIEMAP_EXTENT_ENCODED: u32 = 0x0000_0008;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x0000_0800;
+
+/// How many extents to ask for in each call
+const EXTENTS_PER_CALL: usize = 256;
+
+/// The header of a `struct fiemap`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+/// The header is followed directly by the array of extents it describes
+#[repr(C)]
+pub(crate) struct FiemapRequest {
+    header: Fiemap,
+    extents: [FiemapExtent; EXTENTS_PER_CALL],
+}
+
+/// Gets the extents of the file `fd`, which is `len` bytes long, filling in
+/// the gaps between them with holes
+///
+/// Each extent is its own segment, so there may be several data segments in
+/// a row if the data is not stored contiguously on the device.
+pub(crate) fn scan_extents(fd: BorrowedFd<'_>, len: u64) -> Result<Vec<Segment>, ScanError> {
+    // Make sure delayed allocations have a place on the device
+    map_extents(fd, 0..len, FIEMAP_FLAG_SYNC)
+}
+
+/// Gets the layout of `window` of the file `fd`, which is `len` bytes long,
+/// with as few syscalls as possible, for
+/// [`scan_chunks`](SparseFile::scan_chunks)
+///
+/// This gives exactly the same segments as walking the file with `SEEK_HOLE`
+/// and `SEEK_DATA`, but one ioctl covers hundreds of segments rather than
+/// needing two seeks for each of them. Returns `None` whenever that can not
+/// be guaranteed, and the caller should seek instead. That is if the
+/// filesystem can not map extents, or the file has unwritten extents, which
+/// the seeks report as holes or data depending on what is in the page cache.
+///
+/// # Errors
+///
+/// Will return `Err` if the ioctl fails for any other reason than the
+/// filesystem not supporting it
+pub(crate) fn scan_layout(
+    fd: BorrowedFd<'_>,
+    len: u64,
+    window: Range<u64>,
+) -> Result<Option<Vec<Segment>>, ScanError> {
+    let window = window.start..window.end.min(len);
+    // Delayed allocations are reported too, so there is no need to sync
+    let extents = match map_extents(fd, window, 0) {
+        Ok(extents) => extents,
+        Err(ScanError::UnsupportedFileSystem) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut segments = Vec::with_capacity(extents.len());
+    for extent in extents {
+        if extent.is_unwritten() {
+            return Ok(None);
+        }
+        layout::push_merged(&mut segments, extent.segment_type, extent.range);
+    }
+    Ok(Some(segments))
+}
+
+/// Gets the extents in `window` of the file `fd`, filling in the gaps between
+/// them with holes
+fn map_extents(
+    fd: BorrowedFd<'_>,
+    window: Range<u64>,
+    flags: u32,
+) -> Result<Vec<Segment>, ScanError> {
+    let mut segments = Vec::new();
+    let mut request = Box::new(FiemapRequest {
+        header: Fiemap::default(),
+        extents: [FiemapExtent::default(); EXTENTS_PER_CALL],
+    });
+    let Range {
+        start: mut offset,
+        end: len,
+    } = window;
+
+    'calls: while offset < len {
+        request.header = Fiemap {
+            start: offset,
+            length: len - offset,
+            flags,
+            extent_count: EXTENTS_PER_CALL as u32,
+            ..Fiemap::default()
+        };
+        sys::fiemap(fd, &mut request)?;
+
+        let mapped = (request.header.mapped_extents as usize).min(EXTENTS_PER_CALL);
+        let call_start = offset;
+        for extent in &request.extents[..mapped] {
+            // Extents cover whole blocks, so can go past the end of the file
+            let start = extent.logical.max(offset);
+            let end = extent.logical.saturating_add(extent.length).min(len);
+            if start >= end {
+                continue;
+            }
+            if start > offset {
+                segments.push(Segment::hole(offset, start));
+            }
+            let location_known =
+                extent.flags & (FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_ENCODED) == 0;
+            // Preallocated space that has not been written to yet
+            let segment_type = if extent.flags & FIEMAP_EXTENT_UNWRITTEN != 0 {
+                SegmentType::Unwritten
+            } else {
+                SegmentType::Data
+            };
+            segments.push(Segment {
+                segment_type,
+                range: start..end,
+                physical_offset: extent
+                    .physical
+                    .checked_add(start - extent.logical)
+                    .filter(|_| location_known),
+            });
+            offset = end;
+            if extent.flags & FIEMAP_EXTENT_LAST != 0 {
+                break 'calls;
+            }
+        }
+        // There are no more extents before the end of the file
+        if offset == call_start {
+            break;
+        }
+    }
+
+    if offset < len {
+        segments.push(Segment::hole(offset, len));
+    }
+    Ok(segments)
+}
@@ -0,0 +1,259 @@
+//! Helpers for building sparse files with a known layout, for use in tests
+//!
+//! Only available with the `test-fixtures` feature. Along with the helpers
+//! here, [`SparseMap`], [`Segment`] and [`SegmentType`] implement quickcheck's
+//! [`Arbitrary`], so that crates that work with layouts can property test
+//! their own code. None of this is meant for use outside of tests.
+//! ```
+//! # use drill_press::*;
+//! # use drill_press::fixtures::*;
+//! let desc = SparseDescription::from_parts(SegmentType::Hole, vec![1, 3]);
+//! let mut file = desc.to_file();
+//! # if cfg!(any(
+//! #     target_os = "linux",
+//! #     target_os = "android",
+//! #     target_os = "freebsd",
+//! #     target_os = "macos",
+//! #     windows,
+//! # )) {
+//! assert_eq!(file.as_file_mut().scan_chunks().unwrap(), desc.segments());
+//! # }
+//! ```
+use super::*;
+
+use std::io::{Seek, SeekFrom, Write};
+use tempfile::NamedTempFile;
+
+use quickcheck::{Arbitrary, Gen};
+
+const MAX_SPLITS: usize = 50;
+
+// minum hole size varies by file system
+/// The smallest hole that can be relied on to be kept as a hole by the
+/// filesystems the tests run on
+#[cfg(unix)]
+pub const BLOCK_SIZE: u64 = 4 * 1024;
+/// The smallest hole that can be relied on to be kept as a hole by the
+/// filesystems the tests run on
+#[cfg(windows)]
+pub const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// A description of the layout of a sparse file, as a list of segments that
+/// cover the whole file
+///
+/// With quickcheck this generates random layouts made of whole
+/// [`BLOCK_SIZE`] blocks, starting with either a hole or data.
+#[derive(Clone, Debug)]
+pub struct SparseDescription {
+    segments: Vec<Segment>,
+}
+
+impl SparseDescription {
+    /// Builds a layout that starts with a segment of `start_type` and then
+    /// switches between hole and data at each of the `split_points`, which
+    /// are counted in [`BLOCK_SIZE`] blocks
+    ///
+    /// The split points are sorted and deduplicated, and any at zero are
+    /// ignored.
+    pub fn from_parts(start_type: SegmentType, mut split_points: Vec<u8>) -> Self {
+        split_points.retain(|x| *x != 0);
+        split_points.truncate(MAX_SPLITS);
+        split_points.sort_unstable();
+        split_points.dedup();
+
+        let mut segment_type = start_type;
+        let mut prev = 0;
+        let mut segments = Vec::with_capacity(split_points.len());
+
+        for point in split_points {
+            let point = point as u64 * BLOCK_SIZE;
+
+            segments.push(Segment::new(segment_type, prev, point));
+            prev = point;
+            segment_type = segment_type.opposite();
+        }
+
+        SparseDescription { segments }
+    }
+
+    /// Builds a layout from a list of segments
+    ///
+    /// # Panics
+    ///
+    /// Panics if the segments do not pass [`validate`] for the length of
+    /// file they describe, the end of the last segment.
+    pub fn from_segments(segments: Vec<Segment>) -> Self {
+        let len = segments.last().map_or(0, |x| x.range.end);
+        if let Err(e) = validate(&segments, len) {
+            panic!("Invalid segments for a SparseDescription: {}", e);
+        }
+        SparseDescription { segments }
+    }
+
+    /// The segments in this layout
+    pub fn segments(&self) -> Vec<Segment> {
+        self.segments.clone()
+    }
+
+    /// The length of the file this description creates
+    pub fn len(&self) -> u64 {
+        self.segments.last().map_or(0, |x| x.range.end)
+    }
+
+    /// Whether this describes an empty file
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// A layout that is a single segment of `start_type`, `end` bytes long,
+    /// rounded down to whole [`BLOCK_SIZE`] blocks
+    pub fn one_segment(start_type: SegmentType, end: u64) -> Self {
+        SparseDescription::from_parts(start_type, vec![(end / BLOCK_SIZE) as u8])
+    }
+
+    /// Writes out a temporary file with this layout, data segments are filled
+    /// with ones and holes are left unwritten
+    ///
+    /// The file is marked as sparse first on Windows, and its length is set
+    /// at the end so that a trailing hole is kept as a hole.
+    ///
+    /// Scanning the file should give back exactly [`segments`](Self::segments),
+    /// as long as the holes line up with blocks the filesystem can leave
+    /// unallocated.
+    pub fn to_file(&self) -> NamedTempFile {
+        let mut temp = sparse_tempfile();
+
+        let file = temp.as_file_mut();
+        // Iterate through the SparseDescription
+        for segment in self.segments.data() {
+            file.seek(SeekFrom::Start(segment.start))
+                .expect("Unable to seek in file");
+            let length = segment.end - segment.start;
+            let buffer = vec![1_u8; length as usize];
+            file.write_all(&buffer[..])
+                .expect("Unable to write bytes to file");
+        }
+
+        temp.as_file_mut()
+            .set_len(self.len())
+            .expect("Unable to set length of file");
+        temp
+    }
+
+    /// Creates a file with the same contents as [`to_file`](Self::to_file),
+    /// but with the holes written out as zeros
+    pub fn to_dense_file(&self) -> NamedTempFile {
+        let mut temp = NamedTempFile::new().expect("Unable to create tempfile");
+
+        let file = temp.as_file_mut();
+        for segment in &self.segments {
+            let byte = if segment.is_data() { 1_u8 } else { 0_u8 };
+            let buffer = vec![byte; segment.len() as usize];
+            file.write_all(&buffer[..])
+                .expect("Unable to write bytes to file");
+        }
+        temp
+    }
+}
+
+/// Creates an empty temp file that is ready to have holes in it
+pub fn sparse_tempfile() -> NamedTempFile {
+    let temp = NamedTempFile::new().expect("Unable to create tempfile");
+
+    // Windows only leaves holes in files that have been marked as sparse
+    #[cfg(windows)]
+    {
+        use std::os::windows::io::AsHandle;
+        crate::sys::set_sparse(temp.as_file().as_handle(), true)
+            .expect("Unable to set the sparse flag on the tempfile");
+    }
+
+    temp
+}
+
+impl Arbitrary for SparseDescription {
+    fn arbitrary(g: &mut Gen) -> Self {
+        // Generate some random points in the file to be boundarires between segments
+        SparseDescription::from_parts(SegmentType::arbitrary(g), Vec::arbitrary(g))
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let Some(start_type) = self.segments.first().map(|x| x.segment_type) else {
+            return quickcheck::empty_shrinker();
+        };
+        // Only layouts made of whole blocks can be described by split points
+        let split_points: Option<Vec<u8>> = self
+            .segments
+            .iter()
+            .map(|x| {
+                let end = x.range.end / BLOCK_SIZE;
+                (x.range.end % BLOCK_SIZE == 0)
+                    .then(|| u8::try_from(end).ok())
+                    .flatten()
+            })
+            .collect();
+        let Some(split_points) = split_points else {
+            return quickcheck::empty_shrinker();
+        };
+        Box::new(
+            split_points
+                .shrink()
+                .map(move |split_points| SparseDescription::from_parts(start_type, split_points)),
+        )
+    }
+}
+
+/// Generates the same layouts as [`SparseDescription`], which only has holes
+/// and data, so unlike a map made from a scan these are always canonical,
+/// alternating between hole and data with no gaps
+/// ```
+/// # use drill_press::*;
+/// use quickcheck::{quickcheck, TestResult};
+///
+/// fn prop(map: SparseMap) -> TestResult {
+///     TestResult::from_bool(validate(&map, map.len()).is_ok() && map.union(&map) == map)
+/// }
+/// quickcheck(prop as fn(SparseMap) -> TestResult);
+/// ```
+impl Arbitrary for SparseMap {
+    fn arbitrary(g: &mut Gen) -> Self {
+        SparseMap::from(SparseDescription::arbitrary(g).segments())
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        Box::new(
+            SparseDescription::from_segments(self.to_vec())
+                .shrink()
+                .map(|desc| SparseMap::from(desc.segments())),
+        )
+    }
+}
+
+/// Generates a single hole or data segment that is never empty, on its own
+/// so not part of any particular layout, see [`SparseMap`] for those
+impl Arbitrary for Segment {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let start = u32::arbitrary(g) as u64;
+        let len = u16::arbitrary(g) as u64 + 1;
+        Segment::new(SegmentType::arbitrary(g), start, start + len)
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let segment_type = self.segment_type;
+        Box::new(
+            (self.start(), self.len() - 1)
+                .shrink()
+                .map(move |(start, extra)| Segment::new(segment_type, start, start + extra + 1)),
+        )
+    }
+}
+
+impl Arbitrary for SegmentType {
+    fn arbitrary(g: &mut Gen) -> Self {
+        if bool::arbitrary(g) {
+            SegmentType::Hole
+        } else {
+            SegmentType::Data
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Converting layouts to and from the formats other tools describe sparse
+//! files in
+use super::*;
+
+use std::io::{BufRead, Write};
+
+/// Converts a layout into a GNU tar sparse map, the `offset, numbytes` pairs
+/// of the `GNU.sparse.*` PAX headers
+///
+/// There is one pair for each data segment. Unwritten segments read back as
+/// zeros, so they are left out like holes. If the file ends with a hole the
+/// map ends with a zero length pair at the end of the file, as GNU tar does,
+/// so that the length of the file can be recovered from the map.
+/// ```
+/// # use drill_press::*;
+/// let segments = vec![
+///     Segment::hole(0, 10),
+///     Segment::data(10, 20),
+///     Segment::hole(20, 40),
+/// ];
+/// assert_eq!(to_gnu_sparse_map(&segments), vec![(10, 10), (40, 0)]);
+/// ```
+pub fn to_gnu_sparse_map(segments: &[Segment]) -> Vec<(u64, u64)> {
+    let mut map: Vec<(u64, u64)> = segments
+        .data_segments()
+        .map(|x| (x.start(), x.len()))
+        .collect();
+    if let Some(last) = segments.last().filter(|x| !x.is_data()) {
+        map.push((last.range.end, 0));
+    }
+    map
+}
+
+/// Rebuilds the layout of a file `total_len` bytes long from a GNU tar
+/// sparse map, see [`to_gnu_sparse_map`]
+///
+/// Everything not covered by a pair is a hole. Zero length pairs are
+/// skipped, pairs that overlap the ones before them are merged with them,
+/// and anything past `total_len` is cut off, so the result always covers
+/// exactly `0..total_len` and passes [`validate`].
+pub fn from_gnu_sparse_map(
+    pairs: impl IntoIterator<Item = (u64, u64)>,
+    total_len: u64,
+) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut prev_end = 0;
+    for (offset, len) in pairs {
+        let start = offset.max(prev_end).min(total_len);
+        let end = offset.saturating_add(len).min(total_len);
+        if start >= end {
+            continue;
+        }
+        layout::push_merged(&mut segments, SegmentType::Hole, prev_end..start);
+        layout::push_merged(&mut segments, SegmentType::Data, start..end);
+        prev_end = end;
+    }
+    layout::push_merged(&mut segments, SegmentType::Hole, prev_end..total_len);
+    segments
+}
+
+/// Errors returned by [`from_ddrescue_map`]
+#[derive(Error, Debug)]
+pub enum MapfileError {
+    /// Reading the mapfile failed
+    #[error("IO Error occurred")]
+    IO(#[from] std::io::Error),
+    /// A line of the mapfile could not be understood
+    #[error("Line {line} of the mapfile is not valid: {reason}")]
+    Invalid {
+        /// the line number, counting from one
+        line: usize,
+        /// what was wrong with the line
+        reason: &'static str,
+    },
+    /// The blocks in the mapfile do not cover the file from the start
+    /// without gaps or overlaps
+    #[error("The blocks in the mapfile are not well-formed")]
+    Layout(#[from] ValidationError),
+}
+
+/// Writes a layout as a GNU ddrescue mapfile
+///
+/// Data segments are written as finished, `+`, blocks, and holes and
+/// unwritten segments as non-tried, `?`, blocks, so ddrescue only needs to
+/// copy the data. The status line says the rescue is finished, at the end of
+/// the file.
+/// ```
+/// # use drill_press::*;
+/// let segments = vec![
+///     Segment::data(0, 4096),
+///     Segment::hole(4096, 8192),
+/// ];
+/// let mut mapfile = Vec::new();
+/// to_ddrescue_map(&segments, &mut mapfile).unwrap();
+/// assert_eq!(from_ddrescue_map(&mapfile[..]).unwrap(), segments);
+/// ```
+pub fn to_ddrescue_map(segments: &[Segment], mut writer: impl Write) -> std::io::Result<()> {
+    let len = segments.last().map_or(0, |x| x.range.end);
+    writeln!(writer, "# Mapfile. Created by drill-press")?;
+    writeln!(writer, "# current_pos  current_status  current_pass")?;
+    writeln!(writer, "0x{:08X}     +               1", len)?;
+    writeln!(writer, "#      pos        size  status")?;
+    for segment in segments {
+        let status = if segment.is_data() { '+' } else { '?' };
+        writeln!(
+            writer,
+            "0x{:08X}  0x{:08X}  {}",
+            segment.start(),
+            segment.len(),
+            status
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads a GNU ddrescue mapfile back into a layout, see
+/// [`to_ddrescue_map`]
+///
+/// Finished, `+`, blocks are data and every other status is a hole.
+/// Comments and the status line at the top are skipped, and neighbouring
+/// blocks that end up the same type are merged.
+///
+/// # Errors
+///
+/// Will return `Err(MapfileError::Invalid)` if a line can not be parsed
+///
+/// Will return `Err(MapfileError::Layout)` if the blocks do not start at
+/// zero and follow on from each other, see [`validate`]
+pub fn from_ddrescue_map(reader: impl BufRead) -> Result<Vec<Segment>, MapfileError> {
+    let mut segments = Vec::new();
+    let mut seen_status = false;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason| MapfileError::Invalid {
+            line: index + 1,
+            reason,
+        };
+        // The first line that is not a comment is the current position and
+        // status of the rescue, not a block
+        if !seen_status {
+            seen_status = true;
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let (Some(pos), Some(size), Some(status), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid("expected a position, size and status"));
+        };
+        let pos = parse_number(pos).ok_or_else(|| invalid("the position is not a number"))?;
+        let size = parse_number(size).ok_or_else(|| invalid("the size is not a number"))?;
+        let end = pos
+            .checked_add(size)
+            .ok_or_else(|| invalid("the block ends past the largest offset"))?;
+        let segment_type = match status {
+            "+" => SegmentType::Data,
+            "?" | "*" | "/" | "-" => SegmentType::Hole,
+            _ => return Err(invalid("unknown block status")),
+        };
+        segments.push(Segment::new(segment_type, pos, end));
+    }
+
+    let len = segments.last().map_or(0, |x| x.range.end);
+    validate(&segments, len)?;
+    Ok(layout::coalesce(&segments))
+}
+
+/// Parses a number the way ddrescue writes them, in hex with a `0x` prefix,
+/// or in decimal
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use quickcheck_macros::quickcheck;
+
+    #[quickcheck]
+    fn gnu_sparse_map_round_trips(desc: SparseDescription) -> bool {
+        let map = to_gnu_sparse_map(&desc.segments());
+        from_gnu_sparse_map(map, desc.len()) == desc.segments()
+    }
+
+    #[quickcheck]
+    fn ddrescue_map_round_trips(desc: SparseDescription) -> bool {
+        let mut mapfile = Vec::new();
+        to_ddrescue_map(&desc.segments(), &mut mapfile).expect("Unable to write");
+        from_ddrescue_map(&mapfile[..]).expect("Unable to parse") == desc.segments()
+    }
+
+    #[test]
+    fn ddrescue_map_parsing() {
+        let mapfile = "\
+# Mapfile. Created by GNU ddrescue version 1.27
+# Command line: ddrescue /dev/sdb disk.img disk.map
+# current_pos  current_status  current_pass
+0x00002000     +               1
+#      pos        size  status
+0x00000000  0x00001000  +
+0x00001000  0x00000800  -
+0x00001800  0x00000800  *
+4096        1024        +
+";
+        let segments = from_ddrescue_map(mapfile.as_bytes());
+        assert!(matches!(segments, Err(MapfileError::Layout(_))));
+
+        let mapfile = mapfile.replace("4096        1024", "8192        1024");
+        let segments = from_ddrescue_map(mapfile.as_bytes()).expect("Unable to parse");
+        assert_eq!(
+            segments,
+            vec![
+                Segment::data(0, 0x1000),
+                Segment::hole(0x1000, 0x2000),
+                Segment::data(0x2000, 0x2400),
+            ]
+        );
+
+        let bad = "0x0 +\n0x0 0x10 x\n";
+        assert!(matches!(
+            from_ddrescue_map(bad.as_bytes()),
+            Err(MapfileError::Invalid { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn gnu_sparse_map_edges() {
+        // A leading and trailing hole
+        let segments = vec![
+            Segment::hole(0, 10),
+            Segment::data(10, 20),
+            Segment::hole(20, 30),
+        ];
+        let map = to_gnu_sparse_map(&segments);
+        assert_eq!(map, vec![(10, 10), (30, 0)]);
+        assert_eq!(from_gnu_sparse_map(map, 30), segments);
+
+        // Nothing but hole
+        let all_hole = vec![Segment::hole(0, 30)];
+        assert_eq!(to_gnu_sparse_map(&all_hole), vec![(30, 0)]);
+        assert_eq!(from_gnu_sparse_map([(30, 0)], 30), all_hole);
+        assert_eq!(from_gnu_sparse_map([], 0), vec![]);
+
+        // Overlapping pairs and pairs past the end
+        assert_eq!(
+            from_gnu_sparse_map([(0, 10), (5, 10), (25, 10)], 30),
+            vec![
+                Segment::data(0, 15),
+                Segment::hole(15, 25),
+                Segment::data(25, 30),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1135 @@
+//! Pure functions for building and checking lists of segments
+//!
+//! These do not touch any files, so the boundary maths in them can be tested
+//! on every platform no matter which backend they are used by
+use super::*;
+
+/// Builds the segments of the part of a file in `window` from the sorted
+/// ranges of it that have been allocated, one at a time
+///
+/// The holes are interleaved between the ranges as they arrive, so the
+/// segments come out in order without ever being collected and sorted.
+/// Zero length ranges are skipped, some drivers report them and they would
+/// otherwise turn into zero length or back to front holes. Ranges that touch
+/// or overlap, which a filesystem may report for allocations it has not
+/// coalesced, are merged into one data segment, so there is never a hole
+/// between them. Ranges that start before the one before them are out of
+/// order, and are reported as [`ScanError::Unordered`].
+#[cfg_attr(not(windows), allow(dead_code))]
+pub(crate) fn allocated_to_segments<I>(ranges: I, window: Range<u64>) -> RangeSegments<I::IntoIter>
+where
+    I: IntoIterator<Item = Result<Range<u64>, ScanError>>,
+{
+    RangeSegments::new(ranges.into_iter(), window, SegmentType::Data)
+}
+
+/// Builds the segments of the part of a file in `window` from the sorted
+/// ranges of it that are holes, one at a time
+///
+/// This is [`allocated_to_segments`] the other way round, with the data
+/// interleaved between the holes, and the same rules for ranges that are
+/// empty, touch, overlap or are out of order.
+#[cfg_attr(
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+    )),
+    allow(dead_code)
+)]
+pub(crate) fn holes_to_segments<I>(ranges: I, window: Range<u64>) -> RangeSegments<I::IntoIter>
+where
+    I: IntoIterator<Item = Result<Range<u64>, ScanError>>,
+{
+    RangeSegments::new(ranges.into_iter(), window, SegmentType::Hole)
+}
+
+/// The iterator returned by [`allocated_to_segments`] and
+/// [`holes_to_segments`]
+pub(crate) struct RangeSegments<I> {
+    ranges: I,
+    /// The type of the segments the ranges are, everything between them is
+    /// the opposite
+    segment_type: SegmentType,
+    /// The start of the window
+    start: u64,
+    /// Where the last segment ended
+    prev_end: u64,
+    /// The end of the window
+    end: u64,
+    /// The range that has been read but not returned yet, which the next
+    /// range may still extend
+    current: Option<Range<u64>>,
+    /// A segment that comes after the gap just returned
+    pending: Option<Segment>,
+}
+
+impl<I: Iterator<Item = Result<Range<u64>, ScanError>>> RangeSegments<I> {
+    fn new(ranges: I, window: Range<u64>, segment_type: SegmentType) -> Self {
+        RangeSegments {
+            ranges,
+            segment_type,
+            start: window.start,
+            prev_end: window.start,
+            end: window.end,
+            current: None,
+            pending: None,
+        }
+    }
+
+    fn next_segment(&mut self) -> Result<Option<Segment>, ScanError> {
+        if let Some(segment) = self.pending.take() {
+            return Ok(Some(segment));
+        }
+
+        // Stop reading once the ranges reach the end of the window
+        while self.current.as_ref().map_or(self.prev_end, |x| x.end) < self.end {
+            let Some(range) = self.ranges.next().transpose()? else {
+                break;
+            };
+            let start = range.start.max(self.start);
+            let end = range.end.min(self.end);
+            if start >= end {
+                continue;
+            }
+
+            match &mut self.current {
+                Some(current) if start < current.start => {
+                    return Err(ScanError::Unordered {
+                        start: range.start,
+                        end: range.end,
+                    });
+                }
+                Some(current) if start <= current.end => {
+                    current.end = current.end.max(end);
+                }
+                current => {
+                    if let Some(range) = current.replace(start..end) {
+                        return Ok(Some(self.segment(range)));
+                    }
+                }
+            }
+        }
+
+        if let Some(range) = self.current.take() {
+            return Ok(Some(self.segment(range)));
+        }
+
+        // Check to see if we need to fill in the gap at the end
+        if self.prev_end < self.end {
+            let prev_end = std::mem::replace(&mut self.prev_end, self.end);
+            return Ok(Some(Segment::new(
+                self.segment_type.opposite(),
+                prev_end,
+                self.end,
+            )));
+        }
+        Ok(None)
+    }
+
+    /// Returns the segment for `range`, or the gap before it if there is
+    /// one, keeping the segment to return next
+    fn segment(&mut self, range: Range<u64>) -> Segment {
+        let segment = Segment::new(self.segment_type, range.start, range.end);
+        let prev_end = std::mem::replace(&mut self.prev_end, range.end);
+        if prev_end == range.start {
+            return segment;
+        }
+        self.pending = Some(segment);
+        Segment::new(self.segment_type.opposite(), prev_end, range.start)
+    }
+}
+
+impl<I: Iterator<Item = Result<Range<u64>, ScanError>>> Iterator for RangeSegments<I> {
+    type Item = Result<Segment, ScanError>;
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        let next = self.next_segment();
+        if next.is_err() {
+            // Stop after an error
+            self.prev_end = self.end;
+            self.current = None;
+            self.pending = None;
+        }
+        next.transpose()
+    }
+}
+
+/// Trims a list of segments down to the part of them inside `window`
+///
+/// Segments entirely outside the window are dropped and the ones that cross
+/// its edges are cut short. An empty window has no segments.
+#[cfg_attr(
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        windows,
+    )),
+    allow(dead_code)
+)]
+pub(crate) fn clip(segments: Vec<Segment>, window: Range<u64>) -> Vec<Segment> {
+    segments
+        .iter()
+        .filter_map(|x| clip_segment(x, &window))
+        .collect()
+}
+
+/// Cuts `segment` down to the part of it inside `window`, if there is any
+pub(crate) fn clip_segment(segment: &Segment, window: &Range<u64>) -> Option<Segment> {
+    let start = segment.range.start.max(window.start);
+    let end = segment.range.end.min(window.end);
+    if start >= end {
+        return None;
+    }
+    Some(Segment {
+        segment_type: segment.segment_type,
+        range: start..end,
+        physical_offset: segment
+            .physical_offset
+            .and_then(|physical| physical.checked_add(start - segment.range.start)),
+    })
+}
+
+/// The number of bytes in the data and unwritten segments of a layout
+///
+/// This is the logical amount of data, filesystems that compress or
+/// deduplicate data may use less disk space than this. For the space the
+/// file really takes up see [`disk_usage`](SparseFile::disk_usage).
+/// ```
+/// # use drill_press::*;
+/// let segments = vec![
+///     Segment::data(0, 10),
+///     Segment::hole(10, 40),
+/// ];
+/// assert_eq!(allocated_bytes(&segments), 10);
+/// ```
+pub fn allocated_bytes(segments: &[Segment]) -> u64 {
+    segments
+        .iter()
+        .filter(|x| x.is_allocated())
+        .map(|x| x.len())
+        .sum()
+}
+
+/// The offset of the first byte of data in a layout, the start of the first
+/// data segment, or `None` if there is no data
+///
+/// Unwritten segments read back as zeros so they do not count as data, the
+/// same as for [`is_fully_sparse`](Segments::is_fully_sparse). Everything
+/// before this offset reads back as zeros.
+/// ```
+/// # use drill_press::*;
+/// let segments = vec![
+///     Segment::hole(0, 10),
+///     Segment::data(10, 20),
+///     Segment::hole(20, 40),
+/// ];
+/// assert_eq!(first_data_offset(&segments), Some(10));
+/// ```
+pub fn first_data_offset(segments: &[Segment]) -> Option<u64> {
+    segments.data_segments().next().map(|x| x.start())
+}
+
+/// The offset just past the last byte of data in a layout, the end of the
+/// last data segment, or `None` if there is no data
+///
+/// This is exclusive, so it is the length to truncate the file to, or to
+/// stop reading at, without losing anything but trailing zeros. Unwritten
+/// segments do not count as data, see [`first_data_offset`].
+/// ```
+/// # use drill_press::*;
+/// let segments = vec![
+///     Segment::hole(0, 10),
+///     Segment::data(10, 20),
+///     Segment::hole(20, 40),
+/// ];
+/// assert_eq!(last_data_offset(&segments), Some(20));
+/// ```
+pub fn last_data_offset(segments: &[Segment]) -> Option<u64> {
+    segments
+        .iter()
+        .rev()
+        .find(|x| x.is_data())
+        .map(|x| x.range.end)
+}
+
+/// Whether a segment starts and ends on a multiple of `block_size`, as
+/// returned by [`block_size`](SparseFile::block_size)
+///
+/// The last segment of a file usually ends part way through a block, and
+/// data segments that do not line up with blocks mean the layout was not
+/// reported by the filesystem, or the file shares blocks with others. A
+/// `block_size` of zero never lines up.
+/// ```
+/// # use drill_press::*;
+/// let segment = Segment::hole(4096, 8192);
+/// assert!(is_block_aligned(&segment, 4096));
+/// assert!(!is_block_aligned(&segment, 8192));
+/// ```
+pub fn is_block_aligned(segment: &Segment, block_size: u64) -> bool {
+    block_size > 0 && segment.range.start % block_size == 0 && segment.range.end % block_size == 0
+}
+
+/// The fraction of the bytes in a layout that are in holes, from `0.0` for a
+/// dense file to `1.0` for a file that is all hole
+///
+/// An empty layout has no holes so is `0.0`.
+/// ```
+/// # use drill_press::*;
+/// let segments = vec![
+///     Segment::data(0, 10),
+///     Segment::hole(10, 40),
+/// ];
+/// assert_eq!(sparsity(&segments), 0.75);
+/// ```
+pub fn sparsity(segments: &[Segment]) -> f64 {
+    let total: u64 = segments.iter().map(|x| x.len()).sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let holes: u64 = segments.holes().map(|x| x.end - x.start).sum();
+    holes as f64 / total as f64
+}
+
+/// A summary of a layout, see [`stats`]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SparseStats {
+    /// How many segments are data
+    pub data_segments: usize,
+    /// How many segments are holes
+    pub hole_segments: usize,
+    /// How many segments are unwritten, see [`SegmentType::Unwritten`]
+    pub unwritten_segments: usize,
+    /// The length of the file, the end of the last segment
+    pub total_len: u64,
+    /// How many bytes are in segments that take up space on the disk, see
+    /// [`allocated_bytes`]
+    pub allocated_bytes: u64,
+    /// The length of the longest hole
+    pub largest_hole: u64,
+    /// The length of the longest data segment
+    pub largest_data: u64,
+    /// How many segments there are for each MiB of the file, `0.0` for an
+    /// empty file
+    pub fragmentation: f64,
+}
+
+impl SparseStats {
+    /// The total number of segments
+    pub fn segment_count(&self) -> usize {
+        self.data_segments + self.hole_segments + self.unwritten_segments
+    }
+}
+
+/// Counts up the segments in a layout
+///
+/// Unwritten segments are counted on their own, and as allocated but not as
+/// data, see [`SegmentType::Unwritten`].
+///
+/// The segments are expected to be sorted and cover the file without gaps,
+/// as returned by [`scan_chunks`](SparseFile::scan_chunks).
+/// ```
+/// # use drill_press::*;
+/// let segments = vec![
+///     Segment::data(0, 10),
+///     Segment::hole(10, 40),
+/// ];
+/// let stats = stats(&segments);
+/// assert_eq!(stats.segment_count(), 2);
+/// assert_eq!(stats.largest_hole, 30);
+/// ```
+pub fn stats(segments: &[Segment]) -> SparseStats {
+    let mut stats = SparseStats::default();
+    for segment in segments {
+        match segment.segment_type {
+            SegmentType::Data => {
+                stats.data_segments += 1;
+                stats.largest_data = stats.largest_data.max(segment.len());
+            }
+            SegmentType::Hole => {
+                stats.hole_segments += 1;
+                stats.largest_hole = stats.largest_hole.max(segment.len());
+            }
+            SegmentType::Unwritten => stats.unwritten_segments += 1,
+        }
+    }
+    stats.allocated_bytes = allocated_bytes(segments);
+    stats.total_len = segments.last().map_or(0, |x| x.range.end);
+    if stats.total_len > 0 {
+        stats.fragmentation =
+            stats.segment_count() as f64 / (stats.total_len as f64 / (1024.0 * 1024.0));
+    }
+    stats
+}
+
+/// How closely two layouts have to agree to be considered the same
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tolerance {
+    /// Every segment has to match byte for byte
+    Exact,
+    /// Only the parts of holes that cover whole blocks of the given size are
+    /// compared, the partial blocks at the edges of a hole count as data.
+    ///
+    /// Filesystems can only deallocate whole blocks, so this lets a layout
+    /// recorded with byte precision match one reported by the filesystem.
+    Block(u64),
+}
+
+/// Finds the first offset at which two layouts disagree about whether it is a
+/// hole or data, or `None` if they agree everywhere
+///
+/// Both layouts are expected to be sorted and cover the file without gaps,
+/// as returned by [`scan_chunks`](SparseFile::scan_chunks). If one layout is
+/// longer than the other the end of the shorter one is where they disagree.
+pub fn first_divergence(a: &[Segment], b: &[Segment], tolerance: Tolerance) -> Option<u64> {
+    let a = with_tolerance(a, tolerance);
+    let b = with_tolerance(b, tolerance);
+
+    for (x, y) in a.iter().zip(b.iter()) {
+        if x.segment_type != y.segment_type || x.start() != y.start() {
+            return Some(x.start().min(y.start()));
+        }
+        if x.range.end != y.range.end {
+            return Some(x.range.end.min(y.range.end));
+        }
+    }
+
+    match a.len().cmp(&b.len()) {
+        std::cmp::Ordering::Less => Some(b[a.len()].start()),
+        std::cmp::Ordering::Greater => Some(a[b.len()].start()),
+        std::cmp::Ordering::Equal => None,
+    }
+}
+
+/// Rounds the holes in `segments` inwards to whole blocks, then merges any
+/// neighbouring segments that end up with the same type
+pub(crate) fn with_tolerance(segments: &[Segment], tolerance: Tolerance) -> Vec<Segment> {
+    let mut rounded = Vec::with_capacity(segments.len());
+    for segment in segments {
+        match tolerance {
+            Tolerance::Block(block_size) if block_size > 0 && segment.is_hole() => {
+                let Range { start, end } = segment.range;
+                let hole_start = start.div_ceil(block_size).saturating_mul(block_size);
+                let hole_end = end / block_size * block_size;
+                if hole_start < hole_end {
+                    push_merged(&mut rounded, SegmentType::Data, start..hole_start);
+                    push_merged(&mut rounded, SegmentType::Hole, hole_start..hole_end);
+                    push_merged(&mut rounded, SegmentType::Data, hole_end..end);
+                } else {
+                    push_merged(&mut rounded, SegmentType::Data, start..end);
+                }
+            }
+            _ => push_merged(&mut rounded, segment.segment_type, segment.range.clone()),
+        }
+    }
+    rounded
+}
+
+/// Turns every hole shorter than `min_hole` into data, merging it with the
+/// segments either side
+pub(crate) fn fill_short_holes(segments: &[Segment], min_hole: u64) -> Vec<Segment> {
+    let mut filled = Vec::with_capacity(segments.len());
+    for segment in segments {
+        let segment_type = if segment.is_hole() && segment.len() < min_hole {
+            SegmentType::Data
+        } else {
+            segment.segment_type
+        };
+        push_merged(&mut filled, segment_type, segment.range.clone());
+    }
+    filled
+}
+
+/// Pushes a segment onto the end of the list, extending the last segment
+/// instead if it is of the same type, zero length segments are dropped
+pub(crate) fn push_merged(
+    segments: &mut Vec<Segment>,
+    segment_type: SegmentType,
+    range: Range<u64>,
+) {
+    if range.start >= range.end {
+        return;
+    }
+    match segments.last_mut() {
+        Some(last) if last.segment_type == segment_type && last.range.end == range.start => {
+            last.range.end = range.end;
+        }
+        _ => segments.push(Segment::new(segment_type, range.start, range.end)),
+    }
+}
+
+/// Merges any neighbouring segments of the same type into one, so that holes
+/// and data alternate
+///
+/// Segments are only merged if one carries on exactly where the other stops,
+/// and for data with a known
+/// [`physical_offset`](Segment::physical_offset), only if they are next to
+/// each other on the device as well.
+/// ```
+/// # use drill_press::*;
+/// let segments = vec![
+///     Segment::data(0, 10),
+///     Segment::data(10, 20),
+///     Segment::hole(20, 40),
+/// ];
+/// assert_eq!(
+///     coalesce(&segments),
+///     vec![
+///         Segment::data(0, 20),
+///         Segment::hole(20, 40),
+///     ]
+/// );
+/// ```
+pub fn coalesce(segments: &[Segment]) -> Vec<Segment> {
+    let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+    for segment in segments {
+        let merges = merged
+            .last_mut()
+            .is_some_and(|last| merge_into(last, segment));
+        if !merges {
+            merged.push(segment.clone());
+        }
+    }
+    merged
+}
+
+/// Extends `last` to cover `next` if they can be merged, see [`coalesce`],
+/// returns whether it was merged
+pub(crate) fn merge_into(last: &mut Segment, next: &Segment) -> bool {
+    let same_place = match (last.physical_offset, next.physical_offset) {
+        (None, None) => true,
+        (Some(last_offset), Some(next_offset)) => {
+            last_offset.checked_add(last.len()) == Some(next_offset)
+        }
+        _ => false,
+    };
+    if last.segment_type == next.segment_type && last.range.end == next.range.start && same_place {
+        last.range.end = next.range.end;
+        return true;
+    }
+    false
+}
+
+/// The first problem [`validate`] found with a list of segments
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// A segment ends before it starts
+    #[error("Segment {start}..{end} is back to front")]
+    Backwards {
+        /// start of the segment
+        start: u64,
+        /// end of the segment
+        end: u64,
+    },
+    /// A segment covers no bytes at all
+    #[error("Empty segment at offset {offset}")]
+    Empty {
+        /// where the segment starts and ends
+        offset: u64,
+    },
+    /// A segment starts before the segment in front of it
+    #[error("Segment at offset {offset} is out of order")]
+    OutOfOrder {
+        /// start of the segment that is out of order
+        offset: u64,
+    },
+    /// A segment starts before the segment in front of it ends
+    #[error("Segments overlap at offset {offset}")]
+    Overlap {
+        /// start of the segment that overlaps the one before it
+        offset: u64,
+    },
+    /// No segment covers the bytes in `start..end`, including before the first
+    /// segment and after the last
+    #[error("Gap in the segments at {start}..{end}")]
+    Gap {
+        /// first byte that is not covered
+        start: u64,
+        /// first byte after the gap
+        end: u64,
+    },
+    /// The last segment carries on past the end of the file
+    #[error("Segments end at {end}, past the end of the file at {len}")]
+    PastEnd {
+        /// where the last segment ends
+        end: u64,
+        /// the length of the file
+        len: u64,
+    },
+}
+
+/// Checks that `segments` are sorted and cover `0..file_len` exactly once,
+/// with no gaps or empty segments, as returned by
+/// [`scan_chunks`](SparseFile::scan_chunks)
+///
+/// Returns the first problem found, which makes this handy for checking
+/// segments that came from somewhere else, such as another tool.
+/// ```
+/// # use drill_press::*;
+/// let segments = vec![
+///     Segment::data(0, 10),
+///     Segment::hole(20, 40),
+/// ];
+/// assert_eq!(
+///     validate(&segments, 40),
+///     Err(ValidationError::Gap { start: 10, end: 20 })
+/// );
+/// ```
+pub fn validate(segments: &[Segment], file_len: u64) -> Result<(), ValidationError> {
+    let mut previous: Option<&Range<u64>> = None;
+    for segment in segments {
+        let Range { start, end } = segment.range;
+        if start > end {
+            return Err(ValidationError::Backwards { start, end });
+        }
+        if start == end {
+            return Err(ValidationError::Empty { offset: start });
+        }
+        let expected = match previous {
+            Some(previous) if start < previous.start => {
+                return Err(ValidationError::OutOfOrder { offset: start });
+            }
+            Some(previous) if start < previous.end => {
+                return Err(ValidationError::Overlap { offset: start });
+            }
+            Some(previous) => previous.end,
+            None => 0,
+        };
+        if start > expected {
+            return Err(ValidationError::Gap {
+                start: expected,
+                end: start,
+            });
+        }
+        previous = Some(&segment.range);
+    }
+
+    let end = previous.map_or(0, |x| x.end);
+    match end.cmp(&file_len) {
+        std::cmp::Ordering::Less => Err(ValidationError::Gap {
+            start: end,
+            end: file_len,
+        }),
+        std::cmp::Ordering::Greater => Err(ValidationError::PastEnd { end, len: file_len }),
+        std::cmp::Ordering::Equal => Ok(()),
+    }
+}
+
+/// Splits the file into windows of `block_size` bytes and counts how full of
+/// data each one is
+///
+/// The five buckets are for windows that are 0%, 25%, 50%, 75% and 100%
+/// data. Only windows with no data at all go in the first bucket and only
+/// windows that are all data go in the last, partly filled windows go in
+/// whichever of the middle three is closest. The last window is shorter if
+/// the file is not a whole number of blocks long, and is judged on its own
+/// length. Unwritten segments hold no data so they count as empty, see
+/// [`SegmentType::Unwritten`]. A `block_size` of zero counts nothing.
+///
+/// The segments are expected to be sorted and cover the file without gaps,
+/// as returned by [`scan_chunks`](SparseFile::scan_chunks).
+/// ```
+/// # use drill_press::*;
+/// let segments = vec![
+///     Segment::data(0, 6),
+///     Segment::hole(6, 12),
+/// ];
+/// assert_eq!(fill_factor_histogram(&segments, 4), [1, 0, 1, 0, 1]);
+/// ```
+pub fn fill_factor_histogram(segments: &[Segment], block_size: u64) -> [usize; 5] {
+    let mut histogram = [0; 5];
+    if block_size == 0 {
+        return histogram;
+    }
+
+    let len = segments.last().map_or(0, |x| x.range.end);
+    let mut window_start = 0;
+    let mut window_data = 0;
+    for segment in segments {
+        let mut offset = segment.start();
+        while offset < segment.range.end {
+            // Skip over any whole windows the segment covers in one go
+            let whole_windows = (segment.range.end - offset) / block_size;
+            if offset == window_start && whole_windows > 0 {
+                let bucket = if segment.is_data() { 4 } else { 0 };
+                histogram[bucket] += whole_windows as usize;
+                offset += whole_windows * block_size;
+                window_start = offset;
+                continue;
+            }
+
+            let window_end = window_start.saturating_add(block_size).min(len);
+            let piece_end = segment.range.end.min(window_end);
+            if segment.is_data() {
+                window_data += piece_end - offset;
+            }
+            offset = piece_end;
+
+            if offset == window_end {
+                histogram[fill_bucket(window_data, window_end - window_start)] += 1;
+                window_start = window_end;
+                window_data = 0;
+            }
+        }
+    }
+    histogram
+}
+
+/// Which bucket of [`fill_factor_histogram`] a window with `data` bytes of
+/// data out of `len` goes in
+fn fill_bucket(data: u64, len: u64) -> usize {
+    if data == 0 {
+        0
+    } else if data >= len {
+        4
+    } else {
+        // round to the nearest quarter, but never all the way to empty or full
+        ((data as f64 / len as f64 * 4.0).round() as usize).clamp(1, 3)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocated(ranges: Vec<Range<u64>>, len: u64) -> Result<Vec<Segment>, ScanError> {
+        allocated_to_segments(ranges.into_iter().map(Ok), 0..len).collect()
+    }
+
+    fn holes(ranges: Vec<Range<u64>>, window: Range<u64>) -> Result<Vec<Segment>, ScanError> {
+        holes_to_segments(ranges.into_iter().map(Ok), window).collect()
+    }
+
+    #[test]
+    fn holes_at_the_edges() {
+        assert_eq!(holes(vec![], 0..0).unwrap(), vec![]);
+        assert_eq!(holes(vec![], 0..10).unwrap(), vec![Segment::data(0, 10)]);
+        assert_eq!(
+            holes(vec![0..5, 5..10], 0..10).unwrap(),
+            vec![Segment::hole(0, 10)]
+        );
+        assert_eq!(
+            holes(vec![0..4, 9..10], 0..10).unwrap(),
+            vec![
+                Segment::hole(0, 4),
+                Segment::data(4, 9),
+                Segment::hole(9, 10)
+            ]
+        );
+        // One byte of data either side of holes that overlap
+        assert_eq!(
+            holes(vec![1..5, 3..9], 0..10).unwrap(),
+            vec![
+                Segment::data(0, 1),
+                Segment::hole(1, 9),
+                Segment::data(9, 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn holes_in_window() {
+        // SEEK_DATA reports the end of a hole past the end of the window
+        assert_eq!(
+            holes(vec![0..8, 12..30], 5..20).unwrap(),
+            vec![
+                Segment::hole(5, 8),
+                Segment::data(8, 12),
+                Segment::hole(12, 20)
+            ]
+        );
+        assert_eq!(holes(vec![0..20, 20..30], 10..10).unwrap(), vec![]);
+        assert!(matches!(
+            holes(vec![2..4, 6..8, 0..1], 0..10),
+            Err(ScanError::Unordered { start: 0, end: 1 })
+        ));
+    }
+
+    #[test]
+    fn allocated_skips_zero_length_ranges() {
+        let ranges = vec![0..0, 10..20, 20..20, 30..40];
+        assert_eq!(
+            allocated(ranges, 50).unwrap(),
+            vec![
+                Segment::hole(0, 10),
+                Segment::data(10, 20),
+                Segment::hole(20, 30),
+                Segment::data(30, 40),
+                Segment::hole(40, 50)
+            ]
+        );
+    }
+
+    #[test]
+    fn allocated_zero_length_first_range() {
+        let ranges = vec![0..0, 0..10];
+        assert_eq!(allocated(ranges, 10).unwrap(), vec![Segment::data(0, 10)]);
+    }
+
+    #[test]
+    fn divergence_exact() {
+        let a = vec![
+            Segment::data(0, 10),
+            Segment::hole(10, 20),
+            Segment::data(20, 30),
+        ];
+        assert_eq!(first_divergence(&a, &a, Tolerance::Exact), None);
+
+        let b = vec![
+            Segment::data(0, 12),
+            Segment::hole(12, 20),
+            Segment::data(20, 30),
+        ];
+        assert_eq!(first_divergence(&a, &b, Tolerance::Exact), Some(10));
+
+        let c = vec![Segment::data(0, 10), Segment::hole(10, 20)];
+        assert_eq!(first_divergence(&a, &c, Tolerance::Exact), Some(20));
+
+        // adjacent segments of the same type are the same as one big one
+        let d = vec![
+            Segment::data(0, 5),
+            Segment::data(5, 10),
+            Segment::hole(10, 20),
+            Segment::data(20, 30),
+        ];
+        assert_eq!(first_divergence(&a, &d, Tolerance::Exact), None);
+    }
+
+    #[test]
+    fn divergence_block() {
+        let a = vec![
+            Segment::data(0, 10),
+            Segment::hole(10, 20),
+            Segment::data(20, 30),
+        ];
+        let b = vec![
+            Segment::data(0, 12),
+            Segment::hole(12, 20),
+            Segment::data(20, 30),
+        ];
+        assert_eq!(first_divergence(&a, &b, Tolerance::Block(4)), None);
+
+        let c = vec![
+            Segment::data(0, 16),
+            Segment::hole(16, 20),
+            Segment::data(20, 30),
+        ];
+        assert_eq!(first_divergence(&a, &c, Tolerance::Block(4)), Some(12));
+
+        // holes smaller than a block disappear entirely
+        let d = vec![Segment::data(0, 30)];
+        assert_eq!(first_divergence(&a, &d, Tolerance::Block(16)), None);
+    }
+
+    #[test]
+    fn clip_to_window() {
+        let segments = vec![
+            Segment::data(0, 4),
+            Segment::hole(4, 8),
+            Segment::data(8, 12),
+            Segment::hole(12, 16),
+        ];
+        assert_eq!(
+            clip(segments.clone(), 2..10),
+            vec![
+                Segment::data(2, 4),
+                Segment::hole(4, 8),
+                Segment::data(8, 10)
+            ]
+        );
+        assert_eq!(clip(segments.clone(), 5..7), vec![Segment::hole(5, 7)]);
+        assert_eq!(clip(segments.clone(), 4..8), vec![Segment::hole(4, 8)]);
+        assert_eq!(clip(segments.clone(), 6..6), vec![]);
+        assert_eq!(clip(segments, 20..30), vec![]);
+
+        let mut extent = Segment::data(4, 8);
+        extent.physical_offset = Some(100);
+        let mut clipped = Segment::data(6, 8);
+        clipped.physical_offset = Some(102);
+        assert_eq!(clip(vec![extent], 6..10), vec![clipped]);
+    }
+
+    #[test]
+    fn coalesce_fragments() {
+        let segments = vec![
+            Segment::data(0, 4),
+            Segment::data(4, 8),
+            Segment::data(8, 9),
+            Segment::hole(9, 12),
+            Segment::hole(12, 20),
+            Segment::data(20, 24),
+            Segment::hole(24, 30),
+        ];
+        assert_eq!(
+            coalesce(&segments),
+            vec![
+                Segment::data(0, 9),
+                Segment::hole(9, 20),
+                Segment::data(20, 24),
+                Segment::hole(24, 30)
+            ]
+        );
+
+        // extents that are not next to each other on disk stay apart
+        let mut first = Segment::data(0, 4);
+        first.physical_offset = Some(100);
+        let mut next = Segment::data(4, 8);
+        next.physical_offset = Some(104);
+        let mut apart = Segment::data(8, 12);
+        apart.physical_offset = Some(200);
+        let mut merged = Segment::data(0, 8);
+        merged.physical_offset = Some(100);
+        assert_eq!(coalesce(&[first, next, apart.clone()]), vec![merged, apart]);
+    }
+
+    #[test]
+    fn validate_finds_first_problem() {
+        let good = vec![Segment::data(0, 10), Segment::hole(10, 20)];
+        assert_eq!(validate(&good, 20), Ok(()));
+        assert_eq!(validate(&[], 0), Ok(()));
+        assert_eq!(
+            validate(&[], 5),
+            Err(ValidationError::Gap { start: 0, end: 5 })
+        );
+        assert_eq!(
+            validate(&good, 30),
+            Err(ValidationError::Gap { start: 20, end: 30 })
+        );
+        assert_eq!(
+            validate(&good, 15),
+            Err(ValidationError::PastEnd { end: 20, len: 15 })
+        );
+        assert_eq!(
+            validate(&[Segment::data(5, 10)], 10),
+            Err(ValidationError::Gap { start: 0, end: 5 })
+        );
+        assert_eq!(
+            validate(&[Segment::data(10, 5)], 10),
+            Err(ValidationError::Backwards { start: 10, end: 5 })
+        );
+        assert_eq!(
+            validate(&[Segment::data(0, 0)], 0),
+            Err(ValidationError::Empty { offset: 0 })
+        );
+        let overlap = vec![Segment::data(0, 10), Segment::hole(5, 20)];
+        assert_eq!(
+            validate(&overlap, 20),
+            Err(ValidationError::Overlap { offset: 5 })
+        );
+        let unsorted = vec![
+            Segment::data(0, 10),
+            Segment::hole(10, 20),
+            Segment::data(5, 8),
+        ];
+        assert_eq!(
+            validate(&unsorted, 20),
+            Err(ValidationError::OutOfOrder { offset: 5 })
+        );
+    }
+
+    #[test]
+    fn stats_of_layout() {
+        let mib = 1024 * 1024;
+        let segments = vec![
+            Segment::hole(0, mib),
+            Segment::data(mib, mib + 10),
+            Segment::new(SegmentType::Unwritten, mib + 10, mib + 30),
+            Segment::hole(mib + 30, 2 * mib),
+        ];
+        assert_eq!(
+            stats(&segments),
+            SparseStats {
+                data_segments: 1,
+                hole_segments: 2,
+                unwritten_segments: 1,
+                total_len: 2 * mib,
+                allocated_bytes: 30,
+                largest_hole: mib,
+                largest_data: 10,
+                fragmentation: 2.0,
+            }
+        );
+        assert_eq!(stats(&[]), SparseStats::default());
+    }
+
+    #[test]
+    fn allocated_in_window() {
+        let ranges = vec![0..10, 20..30, 40..50];
+        let segments: Result<Vec<_>, _> =
+            allocated_to_segments(ranges.into_iter().map(Ok), 5..45).collect();
+        assert_eq!(
+            segments.unwrap(),
+            vec![
+                Segment::data(5, 10),
+                Segment::hole(10, 20),
+                Segment::data(20, 30),
+                Segment::hole(30, 40),
+                Segment::data(40, 45)
+            ]
+        );
+    }
+
+    #[test]
+    fn allocated_overlapping_ranges() {
+        let ranges = vec![0..10, 5..20, 8..12, 30..40, 35..38, 38..45];
+        assert_eq!(
+            allocated(ranges, 50).unwrap(),
+            vec![
+                Segment::data(0, 20),
+                Segment::hole(20, 30),
+                Segment::data(30, 45),
+                Segment::hole(45, 50)
+            ]
+        );
+        assert!(matches!(
+            allocated(vec![10..20, 0..40], 40),
+            Err(ScanError::Unordered { start: 0, end: 40 })
+        ));
+    }
+
+    #[test]
+    fn first_and_last_data() {
+        let segments = vec![
+            Segment::new(SegmentType::Unwritten, 0, 5),
+            Segment::data(5, 10),
+            Segment::hole(10, 20),
+            Segment::data(20, 30),
+            Segment::new(SegmentType::Unwritten, 30, 40),
+        ];
+        assert_eq!(first_data_offset(&segments), Some(5));
+        assert_eq!(last_data_offset(&segments), Some(30));
+        assert_eq!(first_data_offset(&[Segment::data(0, 1)]), Some(0));
+        assert_eq!(last_data_offset(&[Segment::data(0, 1)]), Some(1));
+        assert_eq!(first_data_offset(&[Segment::hole(0, 10)]), None);
+        assert_eq!(
+            last_data_offset(&[Segment::new(SegmentType::Unwritten, 0, 10)]),
+            None
+        );
+        assert_eq!(first_data_offset(&[]), None);
+        assert_eq!(last_data_offset(&[]), None);
+    }
+
+    #[test]
+    fn short_holes_are_filled() {
+        let segments = vec![
+            Segment::hole(0, 5),
+            Segment::data(5, 10),
+            Segment::hole(10, 30),
+            Segment::data(30, 40),
+            Segment::hole(40, 45),
+            Segment::data(45, 50),
+            Segment::hole(50, 55),
+        ];
+        assert_eq!(
+            fill_short_holes(&segments, 10),
+            vec![
+                Segment::data(0, 10),
+                Segment::hole(10, 30),
+                Segment::data(30, 55)
+            ]
+        );
+        assert_eq!(fill_short_holes(&segments, 0), segments);
+        assert_eq!(fill_short_holes(&segments, 100), vec![Segment::data(0, 55)]);
+    }
+
+    #[test]
+    fn allocated_ranges_that_touch() {
+        let ranges = vec![0..10, 10..20, 30..40, 40..50];
+        assert_eq!(
+            allocated(ranges, 60).unwrap(),
+            vec![
+                Segment::data(0, 20),
+                Segment::hole(20, 30),
+                Segment::data(30, 50),
+                Segment::hole(50, 60)
+            ]
+        );
+    }
+
+    #[test]
+    fn extremes_of_the_address_space() {
+        const MAX: u64 = u64::MAX;
+        assert_eq!(
+            allocated(vec![0..1, MAX - 1..MAX], MAX).unwrap(),
+            vec![
+                Segment::data(0, 1),
+                Segment::hole(1, MAX - 1),
+                Segment::data(MAX - 1, MAX)
+            ]
+        );
+        assert_eq!(
+            allocated(vec![0..0, 0..MAX], MAX).unwrap(),
+            vec![Segment::data(0, MAX)]
+        );
+
+        let far = Segment {
+            physical_offset: Some(MAX),
+            ..Segment::data(0, 10)
+        };
+        let next = Segment {
+            physical_offset: Some(0),
+            ..Segment::data(10, 20)
+        };
+        assert_eq!(
+            coalesce(&[far.clone(), next.clone()]),
+            vec![far.clone(), next]
+        );
+        assert_eq!(clip(vec![far], 5..10)[0].physical_offset, None);
+
+        assert_eq!(
+            fill_factor_histogram(&[Segment::data(0, MAX)], MAX),
+            [0, 0, 0, 0, 1]
+        );
+        assert_eq!(
+            fill_factor_histogram(&[Segment::hole(0, 1), Segment::data(1, MAX)], MAX),
+            [0, 0, 0, 1, 0]
+        );
+    }
+
+    #[test]
+    fn allocated_nothing_is_one_hole() {
+        // a sparse file that has had all of its contents punched out
+        assert_eq!(allocated(vec![], 100).unwrap(), vec![Segment::hole(0, 100)]);
+    }
+
+    #[test]
+    fn fill_factor() {
+        let segments = vec![
+            Segment::data(0, 6),
+            Segment::hole(6, 12),
+            Segment::data(12, 13),
+            Segment::hole(13, 20),
+            Segment::data(20, 23),
+        ];
+        // windows: 0..4 full, 4..8 half, 8..12 empty, 12..16 quarter,
+        // 16..20 empty, 20..23 full
+        assert_eq!(fill_factor_histogram(&segments, 4), [2, 1, 1, 0, 2]);
+        assert_eq!(fill_factor_histogram(&segments, 100), [0, 0, 1, 0, 0]);
+        assert_eq!(fill_factor_histogram(&[], 4), [0; 5]);
+
+        let unwritten = Segment::new(SegmentType::Unwritten, 2, 8);
+        assert_eq!(
+            fill_factor_histogram(&[Segment::data(0, 2), unwritten], 4),
+            [1, 0, 1, 0, 0]
+        );
+    }
+
+    #[test]
+    fn allocated_and_sparsity() {
+        assert_eq!(allocated_bytes(&[]), 0);
+        assert_eq!(sparsity(&[]), 0.0);
+
+        let segments = vec![
+            Segment::hole(0, 10),
+            Segment::data(10, 20),
+            Segment::hole(20, 40),
+        ];
+        assert_eq!(allocated_bytes(&segments), 10);
+        assert_eq!(sparsity(&segments), 0.75);
+        assert_eq!(sparsity(&[Segment::data(0, 10)]), 0.0);
+        assert_eq!(sparsity(&[Segment::hole(0, 10)]), 1.0);
+    }
+}
@@ -5,27 +5,92 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
 #![doc = include_str!("../README.md")]
 
-use std::io::{Read, Seek};
-use std::ops::Range;
+use std::io::{Read, Seek, SeekFrom};
+use std::iter::Peekable;
+use std::ops::{ControlFlow, Deref, Range};
+use std::path::{Path, PathBuf};
 use std::slice::Iter;
+use std::sync::mpsc::{SendError, Sender};
+use std::sync::Arc;
 use thiserror::Error;
 
+#[cfg(unix)]
+use std::os::unix::io::BorrowedFd;
+#[cfg(windows)]
+use std::os::windows::io::BorrowedHandle;
+
 cfg_if::cfg_if! {
-    if #[cfg(any(target_os = "linux",
-                 target_os = "android",
-                 target_os = "freebsd",
-                 target_os = "macos",
-    ))]{
+    if #[cfg(any(target_os = "linux", target_os = "android"))]{
+        mod fiemap;
+        mod sys;
+        mod unix;
+        use unix as platform;
+    } else if #[cfg(target_os = "freebsd")]{
+        mod sys;
         mod unix;
+        use unix as platform;
+    } else if #[cfg(target_os = "macos")] {
+        mod macos;
+        mod sys;
+        mod unix;
+        use unix as platform;
     } else if #[cfg(windows)] {
+        mod sys;
         mod windows;
+        use windows as platform;
     } else {
         mod default;
+        use default as platform;
     }
 }
 
-#[cfg(test)]
-mod test_utils;
+#[cfg(feature = "tokio")]
+mod asynchronous;
+#[cfg(feature = "tokio")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tokio")))]
+pub use asynchronous::scan_chunks_async;
+
+mod cache;
+pub use cache::CachedScanner;
+
+mod capabilities;
+pub use capabilities::{Capabilities, CapabilityCache};
+
+mod compare;
+pub use compare::{contents_equal_sparse, layouts_equal};
+
+mod copy;
+pub use copy::{apply_map, clone_file, clone_range, copy_sparse, copy_sparse_with, CopyOptions};
+
+mod fallback;
+pub use fallback::scan_mmap;
+
+mod formats;
+pub use formats::{
+    from_ddrescue_map, from_gnu_sparse_map, to_ddrescue_map, to_gnu_sparse_map, MapfileError,
+};
+
+mod layout;
+pub use layout::{
+    allocated_bytes, coalesce, fill_factor_histogram, first_data_offset, first_divergence,
+    is_block_aligned, last_data_offset, sparsity, stats, validate, SparseStats, Tolerance,
+    ValidationError,
+};
+
+mod map;
+pub use map::{diff, Change, SparseMap};
+
+pub mod prelude;
+
+mod reader;
+pub use reader::{DataChunks, SparseReader};
+
+mod writer;
+pub use writer::SparseWriter;
+
+#[cfg(any(test, feature = "test-fixtures"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "test-fixtures")))]
+pub mod fixtures;
 
 #[derive(Error, Debug)]
 /// Errors returned by [`scan_chunks`](SparseFile::scan_chunks)
@@ -33,25 +98,281 @@ pub enum ScanError {
     /// the syscall for scanning allocated chunks of file failed with IO error
     #[error("IO Error occurred")]
     IO(#[from] std::io::Error),
+    /// A system call failed, naming the call so that failures from different
+    /// parts of a scan can be told apart
+    #[error("{call} failed")]
+    Syscall {
+        /// the name of the system call, or ioctl, that failed
+        call: &'static str,
+        /// the error returned by the system call
+        source: std::io::Error,
+    },
     /// This will be returned if you compile for a target that this crate does not support
     #[error("The operation you are trying to perform is not supported on this platform")]
     UnsupportedPlatform,
     /// If the OS reports that the file system the file is on does not support sparse files
+    ///
+    /// This is what `lseek` failing with `EINVAL` for `SEEK_HOLE` or
+    /// `SEEK_DATA` is turned into, and `ERROR_INVALID_FUNCTION` from
+    /// `FSCTL_QUERY_ALLOCATED_RANGES` on Windows. The scans catch it and read
+    /// the file instead, see the crate documentation for the filesystems
+    /// known to need that. The exception is macOS, where the only filesystem
+    /// without `SEEK_HOLE` is HFS+, which can not have holes at all, so the
+    /// scans report the whole file as data without reading it.
     #[error("The filesystem does not support operating on sparse files")]
     UnsupportedFileSystem,
+    /// The file is something like a pipe or socket that can not be seeked
+    /// around, so it can not have holes
+    #[error("The file is not seekable")]
+    NotSeekable,
+    /// The file is a kind of file, such as a directory, that can not be scanned
+    #[error("The type of file can not be scanned")]
+    UnsupportedFileType,
+    /// The file is encrypted with EFS on NTFS, so the layout the OS reports
+    /// for it may not match the data that reads back from it
+    ///
+    /// Only returned on Windows, where `FSCTL_QUERY_ALLOCATED_RANGES` can
+    /// report the wrong ranges for encrypted files. To copy one without
+    /// losing its holes or decrypting it, use `ReadEncryptedFileRaw` and
+    /// `WriteEncryptedFileRaw`, which keep the layout as part of the raw
+    /// encrypted data. [`allocation_info`](SparseFile::allocation_info) still
+    /// works on them, to find out whether a file is encrypted.
+    #[error("The file is encrypted, so its layout can not be trusted")]
+    Encrypted,
+    /// A cross check found that the OS reported a hole where the file does not
+    /// read back as zeros, see [`ScanOptions::cross_check`]
+    #[error("The OS reported a hole at offset {offset} that does not contain zeros")]
+    Inconsistent {
+        /// The first byte where the two scans disagree
+        offset: u64,
+    },
+    /// The requested range does not line up with the block size of the filesystem
+    #[error("Range {start}..{end} is not aligned to the filesystem block size of {block_size}")]
+    Misaligned {
+        /// start of the requested range
+        start: u64,
+        /// end of the requested range
+        end: u64,
+        /// the block size the range must be a multiple of
+        block_size: u64,
+    },
+    /// The requested range is not within the bounds of the file
+    #[error("Range {start}..{end} is out of bounds for a file of length {len}")]
+    OutOfBounds {
+        /// start of the requested range
+        start: u64,
+        /// end of the requested range
+        end: u64,
+        /// the length of the file
+        len: u64,
+    },
+    /// The OS reported a segment that ends before it starts
+    #[error("The OS reported a back to front segment {start}..{end}")]
+    InvalidSegment {
+        /// start of the reported segment
+        start: u64,
+        /// end of the reported segment
+        end: u64,
+    },
+    /// A list of ranges was not sorted, or two of the ranges overlapped
+    #[error("Range {start}..{end} overlaps or is before the previous range")]
+    Unordered {
+        /// start of the offending range
+        start: u64,
+        /// end of the offending range
+        end: u64,
+    },
+    /// The progress callback passed to
+    /// [`scan_chunks_with`](SparseFile::scan_chunks_with) asked for the scan
+    /// to stop
+    #[error("The scan was cancelled")]
+    Cancelled,
+    /// The file changed length while it was being scanned, so the segments
+    /// found may not match what is in it now
+    ///
+    /// Something else was writing to or truncating the file, scanning it
+    /// again once it has settled down will give a coherent layout.
+    #[error("The file changed length from {scanned} to {len} bytes during the scan")]
+    Changed {
+        /// the length of the file the scan found
+        scanned: u64,
+        /// the length of the file after the scan
+        len: u64,
+    },
+}
+
+impl ScanError {
+    /// Builds a [`ScanError::Syscall`] for `call` from the last OS error
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub(crate) fn last_os_error(call: &'static str) -> Self {
+        ScanError::Syscall {
+            call,
+            source: std::io::Error::last_os_error(),
+        }
+    }
+}
+
+/// Sends a scan error down `tx`, only returning it if there is no one there
+/// to receive it
+fn send_error(tx: &Sender<Result<Segment, ScanError>>, e: ScanError) -> Result<(), ScanError> {
+    match tx.send(Err(e)) {
+        Err(SendError(Err(e))) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Scans `file` into `out`, calling `progress` with the end of each segment,
+/// see [`scan_into`](SparseFile::scan_into) and
+/// [`scan_chunks_with`](SparseFile::scan_chunks_with)
+fn scan_into_with<F: SparseFile + ?Sized>(
+    file: &mut F,
+    out: &mut Vec<Segment>,
+    mut progress: impl FnMut(u64) -> ControlFlow<()>,
+) -> Result<(), ScanError> {
+    out.clear();
+    let result = file.segments().and_then(|segments| {
+        for segment in segments {
+            let segment = segment?;
+            let end = segment.range.end;
+            out.push(segment);
+            if progress(end).is_break() {
+                return Err(ScanError::Cancelled);
+            }
+        }
+        Ok(())
+    });
+    // Nothing stops the file changing under the scan, but the one thing that
+    // is cheap to check is that it is still the same length
+    let result = result.and_then(|()| {
+        let scanned = out.last().map_or(0, |x| x.range.end);
+        let len = keep_position(file, |file| Ok(file.seek(SeekFrom::End(0))?))?;
+        if len != scanned {
+            return Err(ScanError::Changed { scanned, len });
+        }
+        Ok(())
+    });
+    if result.is_err() {
+        out.clear();
+    }
+    result
+}
+
+/// Runs `scan` on `file` and then seeks back to where the file was before,
+/// whether the scan worked or not
+///
+/// An error from the scan takes priority over an error seeking back. If the
+/// position can not be found at all, the file is something like a pipe, so
+/// `scan` is left to report that itself.
+pub(crate) fn keep_position<F: Seek + ?Sized, T>(
+    file: &mut F,
+    scan: impl FnOnce(&mut F) -> Result<T, ScanError>,
+) -> Result<T, ScanError> {
+    let Ok(position) = file.stream_position() else {
+        return scan(file);
+    };
+    let result = scan(file);
+    let restored = file.seek(SeekFrom::Start(position));
+    let value = result?;
+    restored?;
+    Ok(value)
+}
+
+/// Rejects a `block_size` of zero, which would never get anywhere
+fn check_block_size(block_size: u64) -> Result<(), ScanError> {
+    if block_size == 0 {
+        return Err(ScanError::IO(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "block size is zero",
+        )));
+    }
+    Ok(())
+}
+
+/// Finds where the first data, or the first hole, starts in `segments`,
+/// for [`next_data`](SparseFile::next_data) and
+/// [`next_hole`](SparseFile::next_hole)
+///
+/// The end of the last segment counts as a hole, so only a list with no
+/// segments at all has no next hole.
+pub(crate) fn next_of_type(
+    segments: impl IntoIterator<Item = Result<Segment, ScanError>>,
+    data: bool,
+) -> Result<Option<u64>, ScanError> {
+    let mut end = None;
+    for segment in segments {
+        let segment = segment?;
+        if segment.is_data() == data {
+            return Ok(Some(segment.start()));
+        }
+        end = Some(segment.range.end);
+    }
+    Ok(if data { None } else { end })
+}
+
+/// Writes zeros over `range` of `file`, so that it is allocated on the disk
+///
+/// This moves the seek position of the file.
+#[cfg_attr(
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        windows
+    )),
+    allow(dead_code)
+)]
+pub(crate) fn write_zeros(mut file: &std::fs::File, range: Range<u64>) -> Result<(), ScanError> {
+    use std::io::Write;
+
+    const CHUNK: u64 = 64 * 1024;
+    let zeros = vec![0_u8; (range.end - range.start).min(CHUNK) as usize];
+    file.seek(SeekFrom::Start(range.start))?;
+    let mut offset = range.start;
+    while offset < range.end {
+        let chunk = (range.end - offset).min(CHUNK) as usize;
+        file.write_all(&zeros[..chunk])?;
+        offset += chunk as u64;
+    }
+    Ok(())
 }
 
 /// Flag for determining if a segment is a hole, or if it contains data
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+///
+/// With the `serde` feature this serializes as a lowercase string, `"hole"`,
+/// `"data"` or `"unwritten"`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+#[non_exhaustive]
 pub enum SegmentType {
     /// A Hole segment is a sequence of zeros in a sparse file that does not take up space on disk
     Hole,
     /// A Data segment may or may not be zero but does take up space on the disk
     Data,
+    /// An Unwritten segment has had space set aside for it on the disk, for
+    /// example with `fallocate`, but has never been written to, so reads as
+    /// zeros
+    ///
+    /// Everywhere in this crate an unwritten segment takes up space but holds
+    /// no data. Anything that asks about what the file contains, like
+    /// [`is_data`](Segment::is_data), [`first_data_offset`] or
+    /// [`fill_factor_histogram`], treats it as a hole, and anything that asks
+    /// about disk space, like [`is_allocated`](Segment::is_allocated),
+    /// [`allocated_bytes`] or [`is_fully_dense`](Segments::is_fully_dense),
+    /// treats it as data.
+    ///
+    /// Only [`scan_extents`](SparseFile::scan_extents) reports these,
+    /// [`scan_chunks`](SparseFile::scan_chunks) reports them as either holes
+    /// or data depending on the filesystem.
+    Unwritten,
 }
 
 impl SegmentType {
     /// The opposite segement type
+    ///
+    /// As unwritten segments take up space on the disk, the opposite of
+    /// `Unwritten` is `Hole`.
     /// ```
     /// # use drill_press::SegmentType;
     /// let data = SegmentType::Data;
@@ -60,19 +381,121 @@ impl SegmentType {
     pub fn opposite(&self) -> Self {
         match self {
             SegmentType::Hole => SegmentType::Data,
-            SegmentType::Data => SegmentType::Hole,
+            SegmentType::Data | SegmentType::Unwritten => SegmentType::Hole,
         }
     }
 }
 
+/// Prints the type in capitals, `HOLE`, `DATA` or `UNWRITTEN`, respecting
+/// any width and alignment
+impl std::fmt::Display for SegmentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            SegmentType::Hole => "HOLE",
+            SegmentType::Data => "DATA",
+            SegmentType::Unwritten => "UNWRITTEN",
+        })
+    }
+}
+
+/// Parses `hole`, `data` or `unwritten`, ignoring case, so anything
+/// [`Display`](std::fmt::Display) prints can be read back
+/// ```
+/// # use drill_press::SegmentType;
+/// assert_eq!("hole".parse(), Ok(SegmentType::Hole));
+/// assert_eq!(SegmentType::Data.to_string().parse(), Ok(SegmentType::Data));
+/// assert!("sparse".parse::<SegmentType>().is_err());
+/// ```
+impl std::str::FromStr for SegmentType {
+    type Err = ParseSegmentTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("hole") {
+            Ok(SegmentType::Hole)
+        } else if s.eq_ignore_ascii_case("data") {
+            Ok(SegmentType::Data)
+        } else if s.eq_ignore_ascii_case("unwritten") {
+            Ok(SegmentType::Unwritten)
+        } else {
+            Err(ParseSegmentTypeError(s.to_owned()))
+        }
+    }
+}
+
+/// The string given to [`SegmentType::from_str`](std::str::FromStr::from_str)
+/// is not the name of a segment type
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Unknown segment type {0:?}, expected hole, data or unwritten")]
+pub struct ParseSegmentTypeError(String);
+
 /// Describes the location of a chunk in the file, as well as indicating if it
 /// contains data or is a hole
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// Segments use half-open ranges, like [`Range`], on every platform: `start`
+/// is the first byte in the segment and `end` is one past the last byte. So
+/// the segments of a file fit together end to start with no gaps, and the
+/// last one ends at the length of the file.
+///
+/// Segments are ordered by where they start, then where they end, so sorting
+/// a list of them puts them in file order.
+///
+/// More fields may be added, so segments are built with
+/// [`Segment::new`], [`Segment::data`] or [`Segment::hole`] outside this
+/// crate rather than with a struct literal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub struct Segment {
     /// Marks this segment as either containing a hole, or containing data
     pub segment_type: SegmentType,
     /// the (half-open) range of bytes in the file covered by this segment
     pub range: Range<u64>,
+    /// Where the data starts on the underlying device, in bytes
+    ///
+    /// Only [`scan_extents`](SparseFile::scan_extents) fills this in, and
+    /// only for data it knows the location of. It is always `None` for holes
+    /// and for segments from [`scan_chunks`](SparseFile::scan_chunks).
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub physical_offset: Option<u64>,
+}
+
+impl PartialOrd for Segment {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Segment {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.range
+            .start
+            .cmp(&other.range.start)
+            .then(self.range.end.cmp(&other.range.end))
+            .then(self.segment_type.cmp(&other.segment_type))
+            .then(self.physical_offset.cmp(&other.physical_offset))
+    }
+}
+
+/// Prints the type, the half-open range and the length of the segment
+/// ```
+/// # use drill_press::*;
+/// let segment = Segment::data(0, 4096);
+/// assert_eq!(segment.to_string(), "DATA [0..4096) 4096 bytes");
+/// ```
+impl std::fmt::Display for Segment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} [{}..{}) {} bytes",
+            self.segment_type,
+            self.range.start,
+            self.range.end,
+            self.len()
+        )
+    }
 }
 
 /// An iterator over the ranges of a file of a specific [`SegmentType`]
@@ -94,31 +517,340 @@ impl<'a> Iterator for SegmentIter<'a> {
     }
 }
 
+/// An iterator over the segments of a file of a specific [`SegmentType`]
+///
+/// Unlike [`SegmentIter`] this yields the whole segment, not just its range.
+#[derive(Debug, Clone)]
+pub struct TypedSegmentIter<'a> {
+    segment_type: SegmentType,
+    iter: Iter<'a, Segment>,
+}
+
+impl<'a> Iterator for TypedSegmentIter<'a> {
+    type Item = &'a Segment;
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        let segment_type = self.segment_type;
+        self.iter
+            .find(|segment| segment.segment_type == segment_type)
+    }
+}
+
+/// An iterator over the data segments of a file, each paired with the number
+/// of hole bytes that follow it
+#[derive(Debug, Clone)]
+pub struct DataGapIter<'a> {
+    iter: Peekable<Iter<'a, Segment>>,
+}
+
+impl Iterator for DataGapIter<'_> {
+    type Item = (Segment, u64);
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        let data = self.iter.by_ref().find(|segment| segment.is_data())?;
+        let mut gap = 0;
+        while let Some(hole) = self.iter.next_if(|segment| !segment.is_data()) {
+            gap += hole.len();
+        }
+        Some((data.clone(), gap))
+    }
+}
+
+/// A lazy scan of the segments of a file, see [`SparseFile::segments`]
+///
+/// Neighbouring segments of the same type are merged as they are found, like
+/// [`coalesce`] does, so each segment is only returned once the OS has been
+/// asked for the one after it.
+pub struct ScanIter<'a> {
+    iter: Box<dyn Iterator<Item = Result<Segment, ScanError>> + 'a>,
+    /// What came after the last segment we returned
+    next: Option<Result<Segment, ScanError>>,
+}
+
+impl<'a> ScanIter<'a> {
+    pub(crate) fn new(iter: impl Iterator<Item = Result<Segment, ScanError>> + 'a) -> Self {
+        ScanIter {
+            iter: Box::new(iter),
+            next: None,
+        }
+    }
+
+    /// For scans that have to find every segment up front anyway
+    pub(crate) fn from_vec(segments: Vec<Segment>) -> Self {
+        ScanIter::new(segments.into_iter().map(Ok))
+    }
+}
+
+impl std::fmt::Debug for ScanIter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScanIter").finish_non_exhaustive()
+    }
+}
+
+impl Iterator for ScanIter<'_> {
+    type Item = Result<Segment, ScanError>;
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        let mut segment = match self.next.take().or_else(|| self.iter.next())? {
+            Ok(segment) => segment,
+            Err(e) => return Some(Err(e)),
+        };
+        loop {
+            match self.iter.next() {
+                Some(Ok(next)) if layout::merge_into(&mut segment, &next) => {}
+                next => {
+                    self.next = next;
+                    return Some(Ok(segment));
+                }
+            }
+        }
+    }
+}
+
 /// An extention trait to filter segments by Hole or Data segments
 pub trait Segments {
     /// An interator of only the data segments
-    fn data(&self) -> SegmentIter;
+    fn data(&self) -> SegmentIter<'_>;
     /// An iterator of only the hole segments
-    fn holes(&self) -> SegmentIter;
+    fn holes(&self) -> SegmentIter<'_>;
+    /// An iterator of the data segments, each paired with the number of hole
+    /// bytes between it and the next data segment, or the end of the file for
+    /// the last one
+    ///
+    /// Unwritten segments hold no data, so they are counted in the gap, see
+    /// [`SegmentType::Unwritten`]. Any hole before the first data segment is
+    /// not included, the start of the first data segment gives its size.
+    /// ```
+    /// # use drill_press::*;
+    /// let segments = vec![
+    ///     Segment::data(0, 10),
+    ///     Segment::hole(10, 15),
+    ///     Segment::data(15, 20),
+    /// ];
+    /// let gaps: Vec<u64> = segments.data_with_following_gap().map(|(_, gap)| gap).collect();
+    /// assert_eq!(gaps, vec![5, 0]);
+    /// ```
+    fn data_with_following_gap(&self) -> DataGapIter<'_>;
+    /// An iterator of only the segments of `segment_type`
+    /// ```
+    /// # use drill_press::*;
+    /// let segments = vec![
+    ///     Segment::data(0, 10),
+    ///     Segment::hole(10, 15),
+    /// ];
+    /// let holes: Vec<&Segment> = segments.iter_typed(SegmentType::Hole).collect();
+    /// assert_eq!(holes, vec![&segments[1]]);
+    /// ```
+    fn iter_typed(&self, segment_type: SegmentType) -> TypedSegmentIter<'_>;
+    /// An iterator of only the data segments, see
+    /// [`iter_typed`](Segments::iter_typed)
+    fn data_segments(&self) -> TypedSegmentIter<'_> {
+        self.iter_typed(SegmentType::Data)
+    }
+    /// An iterator of only the hole segments, see
+    /// [`iter_typed`](Segments::iter_typed)
+    fn hole_segments(&self) -> TypedSegmentIter<'_> {
+        self.iter_typed(SegmentType::Hole)
+    }
+    /// Returns true if there are no data segments, so the file could be
+    /// recreated just by setting its length
+    ///
+    /// Unwritten segments read back as zeros so they do not count as data.
+    /// An empty list is fully sparse.
+    /// ```
+    /// # use drill_press::*;
+    /// let segments = vec![
+    ///     Segment::hole(0, 10),
+    /// ];
+    /// assert!(segments.is_fully_sparse());
+    /// assert!(!segments.is_fully_dense());
+    /// ```
+    fn is_fully_sparse(&self) -> bool {
+        self.data_segments().next().is_none()
+    }
+    /// Returns true if there are no holes, so there is nothing to gain from a
+    /// sparse aware copy
+    ///
+    /// Unwritten segments are allocated so they do not count as holes. An
+    /// empty list is fully dense.
+    fn is_fully_dense(&self) -> bool {
+        self.hole_segments().next().is_none()
+    }
 }
 
-impl Segments for Vec<Segment> {
-    fn data(&self) -> SegmentIter {
+impl Segments for [Segment] {
+    fn data(&self) -> SegmentIter<'_> {
         SegmentIter {
             segment_type: SegmentType::Data,
             iter: self.iter(),
         }
     }
-    fn holes(&self) -> SegmentIter {
+    fn holes(&self) -> SegmentIter<'_> {
         SegmentIter {
             segment_type: SegmentType::Hole,
             iter: self.iter(),
         }
     }
+    fn data_with_following_gap(&self) -> DataGapIter<'_> {
+        DataGapIter {
+            iter: self.iter().peekable(),
+        }
+    }
+    fn iter_typed(&self, segment_type: SegmentType) -> TypedSegmentIter<'_> {
+        TypedSegmentIter {
+            segment_type,
+            iter: self.iter(),
+        }
+    }
+}
+
+/// A cheaply cloneable, thread safe list of segments
+///
+/// The segments are kept in a single shared allocation, so handing a scan
+/// result to another thread or storing it in a cache does not copy it.
+/// Derefs to `[Segment]` so it can be used anywhere a slice of segments can.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedSegments(Arc<[Segment]>);
+
+impl Deref for SharedSegments {
+    type Target = [Segment];
+    fn deref(&self) -> &[Segment] {
+        &self.0
+    }
+}
+
+impl From<Vec<Segment>> for SharedSegments {
+    fn from(segments: Vec<Segment>) -> Self {
+        SharedSegments(segments.into())
+    }
+}
+
+/// Options for [`scan_chunks_with_options`](SparseFile::scan_chunks_with_options)
+///
+/// The defaults give the same result as [`scan_chunks`](SparseFile::scan_chunks)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ScanOptions {
+    /// Scan the file a second time by reading it, and check that every hole
+    /// the OS reports reads back as zeros
+    ///
+    /// This is for catching bugs in the OS or this crate in tests, it reads
+    /// the whole file so it is far too slow for normal use. The read scan
+    /// works in blocks of the filesystem [`block_size`](SparseFile::block_size)
+    /// so only the whole blocks in each hole are checked.
+    pub cross_check: bool,
+    /// The shortest hole, in bytes, to report
+    ///
+    /// Any shorter hole is reported as data and merged with the segments
+    /// either side of it, so a tool copying the file can skip punching holes
+    /// too small to free anything on the destination, by setting this to its
+    /// block size. Zero, the default, reports every hole.
+    pub min_hole: u64,
+    /// Report the part of the file after the last data as a hole, true by
+    /// default
+    ///
+    /// The segments cover the whole length of the file by default, on every
+    /// platform, so a file that ends in a hole ends with a `Hole` segment.
+    /// When this is false that segment is left off, so the segments stop at
+    /// the end of the last data, and a file that is all hole has none at
+    /// all. The length of the file then has to be found some other way.
+    pub include_trailing_hole: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            cross_check: false,
+            min_hole: 0,
+            include_trailing_hole: true,
+        }
+    }
+}
+
+/// Options for [`trim_zero_data`](SparseFile::trim_zero_data)
+///
+/// The defaults report every run of zeroed blocks as a hole, without
+/// punching anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TrimOptions {
+    /// The shortest run of zeros, in bytes, to turn into a hole
+    ///
+    /// Short runs split data segments up for little saving, so raising this
+    /// keeps the layout from fragmenting. `None` uses the
+    /// [`block_size`](SparseFile::block_size) of the filesystem, zeros are
+    /// only ever found in whole blocks so anything smaller has no effect.
+    pub min_run: Option<u64>,
+    /// Drill out the runs of zeros, rather than only reporting them
+    pub punch: bool,
+}
+
+/// How a file is stored on the disk, see
+/// [`allocation_info`](SparseFile::allocation_info)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AllocationInfo {
+    /// The length of the file
+    pub len: u64,
+    /// How many bytes of disk space the file takes up
+    pub allocated_size: u64,
+    /// Whether the file is sparse, see [`is_sparse`](SparseFile::is_sparse)
+    pub sparse: bool,
+    /// Whether the filesystem compresses the file, in which case it can take
+    /// up less space than its data segments add up to even though it is not
+    /// sparse
+    ///
+    /// Only reported on Windows.
+    pub compressed: bool,
+    /// Whether the filesystem encrypts the file
+    ///
+    /// Only reported on Windows. The layout of encrypted files can not be
+    /// trusted, so they are not scanned, see [`ScanError::Encrypted`].
+    pub encrypted: bool,
+}
+
+/// The outcome of [`punch_holes`](SparseFile::punch_holes)
+///
+/// Every requested range ends up in exactly one of the two lists
+#[derive(Debug, Default)]
+pub struct PunchReport {
+    /// The ranges that were successfully turned into holes
+    pub punched: Vec<Range<u64>>,
+    /// The ranges that could not be punched, along with the reason why
+    pub failed: Vec<(Range<u64>, ScanError)>,
+}
+
+impl PunchReport {
+    /// Returns true if every requested range was punched
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
 }
 
-#[allow(clippy::len_without_is_empty)] // Segments should never be zero length
 impl Segment {
+    /// A segment of `segment_type` covering `start..end`, with no physical
+    /// offset
+    /// ```
+    /// # use drill_press::*;
+    /// let segment = Segment::new(SegmentType::Unwritten, 0, 10);
+    /// assert!(segment.is_unwritten());
+    /// assert_eq!(segment.range, 0..10);
+    /// ```
+    pub fn new(segment_type: SegmentType, start: u64, end: u64) -> Self {
+        Segment {
+            segment_type,
+            range: start..end,
+            physical_offset: None,
+        }
+    }
+
+    /// A data segment covering `start..end`
+    pub fn data(start: u64, end: u64) -> Self {
+        Segment::new(SegmentType::Data, start, end)
+    }
+
+    /// A hole covering `start..end`
+    pub fn hole(start: u64, end: u64) -> Self {
+        Segment::new(SegmentType::Hole, start, end)
+    }
+
     /// Returns true if the provided offset is within the range of bytes this
     /// segment specifies
     pub fn contains(&self, offset: &u64) -> bool {
@@ -131,28 +863,76 @@ impl Segment {
     }
 
     /// Returns true if this segment contains data
+    ///
+    /// Unwritten segments do not contain data, even though they take up
+    /// space on the disk, so this is false for them.
     pub fn is_data(&self) -> bool {
         self.segment_type == SegmentType::Data
     }
 
+    /// Returns true if this segment has space set aside on the disk but has
+    /// never been written to
+    pub fn is_unwritten(&self) -> bool {
+        self.segment_type == SegmentType::Unwritten
+    }
+
+    /// Returns true if this segment takes up space on the disk, which is
+    /// anything but a hole
+    pub fn is_allocated(&self) -> bool {
+        !self.is_hole()
+    }
+
     /// The starting position of this segment
     pub fn start(&self) -> u64 {
         self.range.start
     }
 
-    /// The number of bytes in this segment
+    /// The number of bytes in this segment, `end - start` as the range is half-open
     pub fn len(&self) -> u64 {
         self.range.end - self.range.start
     }
+
+    /// Returns true if this segment covers no bytes at all
+    ///
+    /// Segments returned from scanning a file are never empty
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+
+    /// The half-open range of bytes covered by this segment
+    /// ```
+    /// # use drill_press::*;
+    /// let segment = Segment::data(4, 8);
+    /// let file = [0_u8; 16];
+    /// assert_eq!(file[segment.range().start as usize..segment.range().end as usize].len(), 4);
+    /// assert!(segment.range().contains(&7));
+    /// ```
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
 }
 
 /// An extention trait for [`File`](std::fs::File) for sparse files
+///
+/// Only [`scan_chunks`](SparseFile::scan_chunks) and
+/// [`drill_hole`](SparseFile::drill_hole) have to be implemented. The other
+/// scans are built on `scan_chunks` by default, and the methods that need
+/// more from the OS than that return `Err(ScanError::UnsupportedPlatform)`.
 pub trait SparseFile: Read + Seek {
     /// Scans the file to find its logical chunks
     ///
     /// Will return a list of segments, ordered by their start position.
     ///
-    /// The ranges generated are guaranteed to cover all bytes in the file.
+    /// The ranges generated are guaranteed to cover all bytes in the file, and
+    /// none of them will be empty.
+    ///
+    /// The edge cases are the same on every platform. An empty file has no
+    /// segments at all, rather than one empty segment. A file that is all
+    /// hole, however long, is a single `Hole` segment, and a file that is all
+    /// data, down to a single byte, is a single `Data` segment. Data only
+    /// takes up part of a file in whole blocks, so a one byte write at the
+    /// start of a longer file is a `Data` segment the length of a block
+    /// followed by a `Hole`.
     ///
     /// `Hole` segments are guaranteed to represent a part of a file that does
     /// not contain any non-zero data, however, `Data` segments may represent
@@ -161,98 +941,2285 @@ pub trait SparseFile: Read + Seek {
     /// consult their documentation for how they handle sparse files for more
     /// details.
     ///
-    /// Does not make any guarantee about maintaining the Seek position of the
-    /// file, always seek back to a known point after calling this method.
+    /// The Seek position of the file is the same after scanning as it was
+    /// before, even if the scan fails.
+    ///
+    /// If the filesystem can not report where its holes are the file is
+    /// scanned with [`scan_chunks_by_reading`](SparseFile::scan_chunks_by_reading)
+    /// instead, using the block size of the filesystem. On macOS that is only
+    /// HFS+, which can not have holes, so the file is reported as one data
+    /// segment instead of being read.
+    ///
+    /// On Linux and Android the extent map is fetched with `FS_IOC_FIEMAP`
+    /// where the filesystem supports it, which takes far fewer syscalls than
+    /// seeking for every segment of a fragmented file but gives the same
+    /// segments.
+    ///
+    /// On Windows only files marked as sparse can have holes, NTFS fills in
+    /// any gap left in other files, for example by extending them with
+    /// `set_len`, with zeros on the disk. So a file that is not marked is
+    /// reported as a single `Data` segment, the same length as the file,
+    /// without asking for its allocated ranges. Use
+    /// [`drill_hole`](SparseFile::drill_hole) to mark a file and make holes in
+    /// it.
     ///
     /// # Errors
     ///
     /// Will return `Err(ScanError::UnsupportedPlatform)` if support is not
     /// implemented for filesystem level hole finding on your system
     ///
-    /// Will return `Err(ScanError::UnsupportedFileSystem)` if support is
-    /// implemented for your operating system, but the filesystem does not
-    /// support sparse files
+    /// Will return `Err(ScanError::InvalidSegment)` if the OS reports a
+    /// segment that ends before it starts
+    ///
+    /// Will return `Err(ScanError::NotSeekable)` for pipes and sockets, and
+    /// `Err(ScanError::UnsupportedFileType)` for directories, character
+    /// devices and other special files. Block devices are reported as a
+    /// single data segment on Linux and Android, apart from loop devices,
+    /// which have the same holes as the file behind them.
+    ///
+    /// Will return `Err(ScanError::Changed)` if the file is a different
+    /// length at the end of the scan than the segments found add up to,
+    /// because something else truncated or extended it part way through
     ///
     /// Will also return `Err` if any other I/O error occurs
     fn scan_chunks(&mut self) -> Result<Vec<Segment>, ScanError>;
 
-    /// Unallocate a section of the file, freeing the disk space and making
-    /// future reads return zeros
-    fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError>;
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::test_utils::*;
-    use quickcheck_macros::quickcheck;
-    use std::fs::File;
-
-    fn test_chunks_match(file: &mut File, input_segments: &[Segment]) -> bool {
-        // Get both sets of segments
-        let output_segments = file.scan_chunks().expect("Unable to scan chunks");
-
-        let segments_total = output_segments.iter().map(|x| x.len()).sum::<u64>();
+    /// Scans the file lazily, only asking the OS for each segment as it is
+    /// needed
+    ///
+    /// This finds the same segments as
+    /// [`scan_chunks`](SparseFile::scan_chunks), which just collects them, so
+    /// neighbouring segments of the same type are merged the same way as
+    /// [`coalesce`] does, but there is no need to hold them all at once and
+    /// stopping early skips the rest of the scan. After an error the iterator
+    /// ends. The Seek position of the file is put back once the iterator is
+    /// dropped.
+    ///
+    /// On filesystems that can not report holes the file is read to find
+    /// them, and that is done all up front.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks), either straight away or from
+    /// the iterator, apart from `ScanError::Changed` as the iterator can not
+    /// tell when it has been used for the last time
+    fn segments(&mut self) -> Result<ScanIter<'_>, ScanError> {
+        Ok(ScanIter::from_vec(self.scan_chunks()?))
+    }
 
-        let file_len = file.metadata().expect("file to exists").len();
+    /// Scans the file like [`scan_chunks`](SparseFile::scan_chunks), but
+    /// into a `Vec` that the caller already has, so scanning lots of files
+    /// one after another does not need a new allocation for each
+    ///
+    /// `out` is cleared first, and is left empty if the scan fails.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    fn scan_into(&mut self, out: &mut Vec<Segment>) -> Result<(), ScanError> {
+        scan_into_with(self, out, |_| ControlFlow::Continue(()))
+    }
 
-        if segments_total != file_len {
-            println!("Segment length {} != file len {}", segments_total, file_len);
-        }
+    /// Scans the file like [`scan_chunks`](SparseFile::scan_chunks), calling
+    /// `progress` with how far through the file the scan has got after each
+    /// segment is found
+    ///
+    /// `progress` can return `ControlFlow::Break` to stop the scan, for
+    /// example when the user gives up on a huge file.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::Cancelled)` if `progress` stops the scan
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    fn scan_chunks_with(
+        &mut self,
+        progress: impl FnMut(u64) -> ControlFlow<()>,
+    ) -> Result<Vec<Segment>, ScanError>
+    where
+        Self: Sized,
+    {
+        let mut segments = Vec::new();
+        scan_into_with(self, &mut segments, progress)?;
+        Ok(segments)
+    }
 
-        if *input_segments != output_segments {
-            println!("Expected: \n {:?} \n", input_segments);
-            println!("Got: \n {:?} \n", output_segments);
+    /// Scans only the part of the file from `start` up to `end`
+    ///
+    /// The segments are the same as [`scan_chunks`](SparseFile::scan_chunks)
+    /// would return, but clipped to exactly `start..end`, so the first and
+    /// last segments may be cut short. Parts of the window beyond the end of
+    /// the file are left out, so a window that starts at or after the end of
+    /// the file has no segments.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::InvalidSegment)` if `end` is before
+    /// `start`
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    fn scan_range(&mut self, start: u64, end: u64) -> Result<Vec<Segment>, ScanError> {
+        if start > end {
+            return Err(ScanError::InvalidSegment { start, end });
         }
-        *input_segments == output_segments
+        Ok(layout::clip(self.scan_chunks()?, start..end))
     }
 
-    // Creates a file based on desc, then tests that the resulting output of
-    // file.scan_chunks() matches the description used to create the file
-    fn test_round_trips(desc: SparseDescription) -> bool {
-        let mut file = desc.to_file();
-        // Get both sets of segments
-        let input_segments = desc.segments();
-        test_chunks_match(file.as_file_mut(), &input_segments)
+    /// Finds where the next data starts, at or after `from`
+    ///
+    /// Returns `None` if there is only hole from `from` to the end of the
+    /// file, or `from` is at or past the end. The default does a full
+    /// [`scan_range`](SparseFile::scan_range) from `from`, which unless that
+    /// is overridden too is a whole [`scan_chunks`](SparseFile::scan_chunks).
+    /// The Unix and Windows backends override it to only ask the OS about
+    /// what comes after `from`, on Unix that is a single `lseek` with
+    /// `SEEK_DATA`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    fn next_data(&mut self, from: u64) -> Result<Option<u64>, ScanError> {
+        next_of_type(self.scan_range(from, u64::MAX)?.into_iter().map(Ok), true)
     }
 
-    #[quickcheck]
-    fn round_trips(desc: SparseDescription) -> bool {
-        test_round_trips(desc)
+    /// Finds where the next hole starts, at or after `from`
+    ///
+    /// Like `SEEK_HOLE`, the end of the file counts as a hole, so this only
+    /// returns `None` if `from` is at or past the end of the file. As with
+    /// [`next_data`](SparseFile::next_data) the default does a full scan, and
+    /// the Unix and Windows backends override it with a bounded query.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    fn next_hole(&mut self, from: u64) -> Result<Option<u64>, ScanError> {
+        next_of_type(self.scan_range(from, u64::MAX)?.into_iter().map(Ok), false)
     }
 
-    #[quickcheck]
-    fn drill_hole(desc: SparseDescription, drop: u8) -> bool {
-        let mut file = desc.to_file();
-        // Get both sets of segments
-        let mut input_segments = desc.segments();
-
-        if input_segments.is_empty() {
-            return true;
-        }
+    /// Scans the file for the extents the filesystem has stored it in,
+    /// including where each one is on the underlying device
+    ///
+    /// Unlike [`scan_chunks`](SparseFile::scan_chunks) each extent is
+    /// reported as its own data segment, so there can be several data
+    /// segments in a row when the file is fragmented. The gaps between
+    /// extents are reported as holes, and extents that have been allocated
+    /// but not written to yet as [`Unwritten`](SegmentType::Unwritten). The
+    /// [`physical_offset`](Segment::physical_offset) of a data segment is
+    /// `None` if the filesystem does not know where it is yet, or the data is
+    /// stored in a way that does not map straight onto the device, for
+    /// example when compressed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::UnsupportedPlatform)` on platforms other
+    /// than Linux and Android, which use `FS_IOC_FIEMAP`
+    ///
+    /// Will return `Err(ScanError::UnsupportedFileSystem)` if the filesystem
+    /// can not map its extents
+    ///
+    /// Will also return `Err` if any other I/O error occurs
+    fn scan_extents(&mut self) -> Result<Vec<Segment>, ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
 
-        #[cfg(target_os = "macos")]
-        for hole in input_segments.holes() {
-            file.as_file_mut()
+    /// Unallocate a section of the file, freeing the disk space and making
+    /// future reads return zeros
+    ///
+    /// On Windows the file is marked as sparse first if it is not already.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::InvalidSegment)` if `end` is before
+    /// `start`
+    ///
+    /// Will return `Err(ScanError::UnsupportedFileSystem)` if the filesystem
+    /// can not deallocate parts of a file
+    ///
+    /// Will also return `Err` if any other I/O error occurs
+    fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError>;
+
+    /// Fills in every hole in the file, so that the whole file is allocated
+    /// on the disk, without changing what it reads back as
+    ///
+    /// This is the opposite of [`restore_sparseness`](SparseFile::restore_sparseness),
+    /// for handing a file to something that does not cope with holes. On
+    /// Linux and Android the holes are preallocated with `fallocate`, which
+    /// some filesystems, such as ext4, still report as holes to
+    /// [`scan_chunks`](SparseFile::scan_chunks) even though they take up
+    /// space, see [`scan_extents`](SparseFile::scan_extents). Anywhere else,
+    /// or if the filesystem can not preallocate, zeros are written over the
+    /// holes. On Windows the sparse attribute is cleared as well. Either way
+    /// [`is_sparse`](SparseFile::is_sparse) reports false afterwards. The
+    /// seek position is preserved.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if scanning the file fails, see
+    /// [`scan_chunks`](SparseFile::scan_chunks), or if writing fails
+    fn materialize(&mut self) -> Result<(), ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
+
+    /// Unallocate the `len` bytes starting at `start`, like
+    /// [`drill_hole`](SparseFile::drill_hole) but taking a length rather than
+    /// an end
+    ///
+    /// The range reads back as zeros afterwards and later scans report it as
+    /// a hole, though filesystems only deallocate whole blocks so any partial
+    /// blocks at the ends of the range are zeroed but stay data. The length
+    /// of the file is never changed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::InvalidSegment)` if `start + len`
+    /// overflows
+    ///
+    /// Will return `Err(ScanError::UnsupportedFileSystem)` if the filesystem
+    /// can not deallocate parts of a file
+    ///
+    /// Will also return `Err` if any other I/O error occurs
+    fn punch_hole(&mut self, start: u64, len: u64) -> Result<(), ScanError> {
+        let end = start.checked_add(len).ok_or(ScanError::InvalidSegment {
+            start,
+            end: u64::MAX,
+        })?;
+        self.drill_hole(start, end)
+    }
+
+    /// Drill a hole for each of the given segments
+    ///
+    /// The whole list is checked before anything is touched, the segments
+    /// must be sorted, not overlap each other and be aligned to the
+    /// [`block_size`](SparseFile::block_size) of the filesystem. After that
+    /// each hole is drilled in turn, a failure to drill one hole does not stop
+    /// the others being drilled, instead it is recorded in the returned
+    /// [`PunchReport`].
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::Unordered)` if the segments are not sorted or overlap
+    ///
+    /// Will return `Err(ScanError::Misaligned)` if a segment does not start
+    /// and end on a block boundary
+    ///
+    /// Will return `Err` if the block size can not be found
+    fn punch_holes(&mut self, holes: &[Segment]) -> Result<PunchReport, ScanError> {
+        let block_size = self.block_size()?;
+        let mut prev_end = 0;
+        for hole in holes {
+            let Range { start, end } = hole.range;
+            if start < prev_end || end < start {
+                return Err(ScanError::Unordered { start, end });
+            }
+            if start % block_size != 0 || end % block_size != 0 {
+                return Err(ScanError::Misaligned {
+                    start,
+                    end,
+                    block_size,
+                });
+            }
+            prev_end = end;
+        }
+
+        let mut report = PunchReport::default();
+        for hole in holes {
+            match self.drill_hole(hole.range.start, hole.range.end) {
+                Ok(()) => report.punched.push(hole.range.clone()),
+                Err(e) => report.failed.push((hole.range.clone(), e)),
+            }
+        }
+        Ok(report)
+    }
+
+    /// The block size of the filesystem the file is on
+    ///
+    /// This is `f_bsize` from `fstatvfs` on Unix and the cluster size on
+    /// Windows. Filesystems allocate space a whole block at a time, so the
+    /// boundaries between holes and data are normally multiples of this, see
+    /// [`is_block_aligned`].
+    fn block_size(&self) -> Result<u64, ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
+
+    /// Returns true if the file is sparse, without scanning it
+    ///
+    /// On Windows this checks the sparse attribute of the file, elsewhere it
+    /// is a cheap heuristic that checks whether fewer bytes are allocated on
+    /// disk than the length of the file. Neither says where the holes are,
+    /// and the heuristic can disagree with
+    /// [`scan_chunks`](SparseFile::scan_chunks): preallocated space that has
+    /// not been written yet counts as allocated even where a scan finds
+    /// holes, and filesystems that compress files, or count their metadata
+    /// against them, can make a file with no holes look sparse or one with
+    /// holes look dense. Use it to decide whether a scan is worth doing, not
+    /// in place of one.
+    fn is_sparse(&mut self) -> Result<bool, ScanError> {
+        Ok(self.allocation_info()?.sparse)
+    }
+
+    /// Scans the file and returns true if it is entirely holes, see
+    /// [`Segments::is_fully_sparse`]
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    fn is_fully_sparse(&mut self) -> Result<bool, ScanError> {
+        Ok(Segments::is_fully_sparse(&self.scan_chunks()?[..]))
+    }
+
+    /// Scans the file and returns true if it has no holes at all, see
+    /// [`Segments::is_fully_dense`]
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    fn is_fully_dense(&mut self) -> Result<bool, ScanError> {
+        Ok(Segments::is_fully_dense(&self.scan_chunks()?[..]))
+    }
+
+    /// Finds out how the file is stored on the disk, without scanning it
+    ///
+    /// This tells sparse files apart from ones that take up less space than
+    /// their length for other reasons, like NTFS compression, which
+    /// `FSCTL_QUERY_ALLOCATED_RANGES` reports as fully allocated. The
+    /// allocated size comes from `st_blocks` on Unix and
+    /// `GetFileInformationByHandleEx` on Windows.
+    fn allocation_info(&mut self) -> Result<AllocationInfo, ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
+
+    /// The apparent size of the file and the disk space it takes up, as
+    /// `(apparent, allocated)`, like `du --apparent-size` and `du` report
+    ///
+    /// The allocated size is `st_blocks * 512` on Unix and the
+    /// `AllocationSize` from `FileStandardInfo` on Windows, see
+    /// [`allocation_info`](SparseFile::allocation_info). That is the real
+    /// space used, so it includes any savings from compression, and whole
+    /// blocks for partly filled ones, which [`allocated_bytes`] does not. It
+    /// only adds up the lengths of the data segments.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`allocation_info`](SparseFile::allocation_info)
+    fn disk_usage(&mut self) -> Result<(u64, u64), ScanError> {
+        let info = self.allocation_info()?;
+        Ok((info.len, info.allocated_size))
+    }
+
+    /// The largest offset the file could be scanned up to
+    ///
+    /// This is the smaller of the biggest file the filesystem can hold and
+    /// the largest offset the platform's seek calls can express, so it can be
+    /// used to check that a planned file size is possible before writing it.
+    /// Where the filesystem does not report a limit this falls back to
+    /// `i64::MAX`, which is the limit on every supported platform, on Windows
+    /// this is always the case.
+    fn max_scannable_offset(&mut self) -> Result<u64, ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
+
+    /// Scans the file like [`scan_chunks`](SparseFile::scan_chunks), using
+    /// `cache` to avoid probing the filesystem again for every file on the
+    /// same device
+    ///
+    /// Once an earlier scan has found out whether a device can report holes,
+    /// files on it are either scanned by asking the OS, without falling back
+    /// to reading them, or read straight away.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    fn scan_chunks_cached_caps(
+        &mut self,
+        _cache: &CapabilityCache,
+    ) -> Result<Vec<Segment>, ScanError> {
+        self.scan_chunks()
+    }
+
+    /// Reads the contents of `segment` into `buf`, replacing whatever was in
+    /// it
+    ///
+    /// Data segments are read from the file, holes and unwritten segments are
+    /// filled with zeros without touching it. The seek position of the file
+    /// is the same afterwards as it was before.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::IO)` with `UnexpectedEof` if the file ends
+    /// before the end of a data segment, or if any other I/O error occurs
+    fn read_segment(&mut self, segment: &Segment, buf: &mut Vec<u8>) -> Result<(), ScanError> {
+        let len = usize::try_from(segment.len()).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::OutOfMemory,
+                "segment is too long to read into memory",
+            )
+        })?;
+        buf.clear();
+        buf.resize(len, 0);
+        if !segment.is_data() {
+            return Ok(());
+        }
+        let result = keep_position(self, |file| {
+            file.seek(SeekFrom::Start(segment.start()))?;
+            file.read_exact(buf)?;
+            Ok(())
+        });
+        if result.is_err() {
+            buf.clear();
+        }
+        result
+    }
+
+    /// Reads the data of the file in pieces of at most `chunk_size` bytes,
+    /// along with the offset in the file each piece came from, without ever
+    /// reading the holes
+    ///
+    /// The file is scanned with [`scan_chunks`](SparseFile::scan_chunks) when
+    /// the first piece is asked for, and then each data segment is split into
+    /// pieces and read with [`read_segment`](SparseFile::read_segment). A piece
+    /// never spans more than one data segment, so the last piece of each
+    /// segment may be short. This is what a backup that uploads files in
+    /// fixed size chunks needs to skip the holes and record where the data
+    /// goes.
+    /// ```
+    /// # use drill_press::*;
+    /// # use std::io::Write;
+    /// # let mut file = tempfile::tempfile().unwrap();
+    /// # file.write_all(&[1; 10]).unwrap();
+    /// for chunk in file.chunks(4) {
+    ///     let (offset, bytes) = chunk.unwrap();
+    ///     assert!(bytes.len() <= 4);
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0
+    ///
+    /// # Errors
+    ///
+    /// Yields `Err` and stops if the scan fails, under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks), or if reading a piece fails,
+    /// under the same conditions as [`read_segment`](SparseFile::read_segment)
+    fn chunks(&mut self, chunk_size: u64) -> DataChunks<'_, Self>
+    where
+        Self: Sized,
+    {
+        DataChunks::new(self, chunk_size)
+    }
+
+    /// Scans the file and sends each segment down `tx`, so the scan can be one
+    /// stage in a pipeline of threads
+    ///
+    /// Each segment is sent as soon as it is found, see
+    /// [`segments`](SparseFile::segments). Returns once every segment has been
+    /// sent, or as soon as the receiver is dropped. If the scan fails the
+    /// error is sent down the channel so the next stage sees it, it is only
+    /// returned from here if the receiver has already gone away and would
+    /// never see it.
+    fn scan_to_channel(&mut self, tx: Sender<Result<Segment, ScanError>>) -> Result<(), ScanError> {
+        let segments = match self.segments() {
+            Ok(segments) => segments,
+            Err(e) => return send_error(&tx, e),
+        };
+        for segment in segments {
+            let failed = segment.is_err();
+            match tx.send(segment) {
+                Err(SendError(Err(e))) => return Err(e),
+                Err(_) => break,
+                Ok(()) if failed => break,
+                Ok(()) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Scans the file and compares it against a previously recorded layout
+    ///
+    /// Returns `None` if the file still has the `expected` layout, or the
+    /// offset of the first byte where it differs. See [`first_divergence`]
+    /// for how `tolerance` is applied.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    fn has_drifted(
+        &mut self,
+        expected: &[Segment],
+        tolerance: Tolerance,
+    ) -> Result<Option<u64>, ScanError> {
+        Ok(first_divergence(&self.scan_chunks()?, expected, tolerance))
+    }
+
+    /// Scans the file and counts how full of data each `block_size` window of
+    /// it is, see [`fill_factor_histogram`] for how the windows are bucketed
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::IO)` with `ErrorKind::InvalidInput` if
+    /// `block_size` is zero
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    fn fill_factor_histogram(&mut self, block_size: u64) -> Result<[usize; 5], ScanError> {
+        check_block_size(block_size)?;
+        Ok(fill_factor_histogram(&self.scan_chunks()?, block_size))
+    }
+
+    /// Turn any blocks of zeros in the data segments of the file back into holes
+    ///
+    /// This is for repairing files that have lost their sparseness, for
+    /// example after being copied by a tool that does not understand holes.
+    /// The file is marked as sparse again where the platform needs that, then
+    /// every `block_size` aligned run of zeros inside a data segment is drilled
+    /// out. `block_size` should be a multiple of the
+    /// [`block_size`](SparseFile::block_size) of the filesystem, any partial
+    /// blocks at the edges of data segments are left alone.
+    ///
+    /// Returns the number of bytes that were turned into holes.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::IO)` with `ErrorKind::InvalidInput` if
+    /// `block_size` is zero
+    ///
+    /// Will return `Err` if scanning, reading or drilling the file fails
+    fn restore_sparseness(&mut self, block_size: u64) -> Result<u64, ScanError> {
+        check_block_size(block_size)?;
+
+        let segments = self.scan_chunks()?;
+        let mut reclaimed = 0;
+        for run in fallback::zero_runs(self, &segments, block_size)? {
+            self.drill_hole(run.start, run.end)?;
+            reclaimed += run.end - run.start;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Scans the file and reports the runs of zeros in its data segments as
+    /// holes, optionally punching them out of the file too
+    ///
+    /// Some filesystems and copy tools leave blocks that are all zeros
+    /// allocated, so they are reported as data. This reads every data segment
+    /// and turns each run of whole, zeroed, filesystem blocks that is at least
+    /// [`TrimOptions::min_run`] bytes long into a hole. Unlike
+    /// [`restore_sparseness`](SparseFile::restore_sparseness) nothing is
+    /// changed on disk unless [`TrimOptions::punch`] is set. The position of
+    /// the file is left where it was.
+    ///
+    /// Returns the layout of the file with the runs of zeros as holes.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if scanning, reading or drilling the file fails
+    fn trim_zero_data(&mut self, options: &TrimOptions) -> Result<Vec<Segment>, ScanError> {
+        let block_size = self.block_size()?;
+        let min_run = options.min_run.unwrap_or(block_size);
+        let segments = self.scan_chunks()?;
+        let mut runs = keep_position(self, |file| {
+            fallback::zero_runs(file, &segments, block_size)
+        })?;
+        runs.retain(|x| x.end - x.start >= min_run);
+
+        let mut trimmed = Vec::with_capacity(segments.len());
+        let mut zeros = runs.iter().peekable();
+        for segment in &segments {
+            let mut offset = segment.start();
+            while let Some(run) = zeros.next_if(|x| x.end <= segment.range.end) {
+                layout::push_merged(&mut trimmed, segment.segment_type, offset..run.start);
+                layout::push_merged(&mut trimmed, SegmentType::Hole, run.clone());
+                offset = run.end;
+            }
+            layout::push_merged(
+                &mut trimmed,
+                segment.segment_type,
+                offset..segment.range.end,
+            );
+        }
+
+        if options.punch {
+            for run in &runs {
+                self.drill_hole(run.start, run.end)?;
+            }
+        }
+        Ok(trimmed)
+    }
+
+    /// Insert a hole of `len` bytes at `offset`, shifting all the data after
+    /// `offset` up by `len` bytes and growing the file to match
+    ///
+    /// This is `fallocate` with `FALLOC_FL_INSERT_RANGE`, so nothing is
+    /// written, the filesystem moves the extents after `offset` along and
+    /// leaves the gap unallocated. Both `offset` and `len` must be multiples
+    /// of the filesystem block size and `offset` must be inside the file, it
+    /// is not possible to insert past the end.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::Misaligned)` if `offset` or `len` is not a
+    /// multiple of the filesystem block size
+    ///
+    /// Will return `Err(ScanError::OutOfBounds)` if `offset` is not before the
+    /// end of the file
+    ///
+    /// Will return `Err(ScanError::UnsupportedPlatform)` on platforms other
+    /// than Linux and Android
+    ///
+    /// Will return `Err(ScanError::UnsupportedFileSystem)` if the filesystem
+    /// can not insert ranges
+    fn insert_range(&mut self, _offset: u64, _len: u64) -> Result<(), ScanError> {
+        Err(ScanError::UnsupportedPlatform)
+    }
+
+    /// Scans the file by reading it `block_size` bytes at a time, rather
+    /// than asking the OS where the holes are
+    ///
+    /// Blocks that are all zeros are reported as holes and any other block
+    /// as data, with runs of the same type merged into one segment. This
+    /// works on any file, even on filesystems that do not support sparse
+    /// files, but it reads the whole file so is much slower than
+    /// [`scan_chunks`](SparseFile::scan_chunks). The last block is shorter
+    /// if the length of the file is not a multiple of `block_size`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::IO)` with `ErrorKind::InvalidInput` if
+    /// `block_size` is zero
+    ///
+    /// Will return `Err` if reading the file fails
+    fn scan_chunks_by_reading(&mut self, block_size: u64) -> Result<Vec<Segment>, ScanError> {
+        check_block_size(block_size)?;
+        keep_position(self, |file| {
+            fallback::scan_by_reading(file, block_size, 0..u64::MAX)
+        })
+    }
+
+    /// Scans the file like [`scan_chunks`](SparseFile::scan_chunks), with
+    /// extra [`ScanOptions`]
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ScanError::Inconsistent)` if `cross_check` is set and
+    /// the two scans disagree
+    ///
+    /// Will return `Err` under the same conditions as
+    /// [`scan_chunks`](SparseFile::scan_chunks)
+    fn scan_chunks_with_options(
+        &mut self,
+        options: &ScanOptions,
+    ) -> Result<Vec<Segment>, ScanError> {
+        let segments = self.scan_chunks()?;
+        if options.cross_check {
+            let block_size = self.block_size()?;
+            let read = self.scan_chunks_by_reading(block_size)?;
+            if let Some(offset) = fallback::first_inconsistency(&segments, &read, block_size) {
+                return Err(ScanError::Inconsistent { offset });
+            }
+        }
+        let mut segments = if options.min_hole > 0 {
+            layout::fill_short_holes(&segments, options.min_hole)
+        } else {
+            segments
+        };
+        if !options.include_trailing_hole && segments.last().is_some_and(Segment::is_hole) {
+            segments.pop();
+        }
+        Ok(segments)
+    }
+
+    /// Scans the file like [`scan_chunks`](SparseFile::scan_chunks), but
+    /// returns the segments as a [`SharedSegments`] that can be cloned and
+    /// sent between threads without copying the list
+    fn scan_shared_result(&mut self) -> Result<SharedSegments, ScanError> {
+        Ok(self.scan_chunks()?.into())
+    }
+}
+
+/// The platform backends only need a shared reference to the file, so they
+/// are implemented for `&File`, and this forwards to that.
+///
+/// A file that is shared, for example in an [`Arc`], can be scanned through
+/// a `&File`, but scanning still moves the seek position, and every handle
+/// to the same open file shares that one position. Two threads scanning the
+/// same file at once move it out from under each other and get the wrong
+/// segments, and each puts back the position it saw when it started. So
+/// scans of a shared file have to take turns, for example behind a `Mutex`,
+/// or each thread has to open the file for itself.
+impl SparseFile for std::fs::File {
+    fn scan_chunks(&mut self) -> Result<Vec<Segment>, ScanError> {
+        (&*self).scan_chunks()
+    }
+
+    fn segments(&mut self) -> Result<ScanIter<'_>, ScanError> {
+        platform::segments(self, 0..u64::MAX)
+    }
+
+    fn scan_range(&mut self, start: u64, end: u64) -> Result<Vec<Segment>, ScanError> {
+        (&*self).scan_range(start, end)
+    }
+
+    fn next_data(&mut self, from: u64) -> Result<Option<u64>, ScanError> {
+        (&*self).next_data(from)
+    }
+
+    fn next_hole(&mut self, from: u64) -> Result<Option<u64>, ScanError> {
+        (&*self).next_hole(from)
+    }
+
+    fn scan_extents(&mut self) -> Result<Vec<Segment>, ScanError> {
+        (&*self).scan_extents()
+    }
+
+    fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
+        (&self).drill_hole(start, end)
+    }
+
+    fn materialize(&mut self) -> Result<(), ScanError> {
+        (&*self).materialize()
+    }
+
+    fn insert_range(&mut self, offset: u64, len: u64) -> Result<(), ScanError> {
+        (&*self).insert_range(offset, len)
+    }
+
+    fn block_size(&self) -> Result<u64, ScanError> {
+        (&self).block_size()
+    }
+
+    fn is_sparse(&mut self) -> Result<bool, ScanError> {
+        (&*self).is_sparse()
+    }
+
+    fn allocation_info(&mut self) -> Result<AllocationInfo, ScanError> {
+        (&*self).allocation_info()
+    }
+
+    fn max_scannable_offset(&mut self) -> Result<u64, ScanError> {
+        (&*self).max_scannable_offset()
+    }
+
+    fn scan_chunks_cached_caps(
+        &mut self,
+        cache: &CapabilityCache,
+    ) -> Result<Vec<Segment>, ScanError> {
+        (&*self).scan_chunks_cached_caps(cache)
+    }
+}
+
+/// Opens the file at `path` read only and scans it, see
+/// [`scan_chunks`](SparseFile::scan_chunks)
+///
+/// On Windows the file is opened with `FILE_FLAG_BACKUP_SEMANTICS`, so that
+/// a process with the backup privilege can scan files it could not otherwise
+/// read, and the file can still be written or deleted by others while it is
+/// being scanned.
+///
+/// # Errors
+///
+/// Will return `Err(ScanError::IO)` if the file can not be opened
+///
+/// Will return `Err` under the same conditions as
+/// [`scan_chunks`](SparseFile::scan_chunks)
+pub fn scan_path(path: impl AsRef<Path>) -> Result<Vec<Segment>, ScanError> {
+    scan_open_options().open(path)?.scan_chunks()
+}
+
+/// How files are opened to be scanned by path, read only, and with
+/// `FILE_FLAG_BACKUP_SEMANTICS` on Windows
+fn scan_open_options() -> std::fs::OpenOptions {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true);
+    #[cfg(windows)]
+    std::os::windows::fs::OpenOptionsExt::custom_flags(
+        &mut options,
+        winapi::um::winbase::FILE_FLAG_BACKUP_SEMANTICS,
+    );
+    options
+}
+
+/// Scans each of the files in `paths` with [`scan_path`], for example every
+/// file in a directory listing
+///
+/// A file that can not be opened or scanned, say because permission is
+/// denied, does not stop the others being scanned, its error is returned
+/// alongside its path instead. The results are in the same order as `paths`.
+pub fn scan_all(
+    paths: impl IntoIterator<Item = PathBuf>,
+) -> Vec<(PathBuf, Result<Vec<Segment>, ScanError>)> {
+    paths
+        .into_iter()
+        .map(|path| {
+            let result = scan_path(&path);
+            (path, result)
+        })
+        .collect()
+}
+
+/// Scans the file behind a borrowed file descriptor
+///
+/// The descriptor is duplicated for the length of the scan, so ownership of
+/// `fd` stays with the caller and it is never closed here. The duplicate shares
+/// its seek position with `fd`, see [`scan_chunks`](SparseFile::scan_chunks)
+/// for what that means.
+#[cfg(unix)]
+#[cfg_attr(docsrs, doc(cfg(unix)))]
+pub fn scan_chunks_borrowed(fd: BorrowedFd<'_>) -> Result<Vec<Segment>, ScanError> {
+    std::fs::File::from(fd.try_clone_to_owned()?).scan_chunks()
+}
+
+/// Scans the file behind a borrowed handle
+///
+/// The handle is duplicated for the length of the scan, so ownership of
+/// `handle` stays with the caller and it is never closed here. The duplicate
+/// shares its seek position with `handle`, see
+/// [`scan_chunks`](SparseFile::scan_chunks) for what that means.
+#[cfg(windows)]
+#[cfg_attr(docsrs, doc(cfg(windows)))]
+pub fn scan_chunks_borrowed(handle: BorrowedHandle<'_>) -> Result<Vec<Segment>, ScanError> {
+    std::fs::File::from(handle.try_clone_to_owned()?).scan_chunks()
+}
+
+/// Opens the file at `path` for reading with backup semantics, so that it can
+/// be scanned even if its security settings would not let it be opened
+/// normally
+///
+/// This opens the file the way [`scan_path`] does, with
+/// `FILE_FLAG_BACKUP_SEMANTICS`, which is how backup programs read system
+/// files and files in Volume Shadow Copy snapshots. Other programs are left
+/// free to read, write and delete the file while it is open. The flag only
+/// gets past the security of the file if the `SeBackupPrivilege` is enabled
+/// on the token of the process. That needs the process to be running as an
+/// administrator or backup operator, and the privilege to have been enabled
+/// with `AdjustTokenPrivileges` first, which this does not do. Without it
+/// files are opened as normal.
+///
+/// # Errors
+///
+/// Will return `Err(ScanError::Syscall)` naming `CreateFileW` if the file
+/// can not be opened, with an error of kind `PermissionDenied` if access to
+/// it was denied
+#[cfg(windows)]
+#[cfg_attr(docsrs, doc(cfg(windows)))]
+pub fn open_for_scan(path: impl AsRef<Path>) -> Result<std::fs::File, ScanError> {
+    scan_open_options()
+        .open(path)
+        .map_err(|source| ScanError::Syscall {
+            call: "CreateFileW",
+            source,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use quickcheck_macros::quickcheck;
+    use std::fs::File;
+
+    fn test_chunks_match(file: &mut File, input_segments: &[Segment]) -> bool {
+        // Get both sets of segments
+        let output_segments = file.scan_chunks().expect("Unable to scan chunks");
+
+        let file_len = file.metadata().expect("file to exists").len();
+
+        if let Err(e) = validate(&output_segments, file_len) {
+            eprintln!("Invalid segments: {}", e);
+            return false;
+        }
+
+        if *input_segments != output_segments {
+            eprintln!("Expected: \n {:?} \n", input_segments);
+            eprintln!("Got: \n {:?} \n", output_segments);
+        }
+        *input_segments == output_segments
+    }
+
+    // Creates a file based on desc, then tests that the resulting output of
+    // file.scan_chunks() matches the description used to create the file
+    fn test_round_trips(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        // Get both sets of segments
+        let input_segments = desc.segments();
+        test_chunks_match(file.as_file_mut(), &input_segments)
+    }
+
+    #[quickcheck]
+    fn round_trips(desc: SparseDescription) -> bool {
+        test_round_trips(desc)
+    }
+
+    #[quickcheck]
+    fn no_empty_segments(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let segments = file
+            .as_file_mut()
+            .scan_chunks()
+            .expect("Unable to scan chunks");
+        segments.iter().all(|x| !x.is_empty())
+    }
+
+    #[test]
+    fn empty_and_tiny_files() {
+        use std::io::Write;
+
+        let scans = |file: &mut File| {
+            let segments = file.scan_chunks().expect("Unable to scan chunks");
+            let lazy: Result<Vec<_>, _> = file.segments().expect("Unable to scan").collect();
+            assert_eq!(lazy.expect("Unable to scan"), segments);
+            let read = file
+                .scan_chunks_by_reading(BLOCK_SIZE)
+                .expect("Unable to scan by reading");
+            assert_eq!(read, segments);
+            segments
+        };
+
+        // No segments at all, not an empty one
+        let mut empty = sparse_tempfile();
+        assert_eq!(scans(empty.as_file_mut()), vec![]);
+        assert_eq!(
+            empty.as_file_mut().scan_range(0, BLOCK_SIZE).expect("scan"),
+            vec![]
+        );
+
+        let mut one_byte = sparse_tempfile();
+        one_byte.as_file_mut().write_all(&[1]).expect("write");
+        assert_eq!(scans(one_byte.as_file_mut()), vec![Segment::data(0, 1)]);
+
+        // The byte takes up its whole block
+        one_byte
+            .as_file_mut()
+            .set_len(4 * BLOCK_SIZE)
+            .expect("extend");
+        assert_eq!(
+            scans(one_byte.as_file_mut()),
+            vec![
+                Segment::data(0, BLOCK_SIZE),
+                Segment::hole(BLOCK_SIZE, 4 * BLOCK_SIZE)
+            ]
+        );
+
+        let mut all_hole = sparse_tempfile();
+        all_hole
+            .as_file_mut()
+            .set_len(256 * BLOCK_SIZE)
+            .expect("extend");
+        assert_eq!(
+            scans(all_hole.as_file_mut()),
+            vec![Segment::hole(0, 256 * BLOCK_SIZE)]
+        );
+    }
+
+    #[quickcheck]
+    fn drill_hole(desc: SparseDescription, drop: u8) -> bool {
+        let mut file = desc.to_file();
+        // Get both sets of segments
+        let mut input_segments = desc.segments();
+
+        if input_segments.is_empty() {
+            return true;
+        }
+
+        #[cfg(target_os = "macos")]
+        for hole in input_segments.holes() {
+            file.as_file_mut()
                 .drill_hole(hole.start, hole.end)
                 .expect("pre drill holes");
         }
 
-        test_chunks_match(file.as_file_mut(), &input_segments);
+        test_chunks_match(file.as_file_mut(), &input_segments);
+
+        // pick a segment to make a hole
+        let drop_idx = drop as usize % input_segments.len();
+        let drop = &mut input_segments[drop_idx];
+
+        file.as_file_mut()
+            .drill_hole(drop.range.start, drop.range.end)
+            .expect("drilled hole");
+
+        drop.segment_type = SegmentType::Hole;
+
+        combine_segments(&mut input_segments);
+
+        test_chunks_match(file.as_file_mut(), &input_segments)
+    }
+
+    #[quickcheck]
+    fn punch_holes(desc: SparseDescription) -> bool {
+        let mut file = SparseDescription::one_segment(SegmentType::Data, desc.len()).to_file();
+        let file = file.as_file_mut();
+
+        let expected = desc.segments();
+        let holes: Vec<Segment> = expected.iter().filter(|x| x.is_hole()).cloned().collect();
+        let report = file.punch_holes(&holes).expect("valid holes");
+
+        report.is_complete()
+            && report.punched.len() == holes.len()
+            && test_chunks_match(file, &expected)
+    }
+
+    #[test]
+    fn punch_holes_validates_first() {
+        let mut file = SparseDescription::one_segment(SegmentType::Data, 4 * BLOCK_SIZE).to_file();
+        let file = file.as_file_mut();
+
+        let unordered = [
+            Segment::hole(2 * BLOCK_SIZE, 3 * BLOCK_SIZE),
+            Segment::hole(0, BLOCK_SIZE),
+        ];
+        assert!(matches!(
+            file.punch_holes(&unordered),
+            Err(ScanError::Unordered { .. })
+        ));
+        let misaligned = [
+            Segment::hole(0, BLOCK_SIZE),
+            Segment::hole(2 * BLOCK_SIZE, 3 * BLOCK_SIZE - 1),
+        ];
+        assert!(matches!(
+            file.punch_holes(&misaligned),
+            Err(ScanError::Misaligned { .. })
+        ));
+
+        let dense = vec![Segment::data(0, 4 * BLOCK_SIZE)];
+        assert!(test_chunks_match(file, &dense));
+    }
+
+    #[quickcheck]
+    fn restore_sparseness(desc: SparseDescription) -> bool {
+        let mut file = desc.to_dense_file();
+        let file = file.as_file_mut();
+
+        let reclaimed = file.restore_sparseness(BLOCK_SIZE).expect("restored");
+
+        reclaimed
+            == desc
+                .segments()
+                .holes()
+                .map(|x| x.end - x.start)
+                .sum::<u64>()
+            && test_chunks_match(file, &desc.segments())
+    }
+
+    #[quickcheck]
+    fn trim_zero_data(desc: SparseDescription) -> bool {
+        let mut file = desc.to_dense_file();
+        let file = file.as_file_mut();
+        let dense = file.scan_chunks().expect("scanned");
+
+        let mut options = TrimOptions {
+            min_run: Some(u64::MAX),
+            ..TrimOptions::default()
+        };
+        let untouched = file.trim_zero_data(&options).expect("trimmed");
+        options.min_run = None;
+        let trimmed = file.trim_zero_data(&options).expect("trimmed");
+        let still_dense = file.scan_chunks().expect("scanned");
+        options.punch = true;
+        let punched = file.trim_zero_data(&options).expect("trimmed");
+
+        untouched == dense
+            && trimmed == desc.segments()
+            && still_dense == dense
+            && punched == desc.segments()
+            && test_chunks_match(file, &desc.segments())
+    }
+
+    #[test]
+    fn max_scannable_offset() {
+        let mut file = SparseDescription::one_segment(SegmentType::Hole, BLOCK_SIZE).to_file();
+        let max = file
+            .as_file_mut()
+            .max_scannable_offset()
+            .expect("Unable to find max offset");
+        assert!(max >= BLOCK_SIZE && max <= i64::MAX as u64);
+    }
+
+    #[quickcheck]
+    fn data_with_following_gap(desc: SparseDescription) -> bool {
+        let mut next_start = None;
+        for (data, gap) in desc.segments().data_with_following_gap() {
+            if next_start.is_some_and(|start| start != data.start()) {
+                return false;
+            }
+            next_start = Some(data.range.end + gap);
+        }
+        next_start.map_or(true, |end| end == desc.len())
+    }
+
+    #[quickcheck]
+    fn has_drifted(desc: SparseDescription, drop: u8) -> bool {
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        let mut expected = desc.segments();
+        if file
+            .has_drifted(&expected, Tolerance::Exact)
+            .expect("scan")
+            .is_some()
+        {
+            return false;
+        }
+        if expected.is_empty() {
+            return true;
+        }
+
+        let drop_idx = drop as usize % expected.len();
+        let drop = &mut expected[drop_idx];
+        drop.segment_type = drop.segment_type.opposite();
+        let drop_start = drop.start();
+        let drifted = file
+            .has_drifted(&expected, Tolerance::Block(BLOCK_SIZE))
+            .expect("scan");
+        drifted == Some(drop_start)
+    }
+
+    #[quickcheck]
+    fn scan_to_channel(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let received = std::thread::spawn(move || rx.iter().collect::<Result<Vec<_>, _>>());
+
+        file.as_file_mut().scan_to_channel(tx).expect("scanned");
+        received.join().unwrap().expect("no errors") == desc.segments()
+    }
+
+    #[test]
+    fn scan_to_dropped_channel() {
+        let mut file = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3]).to_file();
+        let (tx, rx) = std::sync::mpsc::channel();
+        drop(rx);
+        file.as_file_mut()
+            .scan_to_channel(tx)
+            .expect("stops cleanly");
+    }
+
+    #[test]
+    fn capability_cache() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3]);
+        let cache = CapabilityCache::new();
+        for _ in 0..2 {
+            let mut file = desc.to_file();
+            let segments = file
+                .as_file_mut()
+                .scan_chunks_cached_caps(&cache)
+                .expect("Unable to scan chunks");
+            assert_eq!(segments, desc.segments());
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let device = desc.to_file().as_file().metadata().unwrap().dev();
+            let capabilities = cache.get(device).expect("device was probed");
+            assert!(capabilities.reports_holes);
+            assert!(capabilities.block_size > 0);
+        }
+    }
 
-        // pick a segment to make a hole
-        let drop_idx = drop as usize % input_segments.len();
-        let drop = &mut input_segments[drop_idx];
+    #[quickcheck]
+    fn cross_check(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let options = ScanOptions {
+            cross_check: true,
+            ..ScanOptions::default()
+        };
+        let segments = file
+            .as_file_mut()
+            .scan_chunks_with_options(&options)
+            .expect("scans agree");
+        segments == desc.segments()
+    }
 
-        file.as_file_mut()
-            .drill_hole(drop.range.start, drop.range.end)
-            .expect("drilled hole");
+    #[quickcheck]
+    fn min_hole(desc: SparseDescription, blocks: u8) -> bool {
+        let mut file = desc.to_file();
+        let options = ScanOptions {
+            min_hole: blocks as u64 * BLOCK_SIZE,
+            ..ScanOptions::default()
+        };
+        let segments = file
+            .as_file_mut()
+            .scan_chunks_with_options(&options)
+            .expect("Unable to scan");
+        validate(&segments, desc.len()).is_ok()
+            && segments
+                .holes()
+                .all(|x| x.end - x.start >= options.min_hole)
+            && desc.segments().data().all(|x| {
+                segments
+                    .data()
+                    .any(|y| y.start <= x.start && x.end <= y.end)
+            })
+    }
 
-        drop.segment_type = SegmentType::Hole;
+    #[quickcheck]
+    fn without_trailing_hole(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let options = ScanOptions {
+            include_trailing_hole: false,
+            ..ScanOptions::default()
+        };
+        let segments = file
+            .as_file_mut()
+            .scan_chunks_with_options(&options)
+            .expect("Unable to scan");
+        let mut expected = desc.segments();
+        if expected.last().is_some_and(Segment::is_hole) {
+            expected.pop();
+        }
+        segments == expected
+            && segments.last().map(|x| x.range.end) == last_data_offset(&desc.segments())
+    }
 
-        combine_segments(&mut input_segments);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[quickcheck]
+    fn scan_extents(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let extents = match file.as_file_mut().scan_extents() {
+            Ok(extents) => extents,
+            // Not every filesystem the tests run on can map extents
+            Err(ScanError::UnsupportedFileSystem) => return true,
+            Err(e) => panic!("Unable to scan extents: {e}"),
+        };
+        if extents
+            .iter()
+            .any(|x| x.is_hole() && x.physical_offset.is_some())
+        {
+            return false;
+        }
 
-        test_chunks_match(file.as_file_mut(), &input_segments)
+        // Without the physical offsets the extents should merge into the
+        // same segments as a normal scan
+        let mut merged = Vec::new();
+        for extent in extents {
+            layout::push_merged(&mut merged, extent.segment_type, extent.range);
+        }
+        merged == desc.segments()
+    }
+
+    /// The layout of the file found by seeking from one hole or data to the
+    /// next, without FIEMAP
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn seek_layout(file: &mut File) -> Vec<Segment> {
+        let len = file.metadata().expect("Unable to get metadata").len();
+        let mut segments = Vec::new();
+        let mut offset = 0;
+        while offset < len {
+            let data = file.next_data(offset).expect("seeked").unwrap_or(len);
+            layout::push_merged(&mut segments, SegmentType::Hole, offset..data);
+            if data >= len {
+                break;
+            }
+            let hole = file.next_hole(data).expect("seeked").unwrap_or(len);
+            layout::push_merged(&mut segments, SegmentType::Data, data..hole);
+            offset = hole;
+        }
+        segments
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[quickcheck]
+    fn fiemap_matches_seeking(desc: SparseDescription, a: u64, b: u64) -> bool {
+        use std::os::unix::io::AsFd;
+
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        let window = a.min(b) % (desc.len() + 1)..a.max(b);
+        let seeked = seek_layout(file);
+        match crate::fiemap::scan_layout(file.as_fd(), desc.len(), window.clone()) {
+            Ok(Some(mapped)) => mapped == layout::clip(seeked, window),
+            // Only filesystems that can not map extents fall back
+            Ok(None) => file.scan_extents().is_err(),
+            Err(_) => false,
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn scan_unwritten_extents() {
+        use std::os::unix::io::AsFd;
+
+        let mut file = SparseDescription::one_segment(SegmentType::Hole, 4 * BLOCK_SIZE).to_file();
+        let fd = file.as_file().as_fd();
+        let allocated = sys::preallocate(fd, BLOCK_SIZE, 2 * BLOCK_SIZE).expect("fallocate failed");
+        if !allocated {
+            // Nothing to test on a filesystem without fallocate
+            return;
+        }
+
+        let extents = match file.as_file_mut().scan_extents() {
+            Ok(extents) => extents,
+            Err(ScanError::UnsupportedFileSystem) => return,
+            Err(e) => panic!("Unable to scan extents: {e}"),
+        };
+        let types: Vec<_> = extents
+            .iter()
+            .map(|x| (x.segment_type, x.range.clone()))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                (SegmentType::Hole, 0..BLOCK_SIZE),
+                (SegmentType::Unwritten, BLOCK_SIZE..2 * BLOCK_SIZE),
+                (SegmentType::Hole, 2 * BLOCK_SIZE..4 * BLOCK_SIZE),
+            ]
+        );
+        assert!(extents[1].is_allocated() && !extents[1].is_data());
+        assert_eq!(allocated_bytes(&extents), BLOCK_SIZE);
+
+        // Seeking decides what unwritten extents are, so FIEMAP is not used
+        let fd = file.as_file().as_fd();
+        assert_eq!(
+            crate::fiemap::scan_layout(fd, 4 * BLOCK_SIZE, 0..u64::MAX).ok(),
+            Some(None)
+        );
+        let segments = file.as_file_mut().scan_chunks().expect("scanned");
+        assert_eq!(segments, seek_layout(file.as_file_mut()));
+    }
+
+    #[test]
+    fn only_required_methods() {
+        // Something outside the crate that only knows its own layout
+        struct Fixed(std::io::Cursor<Vec<u8>>);
+        impl Read for Fixed {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+        impl Seek for Fixed {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+        impl SparseFile for Fixed {
+            fn scan_chunks(&mut self) -> Result<Vec<Segment>, ScanError> {
+                Ok(vec![Segment::hole(0, 4), Segment::data(4, 8)])
+            }
+            fn drill_hole(&self, _start: u64, _end: u64) -> Result<(), ScanError> {
+                Err(ScanError::UnsupportedPlatform)
+            }
+        }
+
+        let mut file = Fixed(std::io::Cursor::new(vec![0; 8]));
+        let segments = file.segments().unwrap().collect::<Result<Vec<_>, _>>();
+        assert_eq!(segments.unwrap(), file.scan_chunks().unwrap());
+        assert_eq!(file.scan_range(2, 6).unwrap()[1], Segment::data(4, 6));
+        assert_eq!(file.next_data(1).unwrap(), Some(4));
+        assert_eq!(file.next_hole(5).unwrap(), Some(8));
+        assert!(matches!(
+            file.insert_range(0, 4),
+            Err(ScanError::UnsupportedPlatform)
+        ));
+        assert!(matches!(
+            file.is_sparse(),
+            Err(ScanError::UnsupportedPlatform)
+        ));
+    }
+
+    #[test]
+    fn shared_file() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3]);
+        let temp = desc.to_file();
+        let file = Arc::new(temp.reopen().expect("reopen"));
+
+        let mut shared = &*file;
+        let segments = shared.scan_chunks().expect("Unable to scan chunks");
+        assert_eq!(segments, desc.segments());
+
+        shared
+            .drill_hole(0, BLOCK_SIZE)
+            .expect("Unable to drill hole");
+        let segments = (&*file).scan_chunks().expect("Unable to scan chunks");
+        assert!(segments[0].is_hole());
+    }
+
+    #[quickcheck]
+    fn copy_sparse(desc: SparseDescription) -> bool {
+        let mut src = desc.to_file();
+        let mut dst = tempfile::NamedTempFile::new().expect("Unable to create tempfile");
+        let copied = crate::copy_sparse(src.as_file_mut(), dst.as_file_mut()).expect("copy");
+
+        let mut src_bytes = Vec::new();
+        let mut dst_bytes = Vec::new();
+        src.as_file_mut().rewind().unwrap();
+        src.as_file_mut().read_to_end(&mut src_bytes).unwrap();
+        dst.as_file_mut().rewind().unwrap();
+        dst.as_file_mut().read_to_end(&mut dst_bytes).unwrap();
+
+        copied == allocated_bytes(&desc.segments())
+            && src_bytes == dst_bytes
+            && test_chunks_match(dst.as_file_mut(), &desc.segments())
+    }
+
+    #[quickcheck]
+    fn apply_map(desc: SparseDescription) -> bool {
+        let mut src = desc.to_file();
+        let map = SparseMap::from(src.as_file_mut().scan_chunks().expect("scan"));
+        let mut data = Vec::new();
+        let mut buf = Vec::new();
+        for segment in map.data_segments() {
+            src.as_file_mut()
+                .read_segment(segment, &mut buf)
+                .expect("Unable to read segment");
+            data.extend_from_slice(&buf);
+        }
+
+        let mut dst = sparse_tempfile();
+        crate::apply_map(dst.as_file_mut(), &map, &mut &data[..]).expect("Unable to apply");
+        std::fs::read(dst.path()).expect("read") == std::fs::read(src.path()).expect("read")
+            && test_chunks_match(dst.as_file_mut(), &desc.segments())
+    }
+
+    #[test]
+    fn apply_map_runs_out_of_data() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3]);
+        let map = SparseMap::from(desc.segments());
+        let mut dst = sparse_tempfile();
+        let short = vec![1_u8; BLOCK_SIZE as usize + 1];
+        match crate::apply_map(dst.as_file_mut(), &map, &mut &short[..]) {
+            Err(ScanError::IO(e)) => assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof),
+            result => panic!("Unexpected result {result:?}"),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn copy_sparse_across_filesystems() {
+        // copy_file_range can not copy from /tmp to a tmpfs, so this copies
+        // through the buffer instead
+        if !Path::new("/dev/shm").is_dir() {
+            return;
+        }
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![2, 3, 5, 9]);
+        let mut src = desc.to_file();
+        let mut dst = tempfile::NamedTempFile::new_in("/dev/shm").expect("Unable to create");
+        let copied = crate::copy_sparse(src.as_file_mut(), dst.as_file_mut()).expect("copy");
+        assert_eq!(copied, allocated_bytes(&desc.segments()));
+        assert_eq!(
+            std::fs::read(dst.path()).expect("read"),
+            std::fs::read(src.path()).expect("read")
+        );
+        assert_eq!(
+            dst.as_file_mut().stream_position().expect("position"),
+            5 * BLOCK_SIZE
+        );
+        assert!(test_chunks_match(dst.as_file_mut(), &desc.segments()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn copy_sparse_with_options() {
+        if !Path::new("/dev/shm").is_dir() {
+            return;
+        }
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![1, 3, 4, 6]);
+        let mut src = desc.to_file();
+        let mut dst = tempfile::NamedTempFile::new_in("/dev/shm").expect("Unable to create");
+        // Not a multiple of anything, so the last piece of each segment is short
+        let options = CopyOptions {
+            buffer_size: 1000,
+            punch_holes: true,
+        };
+        let copied =
+            crate::copy_sparse_with(src.as_file_mut(), dst.as_file_mut(), &options).expect("copy");
+        assert_eq!(copied, allocated_bytes(&desc.segments()));
+        assert_eq!(
+            std::fs::read(dst.path()).expect("read"),
+            std::fs::read(src.path()).expect("read")
+        );
+        assert!(test_chunks_match(dst.as_file_mut(), &desc.segments()));
+    }
+
+    #[quickcheck]
+    fn sparse_writer(desc: SparseDescription) -> bool {
+        let mut dst = sparse_tempfile();
+        let mut writer = SparseWriter::new(dst.as_file_mut(), BLOCK_SIZE);
+        let mut src = desc.to_dense_file().reopen().expect("Unable to open");
+        std::io::copy(&mut src, &mut writer).expect("Unable to copy");
+        writer.finish().expect("Unable to finish");
+
+        // The last byte of a hole at the end has to be written to give the
+        // file its length, so the last block is left out of the comparison
+        let len = desc.len();
+        let window = 0..len.saturating_sub(BLOCK_SIZE);
+        let segments = dst.as_file_mut().scan_chunks().expect("Unable to scan");
+        std::fs::read(dst.path()).expect("read")
+            == std::fs::read(desc.to_file().path()).expect("read")
+            && layout::clip(segments, window.clone()) == layout::clip(desc.segments(), window)
+    }
+
+    #[quickcheck]
+    fn sparse_reader(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let segments = file.as_file_mut().scan_chunks().expect("Unable to scan");
+        let mut contents = Vec::new();
+        SparseReader::new(file.as_file_mut(), segments)
+            .read_to_end(&mut contents)
+            .expect("Unable to read");
+
+        let mut expected = Vec::new();
+        desc.to_dense_file()
+            .reopen()
+            .expect("Unable to open")
+            .read_to_end(&mut expected)
+            .expect("Unable to read");
+        contents == expected
+    }
+
+    #[quickcheck]
+    fn materialize(desc: SparseDescription) -> bool {
+        let mut temp = desc.to_file();
+        let file = temp.as_file_mut();
+        file.seek(SeekFrom::Start(3)).unwrap();
+        file.materialize().expect("Unable to materialize");
+
+        let mut contents = Vec::new();
+        let position = file.stream_position().unwrap();
+        file.rewind().unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        let mut expected = Vec::new();
+        desc.to_dense_file()
+            .reopen()
+            .expect("Unable to open")
+            .read_to_end(&mut expected)
+            .expect("Unable to read");
+
+        position == 3 && contents == expected && !file.is_sparse().expect("Unable to check")
+    }
+
+    #[test]
+    fn punch_hole() {
+        let desc = SparseDescription::one_segment(SegmentType::Data, 4 * BLOCK_SIZE);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        file.punch_hole(BLOCK_SIZE, 2 * BLOCK_SIZE)
+            .expect("Unable to punch hole");
+
+        let expected = SparseDescription::from_parts(SegmentType::Data, vec![1, 3, 4]);
+        assert!(test_chunks_match(file, &expected.segments()));
+
+        let mut contents = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        let hole = BLOCK_SIZE as usize..3 * BLOCK_SIZE as usize;
+        assert!(contents[hole].iter().all(|x| *x == 0));
+
+        assert!(matches!(
+            file.punch_hole(BLOCK_SIZE, u64::MAX),
+            Err(ScanError::InvalidSegment { .. })
+        ));
+        assert!(matches!(
+            file.drill_hole(2 * BLOCK_SIZE, BLOCK_SIZE),
+            Err(ScanError::InvalidSegment { .. })
+        ));
+    }
+
+    #[test]
+    fn clone_file() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3]);
+        let src = desc.to_file();
+        let mut dst = sparse_tempfile();
+        match crate::clone_file(src.as_file(), dst.as_file()) {
+            Ok(()) => assert!(test_chunks_match(dst.as_file_mut(), &desc.segments())),
+            // Most filesystems can not clone, but they have to say so
+            Err(ScanError::UnsupportedFileSystem) => {}
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            Err(ScanError::UnsupportedPlatform) => {}
+            Err(e) => panic!("Unexpected error cloning file: {e}"),
+        }
+    }
+
+    #[test]
+    fn clone_range() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3]);
+        let src = desc.to_file();
+        let mut dst = sparse_tempfile();
+        dst.as_file().set_len(desc.len()).expect("extend");
+        let len = 2 * BLOCK_SIZE;
+        match crate::clone_range(src.as_file(), 0, dst.as_file(), BLOCK_SIZE, len) {
+            Ok(()) => assert!(test_chunks_match(
+                dst.as_file_mut(),
+                &[
+                    Segment::hole(0, BLOCK_SIZE),
+                    Segment::data(BLOCK_SIZE, 2 * BLOCK_SIZE),
+                    Segment::hole(2 * BLOCK_SIZE, 3 * BLOCK_SIZE),
+                ]
+            )),
+            Err(ScanError::UnsupportedFileSystem) => {}
+            #[cfg(not(any(target_os = "linux", target_os = "android")))]
+            Err(ScanError::UnsupportedPlatform) => {}
+            Err(e) => panic!("Unexpected error cloning range: {e}"),
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert!(matches!(
+            crate::clone_range(src.as_file(), 1, dst.as_file(), 0, BLOCK_SIZE),
+            Err(ScanError::Misaligned { start: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn scan_range_edges() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![2, 4, 6, 8]);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        let types = |segments: Vec<Segment>| {
+            segments
+                .into_iter()
+                .map(|x| (x.segment_type, x.range))
+                .collect::<Vec<_>>()
+        };
+
+        // starting and ending inside data
+        let segments = file
+            .scan_range(BLOCK_SIZE + 1, 5 * BLOCK_SIZE + 3)
+            .expect("Unable to scan range");
+        assert_eq!(
+            types(segments),
+            vec![
+                (SegmentType::Data, BLOCK_SIZE + 1..2 * BLOCK_SIZE),
+                (SegmentType::Hole, 2 * BLOCK_SIZE..4 * BLOCK_SIZE),
+                (SegmentType::Data, 4 * BLOCK_SIZE..5 * BLOCK_SIZE + 3),
+            ]
+        );
+
+        // starting and ending inside holes
+        let segments = file
+            .scan_range(3 * BLOCK_SIZE, 7 * BLOCK_SIZE)
+            .expect("Unable to scan range");
+        assert_eq!(
+            types(segments),
+            vec![
+                (SegmentType::Hole, 3 * BLOCK_SIZE..4 * BLOCK_SIZE),
+                (SegmentType::Data, 4 * BLOCK_SIZE..6 * BLOCK_SIZE),
+                (SegmentType::Hole, 6 * BLOCK_SIZE..7 * BLOCK_SIZE),
+            ]
+        );
+
+        // inside a single hole, and past the end of the file
+        let segments = file
+            .scan_range(2 * BLOCK_SIZE + 5, 3 * BLOCK_SIZE)
+            .expect("Unable to scan range");
+        assert_eq!(
+            types(segments),
+            vec![(SegmentType::Hole, 2 * BLOCK_SIZE + 5..3 * BLOCK_SIZE)]
+        );
+        let segments = file
+            .scan_range(7 * BLOCK_SIZE, 100 * BLOCK_SIZE)
+            .expect("Unable to scan range");
+        assert_eq!(
+            types(segments),
+            vec![(SegmentType::Hole, 7 * BLOCK_SIZE..8 * BLOCK_SIZE)]
+        );
+        assert_eq!(
+            file.scan_range(9 * BLOCK_SIZE, 10 * BLOCK_SIZE).unwrap(),
+            vec![]
+        );
+        assert!(matches!(
+            file.scan_range(2, 1),
+            Err(ScanError::InvalidSegment { start: 2, end: 1 })
+        ));
+    }
+
+    #[quickcheck]
+    fn scan_range(desc: SparseDescription, a: u64, b: u64) -> bool {
+        let len = desc.len() + 1;
+        let (start, end) = (a % len, b % len);
+        let (start, end) = (start.min(end), start.max(end));
+        let mut file = desc.to_file();
+        let segments = file
+            .as_file_mut()
+            .scan_range(start, end)
+            .expect("Unable to scan range");
+        segments == layout::clip(desc.segments(), start..end)
+    }
+
+    #[quickcheck]
+    fn next_data_and_hole(desc: SparseDescription, from: u64) -> bool {
+        let len = desc.len();
+        let from = from % (len + 2);
+        let next = |data: bool| {
+            let next = desc
+                .segments()
+                .into_iter()
+                .find(|x| x.is_data() == data && x.range.end > from)
+                .map(|x| x.start().max(from));
+            match next {
+                None if !data && from < len => Some(len),
+                next => next,
+            }
+        };
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        file.next_data(from).expect("Unable to find data") == next(true)
+            && file.next_hole(from).expect("Unable to find hole") == next(false)
+            && file
+                .next_data(u64::MAX)
+                .expect("Unable to find data")
+                .is_none()
+    }
+
+    #[quickcheck]
+    fn lazy_segments(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let segments = file
+            .as_file_mut()
+            .segments()
+            .expect("Unable to start scan")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Unable to scan");
+        segments == desc.segments()
+    }
+
+    #[quickcheck]
+    fn scan_into(a: SparseDescription, b: SparseDescription) -> bool {
+        let mut out = Vec::new();
+        a.to_file()
+            .as_file_mut()
+            .scan_into(&mut out)
+            .expect("Unable to scan");
+        let first = out == a.segments();
+        b.to_file()
+            .as_file_mut()
+            .scan_into(&mut out)
+            .expect("Unable to scan");
+        first && out == b.segments()
+    }
+
+    #[test]
+    fn scan_chunks_with_progress() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3, 4]);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+
+        let mut offsets = Vec::new();
+        let segments = file
+            .scan_chunks_with(|offset| {
+                offsets.push(offset);
+                ControlFlow::Continue(())
+            })
+            .expect("Unable to scan");
+        assert_eq!(segments, desc.segments());
+        assert_eq!(
+            offsets,
+            desc.segments()
+                .iter()
+                .map(|x| x.range.end)
+                .collect::<Vec<_>>()
+        );
+
+        let mut calls = 0;
+        let cancelled = file.scan_chunks_with(|_| {
+            calls += 1;
+            ControlFlow::Break(())
+        });
+        assert!(matches!(cancelled, Err(ScanError::Cancelled)));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn file_changes_length_during_scan() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3, 4]);
+        let mut file = desc.to_file();
+        let other = file
+            .as_file()
+            .try_clone()
+            .expect("Unable to clone the handle");
+        let grown = desc.len() + BLOCK_SIZE;
+        let result = file.as_file_mut().scan_chunks_with(|_| {
+            std::thread::scope(|s| s.spawn(|| other.set_len(grown)).join().unwrap())
+                .expect("Unable to extend");
+            ControlFlow::Continue(())
+        });
+        assert!(matches!(
+            result,
+            Err(ScanError::Changed { len, .. }) if len == grown
+        ));
+
+        // However the scan and the writes interleave the result either covers
+        // the whole file as it was at some point or says that it changed
+        let mut file = desc.to_file();
+        let other = file
+            .as_file()
+            .try_clone()
+            .expect("Unable to clone the handle");
+        let base_len = desc.len();
+        let writer = std::thread::spawn(move || {
+            for i in 0..200 {
+                other
+                    .set_len(base_len + (i % 4) * BLOCK_SIZE)
+                    .expect("Unable to change length");
+            }
+        });
+        while !writer.is_finished() {
+            match file.as_file_mut().scan_chunks() {
+                Ok(segments) => {
+                    let len = segments.last().map_or(0, |x| x.range.end);
+                    assert!(validate(&segments, len).is_ok());
+                    assert_eq!(layout::clip(segments, 0..base_len), desc.segments());
+                }
+                Err(ScanError::Changed { .. }) => {}
+                Err(e) => panic!("Unexpected error {:?}", e),
+            }
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn lazy_segments_stop_early() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3, 4]);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        file.seek(SeekFrom::Start(3)).expect("seek");
+
+        let first: Vec<_> = file
+            .segments()
+            .expect("Unable to start scan")
+            .take(2)
+            .collect::<Result<_, _>>()
+            .expect("Unable to scan");
+        assert_eq!(first, desc.segments()[..2]);
+        assert_eq!(file.stream_position().unwrap(), 3);
+    }
+
+    #[cfg(feature = "serde")]
+    #[quickcheck]
+    fn serde_round_trip(desc: SparseDescription) -> bool {
+        let segments = desc.segments();
+        let json = serde_json::to_string(&segments).expect("serialize");
+        let parsed: Vec<Segment> = serde_json::from_str(&json).expect("deserialize");
+        parsed == segments
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn scan_chunks_async() {
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![1, 3]);
+        let file = desc.to_file();
+        let segments = crate::scan_chunks_async(file.path())
+            .await
+            .expect("Unable to scan");
+        assert_eq!(segments, desc.segments());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_format() {
+        let segment = Segment::hole(0, 10);
+        let json = serde_json::to_string(&segment).expect("serialize");
+        assert_eq!(
+            json,
+            r#"{"segment_type":"hole","range":{"start":0,"end":10}}"#
+        );
+    }
+
+    #[quickcheck]
+    fn sorting_segments(desc: SparseDescription) -> bool {
+        let segments = desc.segments();
+        let mut shuffled = segments.clone();
+        shuffled.reverse();
+        shuffled.sort();
+
+        let unique: std::collections::HashSet<_> = segments.iter().cloned().collect();
+        shuffled == segments && unique.len() == segments.len()
+    }
+
+    #[test]
+    fn scan_keeps_position() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3]);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        let position = BLOCK_SIZE + 7;
+        file.seek(SeekFrom::Start(position)).expect("seek");
+
+        file.scan_chunks().expect("Unable to scan chunks");
+        assert_eq!(file.stream_position().unwrap(), position);
+        file.scan_chunks_by_reading(BLOCK_SIZE)
+            .expect("Unable to read file");
+        assert_eq!(file.stream_position().unwrap(), position);
+        file.scan_chunks_cached_caps(&CapabilityCache::new())
+            .expect("Unable to scan chunks");
+        assert_eq!(file.stream_position().unwrap(), position);
+    }
+
+    #[quickcheck]
+    fn scan_by_reading(desc: SparseDescription) -> bool {
+        let mut file = desc.to_dense_file();
+        let segments = file
+            .as_file_mut()
+            .scan_chunks_by_reading(BLOCK_SIZE)
+            .expect("Unable to read file");
+        segments == desc.segments()
+    }
+
+    #[test]
+    fn scan_by_reading_huge_block_size() {
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![2, 3]);
+        let mut file = desc.to_dense_file();
+        let file = file.as_file_mut();
+        // The whole file is one block, which has data in it
+        assert_eq!(
+            file.scan_chunks_by_reading(u64::MAX).expect("scanned"),
+            vec![Segment::data(0, 3 * BLOCK_SIZE)]
+        );
+        assert_eq!(file.restore_sparseness(u64::MAX).expect("restored"), 0);
+        assert_eq!(
+            file.scan_chunks_by_reading(2 * BLOCK_SIZE)
+                .expect("scanned"),
+            vec![
+                Segment::hole(0, 2 * BLOCK_SIZE),
+                Segment::data(2 * BLOCK_SIZE, 3 * BLOCK_SIZE)
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_by_reading_zero_block_size() {
+        let mut file = SparseDescription::one_segment(SegmentType::Data, BLOCK_SIZE).to_file();
+        let file = file.as_file_mut();
+        let invalid =
+            |e| matches!(e, ScanError::IO(e) if e.kind() == std::io::ErrorKind::InvalidInput);
+        assert!(file.scan_chunks_by_reading(0).is_err_and(invalid));
+        assert!(file.fill_factor_histogram(0).is_err_and(invalid));
+        assert!(file.restore_sparseness(0).is_err_and(invalid));
+    }
+
+    #[quickcheck]
+    fn segments_are_contiguous(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let segments = file
+            .as_file_mut()
+            .scan_chunks()
+            .expect("Unable to scan chunks");
+
+        let mut end = 0;
+        for segment in &segments {
+            if segment.range.start != end {
+                return false;
+            }
+            end = segment.range.end;
+        }
+        end == desc.len()
+    }
+
+    #[test]
+    fn dense_file_of_known_length() {
+        use std::io::Write;
+
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        file.write_all(&[1_u8; 100])
+            .expect("Unable to write bytes to file");
+        let segments = file.scan_chunks().expect("Unable to scan chunks");
+        assert_eq!(segments, vec![Segment::data(0, 100)]);
+    }
+
+    #[quickcheck]
+    fn scan_mmap(desc: SparseDescription) -> bool {
+        let contents = std::fs::read(desc.to_dense_file().path()).expect("Unable to read");
+        crate::scan_mmap(&contents, BLOCK_SIZE) == desc.segments()
+    }
+
+    #[test]
+    fn extended_without_being_marked_sparse() {
+        use std::io::Write;
+
+        // Only Windows needs a file to be marked as sparse to have holes
+        let mut file = tempfile::tempfile().expect("Unable to create tempfile");
+        file.write_all(&[1_u8; BLOCK_SIZE as usize])
+            .expect("Unable to write bytes to file");
+        file.set_len(3 * BLOCK_SIZE).expect("Unable to extend file");
+        let expected = if cfg!(windows) {
+            SparseDescription::one_segment(SegmentType::Data, 3 * BLOCK_SIZE)
+        } else {
+            SparseDescription::from_parts(SegmentType::Data, vec![1, 3])
+        };
+        assert_eq!(
+            file.scan_chunks().expect("Unable to scan chunks"),
+            expected.segments()
+        );
+    }
+
+    #[test]
+    fn heavily_fragmented_file() {
+        use std::io::Write;
+
+        // More ranges than fit in a single FSCTL_QUERY_ALLOCATED_RANGES buffer
+        const RANGES: u64 = 2000;
+        let mut temp = sparse_tempfile();
+        let file = temp.as_file_mut();
+        for i in 0..RANGES {
+            file.seek(SeekFrom::Start(i * 2 * BLOCK_SIZE))
+                .expect("Unable to seek in file");
+            file.write_all(&[1_u8])
+                .expect("Unable to write bytes to file");
+        }
+        file.set_len(RANGES * 2 * BLOCK_SIZE)
+            .expect("Unable to set length of file");
+
+        let segments = file.scan_chunks().expect("Unable to scan chunks");
+        assert_eq!(segments.data().count() as u64, RANGES);
+        assert_eq!(segments.holes().count() as u64, RANGES);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipes_are_not_seekable() {
+        let (reader, _writer) = rustix::pipe::pipe().expect("Unable to create pipe");
+        let mut pipe = File::from(reader);
+        assert!(matches!(pipe.scan_chunks(), Err(ScanError::NotSeekable)));
+
+        let mut out = vec![Segment::data(0, 1)];
+        assert!(pipe.scan_into(&mut out).is_err());
+        assert!(out.is_empty());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn seek_errors_are_not_end_of_file() {
+        use rustix::fs::{Mode, OFlags};
+        use std::os::unix::io::AsFd;
+
+        // Seeking an O_PATH descriptor fails with EBADF, which has to come
+        // back as an error rather than as there being no more segments
+        let file = SparseDescription::one_segment(SegmentType::Data, BLOCK_SIZE).to_file();
+        let fd = rustix::fs::open(file.path(), OFlags::PATH, Mode::empty()).expect("open");
+        for result in [sys::seek_data(fd.as_fd(), 0), sys::seek_hole(fd.as_fd(), 0)] {
+            assert!(matches!(
+                result,
+                Err(ScanError::Syscall { source, .. })
+                    if source.raw_os_error() == Some(libc::EBADF)
+            ));
+        }
+    }
+
+    #[cfg(all(feature = "loop-device-tests", target_os = "linux"))]
+    #[test]
+    fn scan_loop_device() {
+        use std::process::Command;
+
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![1, 3, 4, 6, 8]);
+        let temp = desc.to_file();
+        // Start the device a block into the file, so the holes have to move
+        let output = Command::new("losetup")
+            .args(["--find", "--show", "--offset"])
+            .arg(BLOCK_SIZE.to_string())
+            .arg(temp.path())
+            .output()
+            .expect("Unable to run losetup");
+        assert!(output.status.success(), "losetup failed, is this root?");
+        let device = String::from_utf8(output.stdout).expect("Not a device path");
+        let device = device.trim();
+
+        let segments = File::open(device)
+            .map_err(ScanError::from)
+            .and_then(|mut file| file.scan_chunks());
+        Command::new("losetup")
+            .args(["--detach", device])
+            .status()
+            .expect("Unable to detach the loop device");
+
+        let segment = |segment_type, blocks: Range<u64>| {
+            Segment::new(
+                segment_type,
+                blocks.start * BLOCK_SIZE,
+                blocks.end * BLOCK_SIZE,
+            )
+        };
+        assert_eq!(
+            segments.expect("Unable to scan the loop device"),
+            vec![
+                segment(SegmentType::Data, 0..2),
+                segment(SegmentType::Hole, 2..3),
+                segment(SegmentType::Data, 3..5),
+                segment(SegmentType::Hole, 5..7),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files() {
+        let mut dev_null = File::open("/dev/null").expect("Unable to open /dev/null");
+        assert!(matches!(
+            dev_null.scan_chunks(),
+            Err(ScanError::UnsupportedFileType)
+        ));
+
+        let dir = tempfile::tempdir().expect("Unable to create tempdir");
+        let mut dir = File::open(dir.path()).expect("Unable to open directory");
+        assert!(matches!(
+            dir.scan_chunks(),
+            Err(ScanError::UnsupportedFileType)
+        ));
+    }
+
+    #[test]
+    fn syscall_errors() {
+        let e = ScanError::Syscall {
+            call: "lseek(SEEK_HOLE)",
+            source: std::io::Error::from(std::io::ErrorKind::Interrupted),
+        };
+        assert_eq!(e.to_string(), "lseek(SEEK_HOLE) failed");
+        let source = std::error::Error::source(&e)
+            .and_then(|x| x.downcast_ref::<std::io::Error>())
+            .expect("has an io error source");
+        assert_eq!(source.kind(), std::io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn is_sparse() {
+        let mut sparse = SparseDescription::from_parts(SegmentType::Hole, vec![4, 5]).to_file();
+        assert!(sparse.as_file_mut().is_sparse().expect("checked"));
+
+        let mut dense =
+            SparseDescription::one_segment(SegmentType::Data, BLOCK_SIZE).to_dense_file();
+        assert!(!dense.as_file_mut().is_sparse().expect("checked"));
+    }
+
+    #[quickcheck]
+    fn allocation_info(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        let info = file
+            .allocation_info()
+            .expect("Unable to get allocation info");
+        info.len == desc.len()
+            && info.sparse == file.is_sparse().expect("Unable to check")
+            && info.allocated_size >= allocated_bytes(&desc.segments())
+            && !info.compressed
+            && file.disk_usage().expect("Unable to get disk usage")
+                == (info.len, info.allocated_size)
+    }
+
+    #[quickcheck]
+    fn read_segment(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        let expected = std::fs::read(desc.to_dense_file().path()).expect("Unable to read");
+        let position = file.stream_position().expect("seeked");
+
+        let mut contents = Vec::new();
+        let mut buf = vec![7; 3];
+        for segment in file.scan_chunks().expect("scanned") {
+            file.read_segment(&segment, &mut buf).expect("read");
+            contents.extend_from_slice(&buf);
+        }
+        contents == expected && file.stream_position().expect("seeked") == position
+    }
+
+    #[quickcheck]
+    fn chunks(desc: SparseDescription, chunk_size: u16) -> bool {
+        let chunk_size = u64::from(chunk_size) + 1;
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+        let expected = std::fs::read(desc.to_dense_file().path()).expect("Unable to read");
+
+        let mut covered: Vec<Range<u64>> = Vec::new();
+        for chunk in file.chunks(chunk_size) {
+            let (offset, bytes) = chunk.expect("Unable to read chunk");
+            let range = offset..offset + bytes.len() as u64;
+            if bytes.is_empty()
+                || bytes.len() as u64 > chunk_size
+                || bytes[..] != expected[range.start as usize..range.end as usize]
+            {
+                return false;
+            }
+            match covered.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => covered.push(range),
+            }
+        }
+        covered == desc.segments().data().cloned().collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn read_segment_past_the_end() {
+        let mut file = SparseDescription::one_segment(SegmentType::Data, BLOCK_SIZE).to_file();
+        let segment = Segment::data(BLOCK_SIZE / 2, 2 * BLOCK_SIZE);
+        let mut buf = Vec::new();
+        let result = file.as_file_mut().read_segment(&segment, &mut buf);
+        assert!(
+            matches!(result, Err(ScanError::IO(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn starts_with_data_and_alternates() {
+        // Ending with data and with a hole, and single block segments
+        for split_points in [vec![1, 2, 3, 4, 5, 6], vec![1, 3, 4, 7, 8], vec![2, 3]] {
+            let desc = SparseDescription::from_parts(SegmentType::Data, split_points);
+            let mut file = desc.to_file();
+            let file = file.as_file_mut();
+            assert_eq!(file.scan_chunks().expect("scanned"), desc.segments());
+            let lazy: Result<Vec<_>, _> = file.segments().expect("scanned").collect();
+            assert_eq!(lazy.expect("scanned"), desc.segments());
+            // Windows that start part way through the first data segment
+            let window = BLOCK_SIZE / 2..desc.len();
+            assert_eq!(
+                file.scan_range(window.start, window.end).expect("scanned"),
+                layout::clip(desc.segments(), window)
+            );
+        }
+    }
+
+    #[test]
+    fn fully_sparse_and_dense() {
+        let check = |desc: SparseDescription| {
+            let mut file = desc.to_file();
+            let file = file.as_file_mut();
+            let sparse = file.is_fully_sparse().expect("checked");
+            let dense = file.is_fully_dense().expect("checked");
+            let segments = desc.segments();
+            assert_eq!(sparse, segments.is_fully_sparse());
+            assert_eq!(dense, segments.is_fully_dense());
+            (sparse, dense)
+        };
+        assert_eq!(
+            check(SparseDescription::from_segments(vec![])),
+            (true, true)
+        );
+        assert_eq!(
+            check(SparseDescription::one_segment(
+                SegmentType::Hole,
+                BLOCK_SIZE
+            )),
+            (true, false)
+        );
+        assert_eq!(
+            check(SparseDescription::one_segment(
+                SegmentType::Data,
+                BLOCK_SIZE
+            )),
+            (false, true)
+        );
+        assert_eq!(
+            check(SparseDescription::from_parts(SegmentType::Hole, vec![1, 2])),
+            (false, false)
+        );
+
+        let unwritten = [Segment::new(SegmentType::Unwritten, 0, BLOCK_SIZE)];
+        assert!(unwritten.is_fully_sparse() && unwritten.is_fully_dense());
+    }
+
+    #[test]
+    fn fully_sparse_file() {
+        // Only sets the length of the file, nothing is ever written to it
+        let mut file = SparseDescription::one_segment(SegmentType::Hole, 16 * BLOCK_SIZE).to_file();
+        let segments = file
+            .as_file_mut()
+            .scan_chunks()
+            .expect("Unable to scan chunks");
+        assert_eq!(segments, vec![Segment::hole(0, 16 * BLOCK_SIZE)]);
     }
 
     #[quickcheck]
@@ -262,6 +3229,101 @@ mod tests {
         test_round_trips(desc)
     }
 
+    #[quickcheck]
+    fn shared_result_matches(desc: SparseDescription) -> bool {
+        let mut file = desc.to_file();
+        let shared = file.as_file_mut().scan_shared_result().expect("scan");
+        let copy = shared.clone();
+        std::thread::spawn(move || copy.len()).join().unwrap() == shared.len()
+            && *shared == desc.segments()[..]
+    }
+
+    #[test]
+    fn scan_all() {
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![1, 2]);
+        let file = desc.to_file();
+        let dir = tempfile::tempdir().expect("Unable to create a directory");
+        let missing = dir.path().join("missing");
+        let paths = vec![
+            file.path().to_owned(),
+            missing.clone(),
+            dir.path().to_owned(),
+        ];
+
+        let results = crate::scan_all(paths.clone());
+        assert_eq!(
+            results.iter().map(|(path, _)| path).collect::<Vec<_>>(),
+            paths.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            results[0].1.as_ref().expect("Unable to scan chunks"),
+            &desc.segments()
+        );
+        assert!(
+            matches!(&results[1].1, Err(ScanError::IO(e)) if e.kind() == std::io::ErrorKind::NotFound)
+        );
+        assert!(results[2].1.is_err());
+        assert_eq!(
+            scan_path(file.path()).expect("Unable to scan chunks"),
+            desc.segments()
+        );
+    }
+
+    #[quickcheck]
+    fn borrowed_round_trips(desc: SparseDescription) -> bool {
+        let file = desc.to_file();
+        #[cfg(unix)]
+        let borrowed = std::os::unix::io::AsFd::as_fd(file.as_file());
+        #[cfg(windows)]
+        let borrowed = std::os::windows::io::AsHandle::as_handle(file.as_file());
+        scan_chunks_borrowed(borrowed).expect("Unable to scan chunks") == desc.segments()
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn open_for_scan() {
+        let desc = SparseDescription::from_parts(SegmentType::Hole, vec![1, 2]);
+        let file = desc.to_file();
+        let mut opened = crate::open_for_scan(file.path()).expect("Unable to open");
+        assert_eq!(
+            opened.scan_chunks().expect("Unable to scan chunks"),
+            desc.segments()
+        );
+
+        let dir = tempfile::tempdir().expect("Unable to create a directory");
+        assert!(matches!(
+            crate::open_for_scan(dir.path().join("missing")),
+            Err(ScanError::Syscall { call: "CreateFileW", source })
+                if source.kind() == std::io::ErrorKind::NotFound
+        ));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn insert_range() {
+        let desc = SparseDescription::from_parts(SegmentType::Data, vec![2]);
+        let mut file = desc.to_file();
+        let file = file.as_file_mut();
+
+        assert!(matches!(
+            file.insert_range(BLOCK_SIZE + 1, BLOCK_SIZE),
+            Err(ScanError::Misaligned { .. })
+        ));
+        assert!(matches!(
+            file.insert_range(2 * BLOCK_SIZE, BLOCK_SIZE),
+            Err(ScanError::OutOfBounds { .. })
+        ));
+
+        match file.insert_range(BLOCK_SIZE, BLOCK_SIZE) {
+            Ok(()) => {}
+            Err(ScanError::UnsupportedFileSystem) => return,
+            Err(e) => panic!("insert range failed: {:?}", e),
+        }
+
+        let expected = SparseDescription::from_parts(SegmentType::Data, vec![1, 2, 3]);
+        assert!(test_chunks_match(file, &expected.segments()));
+    }
+
     fn combine_segments(segments: &mut Vec<Segment>) {
         let mut prev = 0;
         for i in 1..segments.len() {
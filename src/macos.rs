@@ -0,0 +1,22 @@
+//! The parts of the unix backend that are different on macOS
+//!
+//! APFS supports `SEEK_HOLE` and `SEEK_DATA`, but HFS+ does not support them
+//! at all. The system calls themselves are in [`sys`].
+use super::*;
+
+/// Filesystems without `SEEK_HOLE` support, like HFS+, can not have holes,
+/// so the whole of `range` is reported as one data segment rather than an
+/// error
+pub(crate) fn dense_if_unsupported(
+    result: Result<ScanIter<'_>, ScanError>,
+    range: Range<u64>,
+) -> Result<ScanIter<'_>, ScanError> {
+    match result {
+        Err(ScanError::UnsupportedFileSystem) if range.is_empty() => Ok(ScanIter::from_vec(vec![])),
+        Err(ScanError::UnsupportedFileSystem) => Ok(ScanIter::from_vec(vec![Segment::data(
+            range.start,
+            range.end,
+        )])),
+        result => result,
+    }
+}
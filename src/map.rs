@@ -0,0 +1,499 @@
+//! Set operations on whole layouts
+use super::*;
+
+/// A layout of holes and data that can be combined with other layouts, for
+/// example to find what has changed between two scans of a file
+///
+/// For the set operations the data and unwritten segments of a map are its
+/// members and its holes are not, and every offset past the end of a map
+/// counts as a hole. The maps returned by the set operations and
+/// [`invert`](Self::invert) are canonical, made of only holes and data that
+/// alternate, and as long as the longer of the two maps. A map made with
+/// `From` or [`clip`](Self::clip) keeps the segment types it was given, so it
+/// can still have unwritten segments, and unwritten next to data, use
+/// [`union`](Self::union) with an empty map, or `collect`, to make it
+/// canonical.
+///
+/// The result of a set operation can go straight into [`allocated_bytes`],
+/// [`stats`] or anything else that takes a layout, as the map derefs to its
+/// segments.
+/// ```
+/// # use drill_press::*;
+/// let old = SparseMap::from(vec![
+///     Segment::data(0, 10),
+///     Segment::hole(10, 40),
+/// ]);
+/// let new = SparseMap::from(vec![
+///     Segment::data(0, 20),
+///     Segment::hole(20, 40),
+/// ]);
+/// // the data that was written since the old scan
+/// assert_eq!(
+///     &*new.difference(&old),
+///     &[
+///         Segment::hole(0, 10),
+///         Segment::data(10, 20),
+///         Segment::hole(20, 40),
+///     ]
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SparseMap(Vec<Segment>);
+
+impl SparseMap {
+    /// The length of the file the map covers, the end of its last segment
+    pub fn len(&self) -> u64 {
+        self.0.last().map_or(0, |x| x.range.end)
+    }
+
+    /// Returns true if the map covers nothing at all
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Swaps the data and holes
+    pub fn invert(&self) -> SparseMap {
+        let mut inverted = Vec::with_capacity(self.0.len());
+        for segment in &self.0 {
+            let segment_type = if segment.is_allocated() {
+                SegmentType::Hole
+            } else {
+                SegmentType::Data
+            };
+            layout::push_merged(&mut inverted, segment_type, segment.range.clone());
+        }
+        SparseMap(inverted)
+    }
+
+    /// Data wherever both maps have data
+    pub fn intersection(&self, other: &SparseMap) -> SparseMap {
+        self.combine(other, |a, b| a && b)
+    }
+
+    /// Data wherever either map has data
+    pub fn union(&self, other: &SparseMap) -> SparseMap {
+        self.combine(other, |a, b| a || b)
+    }
+
+    /// Data wherever this map has data and `other` does not
+    pub fn difference(&self, other: &SparseMap) -> SparseMap {
+        self.combine(other, |a, b| a && !b)
+    }
+
+    /// Trims the map down to the part of it inside `window`, so it starts at
+    /// the start of the window rather than at zero
+    pub fn clip(&self, window: Range<u64>) -> SparseMap {
+        SparseMap::from(layout::clip(self.0.clone(), window))
+    }
+
+    /// The segments that overlap `range`, cut down to the part of them inside
+    /// it
+    ///
+    /// This finds the first segment with a binary search, so looking up a
+    /// small range of a big map, for example to answer a read in a FUSE
+    /// filesystem, does not walk through the whole map or copy it, like
+    /// [`clip`](Self::clip) does. To look up a range of a file without
+    /// scanning all of it first use [`scan_range`](SparseFile::scan_range).
+    /// ```
+    /// # use drill_press::*;
+    /// let map = SparseMap::from(vec![
+    ///     Segment::data(0, 10),
+    ///     Segment::hole(10, 20),
+    ///     Segment::data(20, 30),
+    /// ]);
+    /// assert_eq!(
+    ///     map.segments_in(5..15).collect::<Vec<_>>(),
+    ///     vec![
+    ///         Segment::data(5, 10),
+    ///         Segment::hole(10, 15),
+    ///     ]
+    /// );
+    /// ```
+    pub fn segments_in(&self, range: Range<u64>) -> impl Iterator<Item = Segment> + '_ {
+        let first = self.0.partition_point(|x| x.range.end <= range.start);
+        self.0[first..]
+            .iter()
+            .take_while(move |x| x.range.start < range.end)
+            .filter_map(move |x| layout::clip_segment(x, &range))
+    }
+
+    /// Cuts the map in two at `offset`, splitting the segment that crosses it
+    ///
+    /// Both halves keep their offsets, so the second starts at `offset`, use
+    /// [`shift`](Self::shift) to move it back to zero.
+    pub fn split_at(&self, offset: u64) -> (SparseMap, SparseMap) {
+        (self.clip(0..offset), self.clip(offset..u64::MAX))
+    }
+
+    /// Moves every segment along by `delta` bytes, which can be negative
+    ///
+    /// Returns `None` if any segment would be moved to before zero or past
+    /// `u64::MAX`. The physical offsets of the segments are left as they are.
+    pub fn shift(&self, delta: i64) -> Option<SparseMap> {
+        self.0
+            .iter()
+            .map(|segment| {
+                let start = segment.range.start.checked_add_signed(delta)?;
+                let end = segment.range.end.checked_add_signed(delta)?;
+                Some(Segment {
+                    range: start..end,
+                    ..segment.clone()
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(SparseMap)
+    }
+
+    /// Gets back the list of segments
+    pub fn into_inner(self) -> Vec<Segment> {
+        self.0
+    }
+
+    /// Walks through both maps at once, making each stretch where neither
+    /// changes data if `is_data` says it should be
+    fn combine(&self, other: &SparseMap, is_data: impl Fn(bool, bool) -> bool) -> SparseMap {
+        let len = self.len().max(other.len());
+        let mut combined = Vec::new();
+        let (mut a, mut b) = (self.0.iter().peekable(), other.0.iter().peekable());
+        let mut offset = 0;
+        while offset < len {
+            let (a_data, a_end) = allocated_at(&mut a, offset, len);
+            let (b_data, b_end) = allocated_at(&mut b, offset, len);
+            let end = a_end.min(b_end);
+            let segment_type = if is_data(a_data, b_data) {
+                SegmentType::Data
+            } else {
+                SegmentType::Hole
+            };
+            layout::push_merged(&mut combined, segment_type, offset..end);
+            offset = end;
+        }
+        SparseMap(combined)
+    }
+}
+
+/// A region of a file whose allocation changed between two layouts, see
+/// [`diff`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Change {
+    /// Was a hole and is now data, it has been written to
+    Written(Range<u64>),
+    /// Was data and is now a hole, it has been punched out or trimmed
+    Punched(Range<u64>),
+}
+
+impl Change {
+    /// The range of the file that changed
+    pub fn range(&self) -> Range<u64> {
+        match self {
+            Change::Written(range) | Change::Punched(range) => range.clone(),
+        }
+    }
+}
+
+/// Finds the regions of a file that changed from hole to data, or data to
+/// hole, between the `old` and `new` layouts of it
+///
+/// This is what an incremental backup needs to copy, or to punch out of its
+/// copy, to bring it up to date. Unwritten segments count as data, and
+/// anything past the end of the shorter layout as a hole, as they do for
+/// [`SparseMap`]. The changes are sorted and never overlap, and changes of
+/// the same kind never touch, as they would have been merged into one, so
+/// the list is as short as it can be. Two changes in a row can still be of
+/// the same kind if there is a stretch between them that did not change.
+/// ```
+/// # use drill_press::*;
+/// let old = vec![
+///     Segment::data(0, 10),
+///     Segment::hole(10, 30),
+/// ];
+/// let new = vec![
+///     Segment::hole(0, 5),
+///     Segment::data(5, 20),
+///     Segment::hole(20, 30),
+/// ];
+/// assert_eq!(diff(&old, &new), vec![Change::Punched(0..5), Change::Written(10..20)]);
+/// ```
+pub fn diff(old: &[Segment], new: &[Segment]) -> Vec<Change> {
+    let old = SparseMap::from(old.to_vec());
+    let new = SparseMap::from(new.to_vec());
+    let written = new.difference(&old);
+    let punched = old.difference(&new);
+    let mut changes: Vec<Change> = written
+        .data_segments()
+        .map(|x| Change::Written(x.range()))
+        .chain(punched.data_segments().map(|x| Change::Punched(x.range())))
+        .collect();
+    changes.sort_by_key(|x| x.range().start);
+    changes
+}
+
+/// Whether `offset` is allocated, and where that stops being true, skipping
+/// over the segments that end before it
+///
+/// Anything not covered by a segment is a hole, up to `len`.
+fn allocated_at<'a>(
+    segments: &mut Peekable<impl Iterator<Item = &'a Segment>>,
+    offset: u64,
+    len: u64,
+) -> (bool, u64) {
+    while segments.next_if(|x| x.range.end <= offset).is_some() {}
+    match segments.peek() {
+        Some(segment) if segment.start() <= offset => (segment.is_allocated(), segment.range.end),
+        Some(segment) => (false, segment.start()),
+        None => (false, len),
+    }
+}
+
+impl Deref for SparseMap {
+    type Target = [Segment];
+    fn deref(&self) -> &[Segment] {
+        &self.0
+    }
+}
+
+/// Coalesces the segments, see [`coalesce`]
+impl From<Vec<Segment>> for SparseMap {
+    fn from(segments: Vec<Segment>) -> Self {
+        SparseMap(layout::coalesce(&segments))
+    }
+}
+
+/// Builds a canonical map from segments in any order
+///
+/// Where segments overlap an offset is data if any of them is data or
+/// unwritten, as it is for the set operations, and any gaps between them are
+/// holes. So the map is sorted, made of only holes and data that alternate,
+/// and covers the file from zero up to the end of the furthest segment,
+/// whatever order the segments came in.
+/// ```
+/// # use drill_press::*;
+/// let map: SparseMap = vec![
+///     Segment::data(20, 30),
+///     Segment::data(0, 10),
+///     Segment::hole(5, 40),
+/// ]
+/// .into_iter()
+/// .collect();
+/// assert_eq!(
+///     &*map,
+///     &[
+///         Segment::data(0, 10),
+///         Segment::hole(10, 20),
+///         Segment::data(20, 30),
+///         Segment::hole(30, 40),
+///     ]
+/// );
+/// ```
+impl FromIterator<Segment> for SparseMap {
+    fn from_iter<I: IntoIterator<Item = Segment>>(iter: I) -> Self {
+        let mut len = 0;
+        let mut allocated: Vec<Range<u64>> = iter
+            .into_iter()
+            .inspect(|x| len = len.max(x.range.end))
+            .filter(|x| x.is_allocated())
+            .map(|x| x.range)
+            .collect();
+        allocated.sort_unstable_by_key(|x| x.start);
+
+        let mut segments = Vec::with_capacity(allocated.len() * 2 + 1);
+        let mut offset = 0;
+        for range in allocated {
+            layout::push_merged(&mut segments, SegmentType::Hole, offset..range.start);
+            layout::push_merged(
+                &mut segments,
+                SegmentType::Data,
+                range.start.max(offset)..range.end,
+            );
+            offset = offset.max(range.end);
+        }
+        layout::push_merged(&mut segments, SegmentType::Hole, offset..len);
+        SparseMap(segments)
+    }
+}
+
+impl IntoIterator for SparseMap {
+    type Item = Segment;
+    type IntoIter = std::vec::IntoIter<Segment>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a SparseMap {
+    type Item = &'a Segment;
+    type IntoIter = Iter<'a, Segment>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck_macros::quickcheck;
+
+    fn map(segments: &[(SegmentType, Range<u64>)]) -> SparseMap {
+        SparseMap::from(
+            segments
+                .iter()
+                .map(|(segment_type, range)| Segment::new(*segment_type, range.start, range.end))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn set_operations() {
+        use SegmentType::*;
+        let a = map(&[(Data, 0..10), (Hole, 10..20), (Unwritten, 20..30)]);
+        let b = map(&[(Hole, 0..5), (Data, 5..25)]);
+
+        assert_eq!(
+            a.invert(),
+            map(&[(Hole, 0..10), (Data, 10..20), (Hole, 20..30)])
+        );
+        assert_eq!(
+            a.intersection(&b),
+            map(&[
+                (Hole, 0..5),
+                (Data, 5..10),
+                (Hole, 10..20),
+                (Data, 20..25),
+                (Hole, 25..30)
+            ])
+        );
+        assert_eq!(a.union(&b), map(&[(Data, 0..30)]));
+        assert_eq!(
+            a.difference(&b),
+            map(&[(Data, 0..5), (Hole, 5..25), (Data, 25..30)])
+        );
+        assert_eq!(
+            b.difference(&a),
+            map(&[(Hole, 0..10), (Data, 10..20), (Hole, 20..30)])
+        );
+        assert_eq!(
+            a.clip(8..22),
+            map(&[(Data, 8..10), (Hole, 10..20), (Unwritten, 20..22)])
+        );
+        assert_eq!(a.union(&SparseMap::default()), a.invert().invert());
+        assert_eq!(
+            a.union(&SparseMap::default()),
+            map(&[(Data, 0..10), (Hole, 10..20), (Data, 20..30)])
+        );
+
+        let holes: Vec<_> = b.invert().hole_segments().map(|x| x.range()).collect();
+        let data: Vec<_> = b.data_segments().map(|x| x.range()).collect();
+        assert_eq!(holes, data);
+    }
+
+    #[test]
+    fn collect_overlapping_and_unsorted() {
+        use SegmentType::*;
+        let collected: SparseMap = vec![
+            Segment::hole(30, 50),
+            Segment::new(SegmentType::Unwritten, 35, 40),
+            Segment::data(10, 20),
+            Segment::data(0, 5),
+            Segment::data(15, 25),
+            Segment::hole(0, 30),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            collected,
+            map(&[
+                (Data, 0..5),
+                (Hole, 5..10),
+                (Data, 10..25),
+                (Hole, 25..35),
+                (Data, 35..40),
+                (Hole, 40..50)
+            ])
+        );
+        assert!(validate(&collected, 50).is_ok());
+
+        let mut ends = Vec::new();
+        for segment in &collected {
+            ends.push(segment.range.end);
+        }
+        assert_eq!(ends, vec![5, 10, 25, 35, 40, 50]);
+        assert_eq!(collected.into_iter().count(), 6);
+        assert_eq!(SparseMap::from_iter(vec![]), SparseMap::default());
+    }
+
+    #[quickcheck]
+    fn collect_is_canonical(a: SparseMap, b: SparseMap) -> bool {
+        let collected: SparseMap = a.iter().cloned().collect();
+        let both: SparseMap = a.iter().chain(&b).rev().cloned().collect();
+        collected == a.union(&SparseMap::default()) && both == a.union(&b)
+    }
+
+    #[quickcheck]
+    fn set_identities(a: SparseMap, b: SparseMap) -> bool {
+        let all_hole = |len| match len {
+            0 => SparseMap::default(),
+            len => map(&[(SegmentType::Hole, 0..len)]),
+        };
+        a.invert().invert() == a
+            && a.union(&b) == b.union(&a)
+            && a.intersection(&b) == b.intersection(&a)
+            && a.difference(&a) == all_hole(a.len())
+            && a.union(&b).difference(&a).difference(&b) == all_hole(a.len().max(b.len()))
+            && a.intersection(&b).union(&a.difference(&b)) == a.union(&all_hole(b.len()))
+    }
+
+    #[test]
+    fn diff_of_layouts() {
+        use SegmentType::*;
+        let old = map(&[(Data, 0..10), (Hole, 10..20), (Unwritten, 20..30)]);
+        let new = map(&[(Hole, 0..5), (Data, 5..25), (Hole, 25..40)]);
+
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                Change::Punched(0..5),
+                Change::Written(10..20),
+                Change::Punched(25..30)
+            ]
+        );
+        assert_eq!(
+            diff(&new, &old),
+            vec![
+                Change::Written(0..5),
+                Change::Punched(10..20),
+                Change::Written(25..30)
+            ]
+        );
+        assert_eq!(diff(&old, &old), vec![]);
+        assert_eq!(
+            diff(&[], &old),
+            vec![Change::Written(0..10), Change::Written(20..30)]
+        );
+    }
+
+    #[test]
+    fn split_and_shift() {
+        use SegmentType::*;
+        let a = map(&[(Data, 0..10), (Hole, 10..20)]);
+
+        let (before, after) = a.split_at(5);
+        assert_eq!(before, map(&[(Data, 0..5)]));
+        assert_eq!(after, map(&[(Data, 5..10), (Hole, 10..20)]));
+        assert_eq!(before.union(&after), a);
+        assert_eq!(a.split_at(30), (a.clone(), SparseMap::default()));
+
+        assert_eq!(after.shift(-5), Some(map(&[(Data, 0..5), (Hole, 5..15)])));
+        assert_eq!(after.shift(-6), None);
+        assert_eq!(a.shift(i64::MAX).and_then(|x| x.shift(i64::MAX)), None);
+    }
+
+    #[quickcheck]
+    fn segments_in_is_clip(map: SparseMap, start: u64, len: u64) -> bool {
+        // Keep the window near the map most of the time
+        let start = start % (map.len() + 2);
+        let window = start..start.saturating_add(len % (map.len() + 2));
+        map.segments_in(window.clone()).collect::<Vec<_>>() == *map.clip(window)
+    }
+}
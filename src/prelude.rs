@@ -0,0 +1,23 @@
+//! The traits and types that most users of the crate need, to be glob
+//! imported
+//!
+//! This is the part of the crate that is expected to stay stable, the
+//! [`SparseFile`] and [`Segments`] traits that add the scanning methods to
+//! files and lists of segments, the types they work with, and the most used
+//! functions on layouts. Anything more specialised is still imported from
+//! the crate root.
+//! ```
+//! use drill_press::prelude::*;
+//!
+//! let segments = vec![
+//!     Segment::data(0, 10),
+//!     Segment::hole(10, 40),
+//! ];
+//! assert!(validate(&segments, 40).is_ok());
+//! assert_eq!(allocated_bytes(&segments), 10);
+//! assert_eq!(segments.data().count(), 1);
+//! ```
+pub use crate::{
+    allocated_bytes, coalesce, scan_path, validate, ScanError, Segment, SegmentType, Segments,
+    SparseFile, SparseMap,
+};
@@ -0,0 +1,214 @@
+//! Reading the contents of a file without reading its holes
+use super::*;
+
+use std::io::ErrorKind;
+
+/// Reads the contents of a file as laid out by a list of segments, reading
+/// the data segments from the file and filling in the holes with zeros
+/// without touching it
+///
+/// The segments are expected to be sorted and cover the file without gaps,
+/// as returned by [`scan_chunks`](SparseFile::scan_chunks). Unwritten
+/// segments read as zeros, just like holes. The reader ends at the end of the
+/// last segment, however long the underlying file is.
+/// ```
+/// # use drill_press::*;
+/// # use std::io::{Cursor, Read};
+/// let segments = vec![
+///     Segment::data(0, 2),
+///     Segment::hole(2, 4),
+/// ];
+/// let mut reader = SparseReader::new(Cursor::new(vec![1, 2, 3, 4]), segments);
+/// let mut contents = Vec::new();
+/// reader.read_to_end(&mut contents).unwrap();
+/// assert_eq!(contents, vec![1, 2, 0, 0]);
+/// ```
+#[derive(Debug)]
+pub struct SparseReader<R> {
+    inner: R,
+    segments: Vec<Segment>,
+    position: u64,
+    /// where the inner reader is, if it is known, to save seeking before
+    /// every read
+    inner_position: Option<u64>,
+}
+
+impl<R: Read + Seek> SparseReader<R> {
+    /// Creates a reader that reads `inner` as laid out by `segments`
+    pub fn new(inner: R, segments: Vec<Segment>) -> Self {
+        SparseReader {
+            inner,
+            segments,
+            position: 0,
+            inner_position: None,
+        }
+    }
+
+    /// The segments the reader is following
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// The length of the contents, the end of the last segment
+    pub fn len(&self) -> u64 {
+        self.segments.last().map_or(0, |x| x.range.end)
+    }
+
+    /// Whether there is nothing to read at all
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets back the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let index = self
+            .segments
+            .partition_point(|x| x.range.end <= self.position);
+        let Some(segment) = self.segments.get(index) else {
+            return Ok(0);
+        };
+        // A gap in the segments is treated as a hole
+        let (end, is_data) = if segment.start() > self.position {
+            (segment.start(), false)
+        } else {
+            (segment.range.end, segment.is_data())
+        };
+        let chunk = (end - self.position).min(buf.len() as u64) as usize;
+        let buf = &mut buf[..chunk];
+
+        let read = if is_data {
+            if self.inner_position != Some(self.position) {
+                self.inner_position = None;
+                self.inner.seek(SeekFrom::Start(self.position))?;
+            }
+            let read = self.inner.read(buf)?;
+            self.inner_position = Some(self.position + read as u64);
+            read
+        } else {
+            buf.fill(0);
+            chunk
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for SparseReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.len(), offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        match base.checked_add_signed(offset) {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// The iterator returned by [`SparseFile::chunks`], yielding the data of a
+/// file a piece at a time along with the offset of each piece
+#[derive(Debug)]
+pub struct DataChunks<'a, F> {
+    file: &'a mut F,
+    chunk_size: u64,
+    /// The data segments left to read, once the file has been scanned
+    segments: Option<std::vec::IntoIter<Segment>>,
+    /// What is left of the data segment being read
+    current: Range<u64>,
+    /// Whether an error has been yielded, after which there is nothing more
+    done: bool,
+}
+
+impl<'a, F: SparseFile> DataChunks<'a, F> {
+    pub(crate) fn new(file: &'a mut F, chunk_size: u64) -> Self {
+        assert!(chunk_size != 0, "chunk size must not be zero");
+        DataChunks {
+            file,
+            chunk_size,
+            segments: None,
+            current: 0..0,
+            done: false,
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<(u64, Vec<u8>)>, ScanError> {
+        let segments = match &mut self.segments {
+            Some(segments) => segments,
+            None => self.segments.insert(self.file.scan_chunks()?.into_iter()),
+        };
+        while self.current.is_empty() {
+            match segments.find(Segment::is_data) {
+                Some(segment) => self.current = segment.range,
+                None => return Ok(None),
+            }
+        }
+
+        let start = self.current.start;
+        let end = self.current.end.min(start.saturating_add(self.chunk_size));
+        let piece = Segment::data(start, end);
+        let mut buf = Vec::new();
+        self.file.read_segment(&piece, &mut buf)?;
+        self.current.start = end;
+        Ok(Some((start, buf)))
+    }
+}
+
+impl<F: SparseFile> Iterator for DataChunks<'_, F> {
+    type Item = Result<(u64, Vec<u8>), ScanError>;
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.next_chunk();
+        // Stop after an error
+        self.done = next.is_err();
+        next.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn seek_and_read() {
+        let segments = vec![
+            Segment::hole(0, 3),
+            Segment::data(3, 6),
+            Segment::new(SegmentType::Unwritten, 6, 8),
+        ];
+        let mut reader = SparseReader::new(Cursor::new(vec![9_u8; 8]), segments);
+
+        assert_eq!(reader.seek(SeekFrom::End(-6)).unwrap(), 2);
+        let mut buf = [7_u8; 5];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0, 9, 9, 9, 0]);
+        assert_eq!(reader.stream_position().unwrap(), 7);
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, vec![0]);
+
+        assert!(reader.seek(SeekFrom::Current(-9)).is_err());
+        assert_eq!(reader.seek(SeekFrom::Start(20)).unwrap(), 20);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,16 @@
+//! Safe wrappers round the system calls the platform backends make
+//!
+//! This is the only place `unsafe` is needed to talk to the OS. Everything
+//! here takes borrowed file descriptors or handles, so the file is known to
+//! stay open for the length of the call, and turns failures into
+//! [`ScanError`](crate::ScanError)s, so the backends are left with the
+//! logic of scanning.
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+pub(crate) use self::unix::*;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub(crate) use self::windows::*;
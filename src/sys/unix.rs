@@ -0,0 +1,349 @@
+//! Safe wrappers round the Unix system calls the backend uses, built on
+//! rustix where it has the call and libc where it does not
+use crate::ScanError;
+
+use std::io::Error;
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+
+use rustix::fs::SeekFrom as Whence;
+use rustix::io::Errno;
+
+/// Turns an errno from a failed call into a [`ScanError::Syscall`]
+fn syscall_error(call: &'static str, errno: Errno) -> ScanError {
+    ScanError::Syscall {
+        call,
+        source: errno.into(),
+    }
+}
+
+/// Seeks to the next data at or after `offset`, returning `None` if there is
+/// none before the end of the file
+pub(crate) fn seek_data(fd: BorrowedFd<'_>, offset: u64) -> Result<Option<u64>, ScanError> {
+    seek(fd, Whence::Data(offset), "lseek(SEEK_DATA)")
+}
+
+/// Seeks to the next hole at or after `offset`, returning `None` if `offset`
+/// is already past the end of the file
+pub(crate) fn seek_hole(fd: BorrowedFd<'_>, offset: u64) -> Result<Option<u64>, ScanError> {
+    seek(fd, Whence::Hole(offset), "lseek(SEEK_HOLE)")
+}
+
+/// Seeks to the end of the file, returning its length
+pub(crate) fn seek_end(fd: BorrowedFd<'_>) -> Result<u64, ScanError> {
+    Ok(seek(fd, Whence::End(0), "lseek(SEEK_END)")?.unwrap_or(0))
+}
+
+/// The current seek position of the file
+pub(crate) fn position(fd: BorrowedFd<'_>) -> Result<u64, ScanError> {
+    Ok(seek(fd, Whence::Current(0), "lseek")?.unwrap_or(0))
+}
+
+/// Puts the seek position of the file back to `position` after a scan
+///
+/// Nothing can be done about a failure here, and the scan itself already
+/// happened, so any error is ignored.
+pub(crate) fn restore_position(fd: BorrowedFd<'_>, position: u64) {
+    let _ = rustix::fs::seek(fd, Whence::Start(position));
+}
+
+fn seek(fd: BorrowedFd<'_>, whence: Whence, call: &'static str) -> Result<Option<u64>, ScanError> {
+    // Only the errors we understand get special handling, anything else
+    // (EIO, EBADF, ...) is a real failure and must not be mistaken for
+    // running out of segments
+    match rustix::fs::seek(fd, whence) {
+        Ok(offset) => Ok(Some(offset)),
+        // EINVAL indicates that the file system does not support SEEK_HOLE or
+        // SEEK_DATA, so we indicate as such
+        Err(Errno::INVAL) => Err(ScanError::UnsupportedFileSystem),
+        // ESPIPE means the fd is a pipe, socket or fifo
+        Err(Errno::SPIPE) => Err(ScanError::NotSeekable),
+        // ENXIO indicates that the the file offset we are looking for either
+        // doesn't exist, or would be beyond the end of the file. For
+        // SEEK_DATA that means there is only a hole left, and for SEEK_HOLE
+        // that the offset was already at the end, either way there is no next
+        // segment, so we return Ok(none) to indicate as such.
+        Err(Errno::NXIO) => Ok(None),
+        Err(errno) => Err(syscall_error(call, errno)),
+    }
+}
+
+/// The block size of the filesystem the file is on, as reported by `fstatvfs`
+pub(crate) fn block_size(fd: BorrowedFd<'_>) -> Result<u64, ScanError> {
+    let stat = rustix::fs::fstatvfs(fd).map_err(|e| syscall_error("fstatvfs", e))?;
+    Ok(stat.f_bsize)
+}
+
+/// The number of bits needed to hold the largest file the filesystem can
+/// hold, including a sign bit, or `None` if it has no limit
+pub(crate) fn file_size_bits(fd: BorrowedFd<'_>) -> Result<Option<i64>, ScanError> {
+    use errno::{errno, set_errno};
+    use libc::fpathconf;
+
+    cfg_if::cfg_if! {
+        // libc module for android is missing this, value stolen from bionic's unistd.h
+        if #[cfg(target_os = "android")]{
+            const _PC_FILESIZEBITS: libc::c_int = 0;
+        } else {
+            use libc::_PC_FILESIZEBITS;
+        }
+    }
+
+    // fpathconf returns -1 both for errors and for "no limit", the only way
+    // to tell them apart is to see if errno was set
+    set_errno(errno::Errno(0));
+    // SAFETY: fpathconf only reads the descriptor, which is borrowed so open
+    let bits = unsafe { fpathconf(fd.as_raw_fd(), _PC_FILESIZEBITS) };
+    if bits < 0 {
+        return match errno().into() {
+            0 => Ok(None),
+            errno => Err(ScanError::Syscall {
+                call: "fpathconf(_PC_FILESIZEBITS)",
+                source: Error::from_raw_os_error(errno),
+            }),
+        };
+    }
+    #[allow(clippy::unnecessary_cast)] // c_long is only i32 on 32 bit targets
+    Ok(Some(bits as i64))
+}
+
+/// Deallocates `start..end` of the file without changing its length
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn punch_hole(fd: BorrowedFd<'_>, start: u64, end: u64) -> Result<(), ScanError> {
+    use rustix::fs::FallocateFlags;
+
+    let flags = FallocateFlags::PUNCH_HOLE | FallocateFlags::KEEP_SIZE;
+    match rustix::fs::fallocate(fd, flags, start, end - start) {
+        Ok(()) => Ok(()),
+        // EOPNOTSUPP means the filesystem can not deallocate ranges
+        Err(Errno::OPNOTSUPP) => Err(ScanError::UnsupportedFileSystem),
+        Err(errno) => Err(syscall_error("fallocate(FALLOC_FL_PUNCH_HOLE)", errno)),
+    }
+}
+
+/// Deallocates `start..end` of the file with `fspacectl(SPACECTL_DEALLOC)`,
+/// as the `fallocate` on FreeBSD can not punch holes
+#[cfg(target_os = "freebsd")]
+pub(crate) fn punch_hole(fd: BorrowedFd<'_>, start: u64, end: u64) -> Result<(), ScanError> {
+    // Offsets past off_t are rejected the way the kernel would reject them
+    let offset = |x: u64| {
+        libc::off_t::try_from(x)
+            .map_err(|_| syscall_error("fspacectl(SPACECTL_DEALLOC)", Errno::INVAL))
+    };
+    let mut range = libc::spacectl_range {
+        r_offset: offset(start)?,
+        r_len: offset(end - start)?,
+    };
+    // The call can stop part of the way through, leaving `range` as what is
+    // left to do
+    while range.r_len > 0 {
+        let request = range;
+        // SAFETY: both pointers are to spacectl_ranges that live until the
+        // call returns
+        if unsafe {
+            libc::fspacectl(
+                fd.as_raw_fd(),
+                libc::SPACECTL_DEALLOC,
+                &request,
+                0,
+                &mut range,
+            )
+        } < 0
+        {
+            let err = Error::last_os_error();
+            // EOPNOTSUPP means the filesystem can not deallocate ranges
+            return match err.raw_os_error() {
+                Some(libc::EOPNOTSUPP) => Err(ScanError::UnsupportedFileSystem),
+                _ => Err(ScanError::Syscall {
+                    call: "fspacectl(SPACECTL_DEALLOC)",
+                    source: err,
+                }),
+            };
+        }
+    }
+    Ok(())
+}
+
+/// Deallocates `start..end` of the file with `fcntl(F_PUNCHHOLE)`, as there
+/// is no `fallocate` on macOS
+#[cfg(target_os = "macos")]
+pub(crate) fn punch_hole(fd: BorrowedFd<'_>, start: u64, end: u64) -> Result<(), ScanError> {
+    use libc::{c_int, fcntl};
+
+    #[repr(C)]
+    struct fpunchhole_t {
+        fp_flags: c_int, /* unused */
+        reserved: c_int, /* (to maintain 8-byte alignment) */
+        fp_offset: u64,  /* IN: start of the region */
+        fp_length: u64,  /* IN: size of the region */
+    }
+
+    // from fcntl.h
+    const F_PUNCHHOLE: c_int = 99;
+
+    let hole = fpunchhole_t {
+        fp_flags: 0,
+        reserved: 0,
+        fp_offset: start,
+        fp_length: (end - start),
+    };
+
+    // SAFETY: F_PUNCHHOLE takes a pointer to an fpunchhole_t, which lives
+    // until the call returns
+    if unsafe { fcntl(fd.as_raw_fd(), F_PUNCHHOLE, &hole) } < 0 {
+        let err = Error::last_os_error();
+        // ENOTSUP means the filesystem, like HFS+, can not have holes
+        return match err.raw_os_error() {
+            Some(libc::ENOTSUP) => Err(ScanError::UnsupportedFileSystem),
+            _ => Err(ScanError::Syscall {
+                call: "fcntl(F_PUNCHHOLE)",
+                source: err,
+            }),
+        };
+    }
+    Ok(())
+}
+
+/// Allocates `start..end` of the file, returning false if the filesystem can
+/// not do that
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn preallocate(fd: BorrowedFd<'_>, start: u64, end: u64) -> Result<bool, ScanError> {
+    use rustix::fs::FallocateFlags;
+
+    match rustix::fs::fallocate(fd, FallocateFlags::empty(), start, end - start) {
+        Ok(()) => Ok(true),
+        Err(Errno::OPNOTSUPP) => Ok(false),
+        Err(errno) => Err(syscall_error("fallocate", errno)),
+    }
+}
+
+/// Inserts `len` bytes of hole at `offset`, shifting everything after it up
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn insert_range(fd: BorrowedFd<'_>, offset: u64, len: u64) -> Result<(), ScanError> {
+    use rustix::fs::FallocateFlags;
+
+    match rustix::fs::fallocate(fd, FallocateFlags::INSERT_RANGE, offset, len) {
+        Ok(()) => Ok(()),
+        // EOPNOTSUPP means the filesystem can not shift extents around
+        Err(Errno::OPNOTSUPP) => Err(ScanError::UnsupportedFileSystem),
+        Err(errno) => Err(syscall_error("fallocate(FALLOC_FL_INSERT_RANGE)", errno)),
+    }
+}
+
+/// Copies up to `len` bytes from `src_offset` in `src` to `dst_offset` in
+/// `dst` inside the kernel, returning how many bytes were copied, or `None`
+/// if the kernel can not copy between these two files
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn copy_file_range(
+    src: BorrowedFd<'_>,
+    mut src_offset: u64,
+    dst: BorrowedFd<'_>,
+    mut dst_offset: u64,
+    len: u64,
+) -> Result<Option<u64>, ScanError> {
+    let len = usize::try_from(len).unwrap_or(usize::MAX);
+    match rustix::fs::copy_file_range(src, Some(&mut src_offset), dst, Some(&mut dst_offset), len) {
+        Ok(copied) => Ok(Some(copied as u64)),
+        // EXDEV means the files are on different filesystems, or different
+        // types of filesystem since Linux 5.19, ENOSYS that the kernel is too
+        // old to have the call, and some filesystems reject it with
+        // EOPNOTSUPP or EINVAL
+        Err(Errno::XDEV | Errno::NOSYS | Errno::OPNOTSUPP | Errno::INVAL) => Ok(None),
+        Err(errno) => Err(syscall_error("copy_file_range", errno)),
+    }
+}
+
+/// Makes `dst` share the extents of `src`, using `FICLONE`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn clone_file(src: BorrowedFd<'_>, dst: BorrowedFd<'_>) -> Result<(), ScanError> {
+    match rustix::fs::ioctl_ficlone(dst, src) {
+        Ok(()) => Ok(()),
+        // The filesystem can not share extents, or not between these two
+        // files, so the caller needs to copy the data instead
+        Err(Errno::OPNOTSUPP | Errno::XDEV | Errno::NOTTY | Errno::INVAL) => {
+            Err(ScanError::UnsupportedFileSystem)
+        }
+        Err(errno) => Err(syscall_error("ioctl(FICLONE)", errno)),
+    }
+}
+
+/// Makes `len` bytes at `dst_offset` in `dst` share the extents of the same
+/// number of bytes at `src_offset` in `src`, using `FICLONERANGE`
+///
+/// The offsets and length have to be multiples of the block size, apart
+/// from a range that ends at the end of `src`, and a length of zero clones
+/// everything up to the end of `src`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn clone_range(
+    src: BorrowedFd<'_>,
+    src_offset: u64,
+    dst: BorrowedFd<'_>,
+    dst_offset: u64,
+    len: u64,
+) -> Result<(), ScanError> {
+    use rustix::ioctl::{ioctl, opcode, Setter};
+
+    // from linux/fs.h
+    #[repr(C)]
+    struct FileCloneRange {
+        src_fd: i64,
+        src_offset: u64,
+        src_length: u64,
+        dest_offset: u64,
+    }
+
+    // from linux/fs.h, _IOW(0x94, 13, struct file_clone_range)
+    const FICLONERANGE: rustix::ioctl::Opcode = opcode::write::<FileCloneRange>(0x94, 13);
+
+    let range = FileCloneRange {
+        src_fd: src.as_raw_fd().into(),
+        src_offset,
+        src_length: len,
+        dest_offset: dst_offset,
+    };
+    // SAFETY: FICLONERANGE takes a pointer to a struct file_clone_range, and
+    // the descriptor in it is borrowed so stays open for the call
+    match unsafe { ioctl(dst, Setter::<FICLONERANGE, _>::new(range)) } {
+        Ok(()) => Ok(()),
+        // The same as for FICLONE
+        Err(Errno::OPNOTSUPP | Errno::XDEV | Errno::NOTTY | Errno::INVAL) => {
+            Err(ScanError::UnsupportedFileSystem)
+        }
+        Err(errno) => Err(syscall_error("ioctl(FICLONERANGE)", errno)),
+    }
+}
+
+/// The size of a block device, which `st_size` does not report
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn device_size(fd: BorrowedFd<'_>) -> Result<u64, ScanError> {
+    use rustix::ioctl::{ioctl, opcode, Getter};
+
+    // from linux/fs.h, _IOR(0x12, 114, size_t)
+    const BLKGETSIZE64: rustix::ioctl::Opcode = opcode::read::<usize>(0x12, 114);
+
+    // SAFETY: BLKGETSIZE64 writes the size of the device as a u64
+    unsafe { ioctl(fd, Getter::<BLKGETSIZE64, u64>::new()) }
+        .map_err(|e| syscall_error("ioctl(BLKGETSIZE64)", e))
+}
+
+/// Asks the filesystem for the extents of the file described by `request`,
+/// filling in the rest of it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn fiemap(
+    fd: BorrowedFd<'_>,
+    request: &mut crate::fiemap::FiemapRequest,
+) -> Result<(), ScanError> {
+    use rustix::ioctl::{ioctl, opcode, Updater};
+
+    // from linux/fs.h, _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: rustix::ioctl::Opcode =
+        opcode::read_write::<crate::fiemap::Fiemap>(b'f', 11);
+
+    // SAFETY: FS_IOC_FIEMAP takes a struct fiemap followed by room for the
+    // number of extents it says, which is what a FiemapRequest is
+    match unsafe { ioctl(fd, Updater::<FS_IOC_FIEMAP, _>::new(request)) } {
+        Ok(()) => Ok(()),
+        // The filesystem does not have extents to map
+        Err(Errno::OPNOTSUPP) => Err(ScanError::UnsupportedFileSystem),
+        Err(errno) => Err(syscall_error("ioctl(FS_IOC_FIEMAP)", errno)),
+    }
+}
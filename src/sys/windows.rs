@@ -0,0 +1,264 @@
+//! Checked wrappers round the Windows API calls the backend uses
+//!
+//! Every call takes a borrowed handle, so it is known to be open for the
+//! length of the call, and every output is fully written by Windows before it
+//! is read.
+use crate::ScanError;
+
+use std::mem::MaybeUninit;
+use std::ops::Range;
+use std::os::windows::io::{AsRawHandle, BorrowedHandle};
+
+use winapi::shared::minwindef::{DWORD, LPVOID};
+use winapi::shared::winerror::{ERROR_INVALID_FUNCTION, ERROR_MORE_DATA};
+use winapi::um::fileapi::{
+    GetDiskFreeSpaceW, GetFileInformationByHandle, GetFileType, GetFinalPathNameByHandleW,
+    GetVolumePathNameW, BY_HANDLE_FILE_INFORMATION, FILE_STANDARD_INFO,
+};
+use winapi::um::ioapiset::DeviceIoControl;
+use winapi::um::minwinbase::FileStandardInfo;
+use winapi::um::winbase::GetFileInformationByHandleEx;
+use winapi::um::winioctl::{FSCTL_QUERY_ALLOCATED_RANGES, FSCTL_SET_SPARSE, FSCTL_SET_ZERO_DATA};
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FileZeroDataInformation {
+    offset: u64,
+    beyond_final_zero: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FileSetSparseBuffer {
+    set_sparse: u8,
+}
+
+/// A range of a file that has space allocated for it, as returned by
+/// `FSCTL_QUERY_ALLOCATED_RANGES`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct FileAllocatedRange {
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+}
+
+/// The lengths from `FILE_STANDARD_INFO`
+pub(crate) struct StandardInformation {
+    /// How much space the file takes up on the disk
+    pub(crate) allocation_size: u64,
+    /// The length of the file
+    pub(crate) end_of_file: u64,
+}
+
+/// What sort of file the handle is for, one of the `FILE_TYPE_*` constants
+pub(crate) fn file_type(handle: BorrowedHandle<'_>) -> DWORD {
+    // SAFETY: GetFileType only reads the handle, which is borrowed so open
+    unsafe { GetFileType(handle.as_raw_handle() as _) }
+}
+
+/// Get the attributes, volume and index of the file
+pub(crate) fn file_information(
+    handle: BorrowedHandle<'_>,
+) -> Result<BY_HANDLE_FILE_INFORMATION, ScanError> {
+    let mut file_info: MaybeUninit<BY_HANDLE_FILE_INFORMATION> = MaybeUninit::zeroed();
+    // SAFETY: the output is the size GetFileInformationByHandle writes
+    let ret =
+        unsafe { GetFileInformationByHandle(handle.as_raw_handle() as _, file_info.as_mut_ptr()) };
+    if ret == 0 {
+        return Err(ScanError::last_os_error("GetFileInformationByHandle"));
+    }
+    // SAFETY: the call succeeded so it has filled in the whole struct
+    Ok(unsafe { file_info.assume_init() })
+}
+
+/// Get the length of the file and how much space it takes up on the disk
+pub(crate) fn standard_information(
+    handle: BorrowedHandle<'_>,
+) -> Result<StandardInformation, ScanError> {
+    let mut info: MaybeUninit<FILE_STANDARD_INFO> = MaybeUninit::zeroed();
+    // SAFETY: the output is the size of the FILE_STANDARD_INFO that
+    // FileStandardInfo asks for
+    let ret = unsafe {
+        GetFileInformationByHandleEx(
+            handle.as_raw_handle() as _,
+            FileStandardInfo,
+            info.as_mut_ptr() as LPVOID,
+            std::mem::size_of::<FILE_STANDARD_INFO>() as DWORD,
+        )
+    };
+    if ret == 0 {
+        return Err(ScanError::last_os_error(
+            "GetFileInformationByHandleEx(FileStandardInfo)",
+        ));
+    }
+    // SAFETY: the call succeeded so it has filled in the whole struct, and
+    // QuadPart is the whole of each LARGE_INTEGER
+    unsafe {
+        let info = info.assume_init();
+        Ok(StandardInformation {
+            allocation_size: *info.AllocationSize.QuadPart() as u64,
+            end_of_file: *info.EndOfFile.QuadPart() as u64,
+        })
+    }
+}
+
+/// Find the cluster size of the volume the file is on
+///
+/// `GetDiskFreeSpaceW` only takes the path to the root of a volume, so we
+/// have to go from the handle to the full path, and then from there to the
+/// root of the volume it is on.
+pub(crate) fn cluster_size(handle: BorrowedHandle<'_>) -> Result<u64, ScanError> {
+    let mut path: Vec<u16> = vec![0; 261];
+    loop {
+        // SAFETY: the buffer is as long as we say it is
+        let len = unsafe {
+            GetFinalPathNameByHandleW(
+                handle.as_raw_handle() as _,
+                path.as_mut_ptr(),
+                path.len() as DWORD,
+                0,
+            )
+        } as usize;
+        if len == 0 {
+            return Err(ScanError::last_os_error("GetFinalPathNameByHandleW"));
+        }
+        // If the buffer was too small we get told how big it needs to be
+        if len < path.len() {
+            break;
+        }
+        path.resize(len + 1, 0);
+    }
+
+    let mut root: Vec<u16> = vec![0; path.len()];
+    let mut sectors_per_cluster: DWORD = 0;
+    let mut bytes_per_sector: DWORD = 0;
+    let mut free_clusters: DWORD = 0;
+    let mut total_clusters: DWORD = 0;
+    // SAFETY: the path was null terminated by GetFinalPathNameByHandleW, the
+    // root can not be longer than it, and the rest are plain outputs
+    unsafe {
+        if GetVolumePathNameW(path.as_ptr(), root.as_mut_ptr(), root.len() as DWORD) == 0 {
+            return Err(ScanError::last_os_error("GetVolumePathNameW"));
+        }
+        if GetDiskFreeSpaceW(
+            root.as_ptr(),
+            &mut sectors_per_cluster,
+            &mut bytes_per_sector,
+            &mut free_clusters,
+            &mut total_clusters,
+        ) == 0
+        {
+            return Err(ScanError::last_os_error("GetDiskFreeSpaceW"));
+        }
+    }
+    Ok(sectors_per_cluster as u64 * bytes_per_sector as u64)
+}
+
+/// Mark the file as sparse, so that zeroed ranges are deallocated, or clear
+/// the mark, which allocates any ranges that are not
+pub(crate) fn set_sparse(handle: BorrowedHandle<'_>, sparse: bool) -> Result<(), ScanError> {
+    let query = FileSetSparseBuffer {
+        set_sparse: sparse as u8,
+    };
+    device_io_control(handle, FSCTL_SET_SPARSE, &query, &mut [] as &mut [()])?;
+    Ok(())
+}
+
+/// Zero `start..end` of the file, which deallocates it if the file is sparse
+pub(crate) fn set_zero_data(
+    handle: BorrowedHandle<'_>,
+    start: u64,
+    end: u64,
+) -> Result<(), ScanError> {
+    let query = FileZeroDataInformation {
+        offset: start,
+        beyond_final_zero: end,
+    };
+    device_io_control(handle, FSCTL_SET_ZERO_DATA, &query, &mut [] as &mut [()])?;
+    Ok(())
+}
+
+/// Fills `buffer`, up to its capacity, with the allocated ranges in `window`
+/// of the file
+///
+/// Returns true if there were more ranges than would fit.
+pub(crate) fn query_allocated_ranges(
+    handle: BorrowedHandle<'_>,
+    window: Range<u64>,
+    buffer: &mut Vec<FileAllocatedRange>,
+) -> Result<bool, ScanError> {
+    let query = FileAllocatedRange {
+        offset: window.start,
+        length: window.end - window.start,
+    };
+    buffer.clear();
+    let spare = buffer.spare_capacity_mut();
+    let (returned, more) = device_io_control(handle, FSCTL_QUERY_ALLOCATED_RANGES, &query, spare)?;
+    // SAFETY: Windows filled in the first `returned` ranges, and that is
+    // never more than the spare capacity we gave it
+    unsafe { buffer.set_len(returned) };
+    Ok(more)
+}
+
+/// a wrapper round DeviceIoControl
+///
+/// `Q` and `R` must be the plain `#[repr(C)]` structs that `control_code`
+/// takes and gives back, which is why this is private. Returns how many
+/// whole `R`s were written to the start of `result`, and whether there was
+/// more output than would fit.
+fn device_io_control<Q, R>(
+    handle: BorrowedHandle<'_>,
+    control_code: DWORD,
+    query: &Q,
+    result: &mut [R],
+) -> Result<(usize, bool), ScanError> {
+    let mut returned_bytes: DWORD = 0;
+    let capacity = std::mem::size_of_val(result);
+
+    // SAFETY: both buffers are valid for the sizes we give, and the call is
+    // synchronous so nothing writes to them after it returns
+    let ret = unsafe {
+        DeviceIoControl(
+            handle.as_raw_handle() as _,
+            control_code,
+            query as *const Q as LPVOID,
+            std::mem::size_of::<Q>() as DWORD,
+            result.as_mut_ptr() as LPVOID,
+            capacity as DWORD,
+            &mut returned_bytes,
+            std::ptr::null_mut(),
+        )
+    };
+    // Never trust the OS to have stayed inside the buffer
+    let returned = match std::mem::size_of::<R>() {
+        0 => 0,
+        size => (returned_bytes as usize / size).min(result.len()),
+    };
+
+    if ret == 0 {
+        let err = std::io::Error::last_os_error();
+        match err.raw_os_error().map(|code| code as DWORD) {
+            // The output buffer is still filled as far as it can be
+            Some(ERROR_MORE_DATA) => return Ok((returned, true)),
+            // Filesystems like FAT do not support sparse files at all
+            Some(ERROR_INVALID_FUNCTION) => return Err(ScanError::UnsupportedFileSystem),
+            _ => {}
+        }
+        return Err(ScanError::Syscall {
+            call: fsctl_name(control_code),
+            source: err,
+        });
+    }
+
+    Ok((returned, false))
+}
+
+/// The name of a DeviceIoControl call for error messages
+fn fsctl_name(control_code: DWORD) -> &'static str {
+    match control_code {
+        FSCTL_QUERY_ALLOCATED_RANGES => "DeviceIoControl(FSCTL_QUERY_ALLOCATED_RANGES)",
+        FSCTL_SET_SPARSE => "DeviceIoControl(FSCTL_SET_SPARSE)",
+        FSCTL_SET_ZERO_DATA => "DeviceIoControl(FSCTL_SET_ZERO_DATA)",
+        _ => "DeviceIoControl",
+    }
+}
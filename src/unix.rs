@@ -3,140 +3,397 @@
 use super::*;
 
 use std::fs::File;
-use std::io::Error;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::AsFd;
 
-use errno::errno;
-use libc::{c_int, lseek, off_t, EINVAL, ENXIO, SEEK_END};
+#[cfg(target_os = "macos")]
+use crate::macos;
 
-cfg_if::cfg_if! {
-    // libc module for macos is missing these, values stolen from _seek_set.h
-    if #[cfg(target_os = "macos")]{
-        const SEEK_HOLE: c_int  = 3;
-        const SEEK_DATA: c_int  = 4;
-    } else {
-        use libc::{SEEK_DATA, SEEK_HOLE};
+impl SparseFile for &File {
+    fn scan_chunks(&mut self) -> Result<Vec<Segment>, ScanError> {
+        self.scan_chunks_with(|_| ControlFlow::Continue(()))
     }
-}
 
-impl SparseFile for File {
-    fn scan_chunks(&mut self) -> Result<Vec<Segment>, ScanError> {
-        // Create our output vec
-        let mut tags: Vec<Segment> = Vec::new();
-        // Extract the raw fd from the file
-        let fd = self.as_raw_fd();
-        // Find the end
-        let end = safe_lseek(fd, 0, SEEK_END)?.unwrap_or(0);
+    fn segments(&mut self) -> Result<ScanIter<'_>, ScanError> {
+        segments(self, 0..u64::MAX)
+    }
 
-        if end == 0 {
-            return Ok(vec![]);
+    fn scan_range(&mut self, start: u64, end: u64) -> Result<Vec<Segment>, ScanError> {
+        if start > end {
+            return Err(ScanError::InvalidSegment { start, end });
         }
+        segments(self, start..end)?.collect()
+    }
+
+    fn next_data(&mut self, from: u64) -> Result<Option<u64>, ScanError> {
+        next_offset(self, from, true)
+    }
+
+    fn next_hole(&mut self, from: u64) -> Result<Option<u64>, ScanError> {
+        next_offset(self, from, false)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn scan_extents(&mut self) -> Result<Vec<Segment>, ScanError> {
+        let len = self.metadata()?.len();
+        crate::fiemap::scan_extents(self.as_fd(), len)
+    }
 
-        // Our seeking loop assumes that we know what type the previous segment
-        // is, so grab the first hole and if it does not exist or is not at the
-        // start add then the file starts with a data block.
-        let mut last_seek = safe_lseek(fd, 0, SEEK_HOLE)?.unwrap_or(end);
-        let mut last_type = SegmentType::Hole;
-        if last_seek > 0 {
-            tags.push(Segment {
-                segment_type: SegmentType::Data,
-                range: 0..last_seek,
-            })
+    fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
+        if start > end {
+            return Err(ScanError::InvalidSegment { start, end });
         }
+        sys::punch_hole(self.as_fd(), start, end)
+    }
+
+    fn materialize(&mut self) -> Result<(), ScanError> {
+        let segments = self.scan_chunks()?;
+        keep_position(self, |file| {
+            for hole in segments.holes() {
+                if !preallocate(file, hole)? {
+                    write_zeros(file, hole.clone())?;
+                }
+            }
+            Ok(())
+        })
+    }
 
-        while last_seek < end {
-            let seek_type = match last_type {
-                SegmentType::Hole => SEEK_DATA,
-                SegmentType::Data => SEEK_HOLE,
-            };
+    fn scan_chunks_cached_caps(
+        &mut self,
+        cache: &CapabilityCache,
+    ) -> Result<Vec<Segment>, ScanError> {
+        use std::os::unix::fs::MetadataExt;
+
+        let device = self.metadata()?.dev();
+        cache.scan(device, self, |file| {
+            native_segments(file, 0..u64::MAX)?.collect()
+        })
+    }
+
+    fn block_size(&self) -> Result<u64, ScanError> {
+        sys::block_size(self.as_fd())
+    }
+
+    fn is_sparse(&mut self) -> Result<bool, ScanError> {
+        Ok(self.allocation_info()?.sparse)
+    }
 
-            let next_seek = safe_lseek(fd, last_seek, seek_type)?.unwrap_or(end);
-            tags.push(Segment {
-                segment_type: last_type,
-                range: last_seek..next_seek,
+    fn allocation_info(&mut self) -> Result<AllocationInfo, ScanError> {
+        use std::os::unix::fs::MetadataExt;
+
+        // st_blocks is always in units of 512 bytes, whatever the block size
+        let metadata = self.metadata()?;
+        let allocated_size = metadata.blocks().saturating_mul(512);
+        Ok(AllocationInfo {
+            len: metadata.size(),
+            allocated_size,
+            sparse: allocated_size < metadata.size(),
+            compressed: false,
+            encrypted: false,
+        })
+    }
+
+    fn max_scannable_offset(&mut self) -> Result<u64, ScanError> {
+        // lseek works with signed offsets, so this is the limit no matter
+        // what the filesystem says
+        let max = i64::MAX as u64;
+
+        // FILESIZEBITS includes the sign bit
+        match sys::file_size_bits(self.as_fd())? {
+            Some(bits @ 2..=63) => Ok(((1_u64 << (bits - 1)) - 1).min(max)),
+            _ => Ok(max),
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn insert_range(&mut self, offset: u64, len: u64) -> Result<(), ScanError> {
+        let file_len = self.metadata()?.len();
+        let end = offset.saturating_add(len);
+        // You can only insert into the middle of a file, to add to the end
+        // you should just set the length instead
+        if offset >= file_len {
+            return Err(ScanError::OutOfBounds {
+                start: offset,
+                end,
+                len: file_len,
+            });
+        }
+
+        let fd = self.as_fd();
+        let block_size = sys::block_size(fd)?;
+        if offset % block_size != 0 || len % block_size != 0 {
+            return Err(ScanError::Misaligned {
+                start: offset,
+                end,
+                block_size,
             });
-            last_seek = next_seek;
-            last_type = last_type.opposite();
         }
-        Ok(tags)
+
+        // fallocate rejects zero length ranges, but there is nothing to do anyway
+        if len == 0 {
+            return Ok(());
+        }
+
+        sys::insert_range(fd, offset, len)
     }
+}
 
-    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd",))]
-    fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
-        unsafe {
-            use libc::{fallocate, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
-            use std::io::Error;
-            use std::os::unix::io::AsRawFd;
-
-            if fallocate(
-                self.as_raw_fd(),
-                FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
-                start as libc::off_t,
-                (end - start) as libc::off_t,
-            ) < 0
-            {
-                return Err(Error::last_os_error().into());
-            }
+/// Allocates `range` of the file with `fallocate`, returning false if the
+/// filesystem can not do that
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn preallocate(file: &File, range: &Range<u64>) -> Result<bool, ScanError> {
+    sys::preallocate(file.as_fd(), range.start, range.end)
+}
+
+/// There is no way to preallocate a range without changing the length of
+/// the file here, so the zeros have to be written
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn preallocate(_file: &File, _range: &Range<u64>) -> Result<bool, ScanError> {
+    Ok(false)
+}
+
+/// Makes `dst` a copy of `src` that shares its extents, using `FICLONE`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn clone_file(src: &File, dst: &File) -> Result<(), ScanError> {
+    sys::clone_file(src.as_fd(), dst.as_fd())
+}
+
+/// The segments of the file in `window`, falling back to reading the file
+/// if the filesystem can not tell us where the holes are
+pub(crate) fn segments(file: &File, window: Range<u64>) -> Result<ScanIter<'_>, ScanError> {
+    match native_segments(file, window.clone()) {
+        Err(ScanError::UnsupportedFileSystem) => {
+            let mut file = file;
+            Ok(ScanIter::from_vec(fallback::read_window(
+                &mut file, window,
+            )?))
         }
-        Ok(())
+        result => result,
     }
+}
 
+/// Asks the OS for the segments of the file in `window`, without falling
+/// back to reading it if the filesystem does not support that
+fn native_segments(file: &File, window: Range<u64>) -> Result<ScanIter<'_>, ScanError> {
+    if let Some(len) = block_device_len(file)? {
+        return Ok(ScanIter::from_vec(layout::clip(
+            device_segments(file, len),
+            window,
+        )));
+    }
+    // One FIEMAP call can replace hundreds of seeks
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(segments) =
+        crate::fiemap::scan_layout(file.as_fd(), file.metadata()?.len(), window.clone())?
+    {
+        return Ok(ScanIter::from_vec(segments));
+    }
+    let result = SeekHoles::new(file, window.clone()).map(|holes| {
+        let window = window.start..holes.end;
+        ScanIter::new(layout::holes_to_segments(holes, window))
+    });
     #[cfg(target_os = "macos")]
-    fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
-        use libc::fcntl;
-        use std::os::unix::io::AsRawFd;
-
-        #[repr(C)]
-        struct fpunchhole_t {
-            fp_flags: c_int, /* unused */
-            reserved: c_int, /* (to maintain 8-byte alignment) */
-            fp_offset: u64,  /* IN: start of the region */
-            fp_length: u64,  /* IN: size of the region */
+    let result = {
+        let len = file.metadata()?.len();
+        macos::dense_if_unsupported(result, window.start..window.end.min(len))
+    };
+    result
+}
+
+/// Checks that the file is something that can be scanned before seeking
+/// around in it, returning the size of the device for block devices
+///
+/// Pipes and sockets can not be seeked at all, and other special files, such
+/// as directories and character devices, have no segments to report.
+fn block_device_len(file: &File) -> Result<Option<u64>, ScanError> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = file.metadata()?.file_type();
+    if file_type.is_file() {
+        Ok(None)
+    } else if file_type.is_fifo() || file_type.is_socket() {
+        Err(ScanError::NotSeekable)
+    } else if file_type.is_block_device() {
+        device_size(file).map(Some)
+    } else {
+        Err(ScanError::UnsupportedFileType)
+    }
+}
+
+/// The size of a block device, which `st_size` does not report
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn device_size(file: &File) -> Result<u64, ScanError> {
+    sys::device_size(file.as_fd())
+}
+
+/// The layout of a block device that is `len` bytes long
+///
+/// A loop device has the same holes as the part of the file behind it that
+/// it reads from. Any other device is reported as one data segment, as is a
+/// loop device whose file can not be scanned.
+fn device_segments(file: &File, len: u64) -> Vec<Segment> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(segments) = loop_segments(file, len) {
+        return segments;
+    }
+    let _ = file;
+    vec![Segment::data(0, len)]
+}
+
+/// Scans the part of the file behind the loop device `file` that the device
+/// reads from, returning `None` if it is not a loop device
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn loop_segments(file: &File, len: u64) -> Option<Vec<Segment>> {
+    use std::os::unix::fs::MetadataExt;
+
+    // The kernel only tells us the whole path to the file through sysfs
+    let device = file.metadata().ok()?.rdev();
+    let sysfs = PathBuf::from(format!(
+        "/sys/dev/block/{}:{}/loop",
+        rustix::fs::major(device),
+        rustix::fs::minor(device)
+    ));
+    let path = std::fs::read_to_string(sysfs.join("backing_file")).ok()?;
+    let offset: u64 = std::fs::read_to_string(sysfs.join("offset"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    let backing = File::open(path.trim_end_matches('\n')).ok()?;
+
+    let mut shifted = Vec::new();
+    for segment in segments(&backing, offset..offset.checked_add(len)?).ok()? {
+        let segment = segment.ok()?;
+        let range = segment.range.start - offset..segment.range.end - offset;
+        layout::push_merged(&mut shifted, segment.segment_type, range);
+    }
+    // If the file has been cut short since the device was set up, whatever
+    // the device reads past its end is not known to be a hole
+    let covered = shifted.last().map_or(0, |x| x.range.end);
+    layout::push_merged(&mut shifted, SegmentType::Data, covered..len);
+    Some(shifted)
+}
+
+/// Block devices are only supported on Linux and Android, where their size
+/// can be found with `BLKGETSIZE64`
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn device_size(_file: &File) -> Result<u64, ScanError> {
+    Err(ScanError::UnsupportedFileType)
+}
+
+/// Seeks to the next data or hole at or after `from`, putting the seek
+/// position back afterwards
+fn next_offset(file: &File, from: u64, data: bool) -> Result<Option<u64>, ScanError> {
+    // lseek takes signed offsets, so nothing can be found past them
+    if from > i64::MAX as u64 {
+        return Ok(None);
+    }
+    let fd = file.as_fd();
+    let position = sys::position(fd)?;
+    let next = if data {
+        sys::seek_data(fd, from)
+    } else {
+        sys::seek_hole(fd, from)
+    };
+    sys::restore_position(fd, position);
+    match next {
+        Err(ScanError::UnsupportedFileSystem) => {
+            next_of_type(segments(file, from..u64::MAX)?, data)
         }
+        next => next,
+    }
+}
 
-        // from fcntl.h
-        const F_PUNCHHOLE: c_int = 99;
+/// Walks through the file with `SEEK_HOLE` and `SEEK_DATA` to find its holes
+/// in a window one at a time, for [`layout::holes_to_segments`] to fill in
+/// the data between
+///
+/// The seek position of the file is put back when this is dropped.
+struct SeekHoles<'a> {
+    file: &'a File,
+    /// Where the file was positioned before the scan
+    position: u64,
+    /// The length of the file
+    len: u64,
+    /// The end of the window, never past the end of the file
+    end: u64,
+    /// Where to look for the next hole from
+    offset: u64,
+    /// The start of the next hole, if it has already been found
+    next_hole: Option<u64>,
+}
 
-        let hole = fpunchhole_t {
-            fp_flags: 0,
-            reserved: 0,
-            fp_offset: start,
-            fp_length: (end - start),
+impl<'a> SeekHoles<'a> {
+    fn new(file: &'a File, window: Range<u64>) -> Result<Self, ScanError> {
+        let fd = file.as_fd();
+        let position = sys::position(fd)?;
+        let mut holes = SeekHoles {
+            file,
+            position,
+            len: 0,
+            end: 0,
+            offset: window.start,
+            next_hole: None,
         };
+        // Find the end
+        holes.len = sys::seek_end(fd)?;
+        holes.end = window.end.min(holes.len);
 
-        // Try to punch the hole
-        unsafe {
-            let ret = fcntl(self.as_raw_fd(), F_PUNCHHOLE, &hole);
-            if ret < 0 {
-                return Err(Error::last_os_error().into());
-            }
+        // Look for the first hole straight away, so a filesystem that can not
+        // do this is found out before the scan starts
+        if holes.offset < holes.end {
+            holes.next_hole = Some(sys::seek_hole(fd, holes.offset)?.unwrap_or(holes.len));
         }
-        Ok(())
-    }
-}
-
-fn safe_lseek(fd: c_int, offset: u64, seek_type: c_int) -> Result<Option<u64>, ScanError> {
-    unsafe {
-        let new_offset = lseek(fd, offset as off_t, seek_type);
-        // if the return value of lseek is less than 0, an error has occurred
-        if new_offset < 0 {
-            // find and deref errno, honestly the scariest thing we do here
-            let errno = errno().into();
-            match errno {
-                // EINVAL indicates that the file system does not support
-                // SEEK_HOLE or SEEK_DATA, so we indicate as such
-                EINVAL => Err(ScanError::UnsupportedFileSystem),
-                // ENXIO indicates that the the file offset we are looking for
-                // either doesn't exist, or would be beyond the end of the file.
-                // In our case, this just means there is no next segment, so we
-                // return Ok(none) to indicate as such.
-                ENXIO => Ok(None),
-                // None of the other error codes require special handling, so we
-                // just turn them into an std::io::Error for user friendliness
-                _ => Err(Error::last_os_error().into()),
-            }
-        } else {
-            Ok(Some(new_offset as u64))
+        Ok(holes)
+    }
+
+    fn next_hole(&mut self) -> Result<Option<Range<u64>>, ScanError> {
+        if self.offset >= self.end {
+            return Ok(None);
+        }
+        let fd = self.file.as_fd();
+        let hole = match self.next_hole.take() {
+            Some(hole) => hole,
+            None => sys::seek_hole(fd, self.offset)?.unwrap_or(self.len),
+        };
+        if hole < self.offset {
+            return Err(ScanError::InvalidSegment {
+                start: self.offset,
+                end: hole,
+            });
         }
+        if hole >= self.end {
+            self.offset = self.end;
+            return Ok(None);
+        }
+
+        // The hole has to end somewhere after it starts, or the next seek
+        // would find it again
+        let data = sys::seek_data(fd, hole)?.unwrap_or(self.len);
+        if data <= hole {
+            return Err(ScanError::InvalidSegment {
+                start: hole,
+                end: data,
+            });
+        }
+        self.offset = data;
+        Ok(Some(hole..data))
+    }
+}
+
+impl Iterator for SeekHoles<'_> {
+    type Item = Result<Range<u64>, ScanError>;
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        let next = self.next_hole();
+        if next.is_err() {
+            // Stop after an error
+            self.offset = self.end;
+        }
+        next.transpose()
+    }
+}
+
+impl Drop for SeekHoles<'_> {
+    fn drop(&mut self) {
+        sys::restore_position(self.file.as_fd(), self.position);
     }
 }
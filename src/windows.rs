@@ -1,169 +1,230 @@
 use super::*;
 
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
-use std::os::windows::io::{AsRawHandle, RawHandle};
-
-use winapi::shared::minwindef::{DWORD, LPVOID};
-use winapi::um::fileapi::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION};
-use winapi::um::ioapiset::DeviceIoControl;
-use winapi::um::winioctl::{FSCTL_QUERY_ALLOCATED_RANGES, FSCTL_SET_ZERO_DATA};
-use winapi::um::winnt::FILE_ATTRIBUTE_SPARSE_FILE;
-
-use std::mem::MaybeUninit;
-
-impl SparseFile for File {
-    fn scan_chunks(&mut self) -> std::result::Result<std::vec::Vec<Segment>, ScanError> {
-        // Get the length before doing anything
-        let len = self.seek(SeekFrom::End(0))?;
-        // get the handle from the file
-        let handle = self.as_raw_handle();
-        // First check for an empty file
-        if len == 0 {
-            // Return nothing here, an empty file has no ranges
-            Ok(vec![])
-        } else if is_sparse(handle)? {
-            // Call through and get the allocated ranges
-            let ranges = get_allocated_ranges(handle, len)?;
-            // Make a place to put our segments, and copy over our ranges
-
-            let mut prev_end = 0;
-            let mut segments = Vec::with_capacity(ranges.len() * 2 + 1);
-
-            for range in ranges {
-                let end = range.offset + range.length;
-                if prev_end != range.offset {
-                    segments.push(Segment {
-                        segment_type: SegmentType::Hole,
-                        range: prev_end..range.offset,
-                    });
-                }
-                segments.push(Segment {
-                    segment_type: SegmentType::Data,
-                    range: range.offset..end,
-                });
-                prev_end = end;
-            }
+use std::os::windows::io::{AsHandle, BorrowedHandle};
 
-            // Check to see if we need to add a hole segment at the end
-            if prev_end < len {
-                segments.push(Segment {
-                    segment_type: SegmentType::Hole,
-                    range: prev_end..len,
-                });
-            }
+use winapi::um::winbase::{FILE_TYPE_DISK, FILE_TYPE_PIPE};
+use winapi::um::winnt::{
+    FILE_ATTRIBUTE_COMPRESSED, FILE_ATTRIBUTE_ENCRYPTED, FILE_ATTRIBUTE_SPARSE_FILE,
+};
+
+use crate::sys::FileAllocatedRange;
+
+impl SparseFile for &File {
+    fn scan_chunks(&mut self) -> Result<Vec<Segment>, ScanError> {
+        self.scan_chunks_with(|_| ControlFlow::Continue(()))
+    }
+
+    fn segments(&mut self) -> Result<ScanIter<'_>, ScanError> {
+        segments(self, 0..u64::MAX)
+    }
 
-            Ok(segments)
-        } else {
-            Ok(vec![Segment {
-                segment_type: SegmentType::Data,
-                range: 0..len,
-            }])
+    fn scan_range(&mut self, start: u64, end: u64) -> Result<Vec<Segment>, ScanError> {
+        if start > end {
+            return Err(ScanError::InvalidSegment { start, end });
         }
+        segments(self, start..end)?.collect()
+    }
+
+    fn next_data(&mut self, from: u64) -> Result<Option<u64>, ScanError> {
+        next_of_type(segments(self, from..u64::MAX)?, true)
+    }
+
+    fn next_hole(&mut self, from: u64) -> Result<Option<u64>, ScanError> {
+        next_of_type(segments(self, from..u64::MAX)?, false)
     }
 
     fn drill_hole(&self, start: u64, end: u64) -> Result<(), ScanError> {
-        unsafe {
-            device_io_control(
-                self.as_raw_handle(),
-                FSCTL_SET_ZERO_DATA,
-                &FileZeroDataInformation {
-                    offset: start,
-                    beyond_final_zero: end,
-                },
-                std::ptr::null_mut::<()>(),
-                0,
-            )?;
-        };
-        Ok(())
+        if start > end {
+            return Err(ScanError::InvalidSegment { start, end });
+        }
+        // Zeroing data only frees up the space if the file is marked as sparse
+        let handle = self.as_handle();
+        if !is_sparse(handle)? {
+            sys::set_sparse(handle, true)?;
+        }
+        sys::set_zero_data(handle, start, end)
+    }
+
+    fn materialize(&mut self) -> Result<(), ScanError> {
+        let segments = self.scan_chunks()?;
+        keep_position(self, |file| {
+            for hole in segments.holes() {
+                write_zeros(file, hole.clone())?;
+            }
+            Ok(())
+        })?;
+        sys::set_sparse(self.as_handle(), false)
+    }
+
+    fn scan_chunks_cached_caps(
+        &mut self,
+        cache: &CapabilityCache,
+    ) -> Result<Vec<Segment>, ScanError> {
+        let device = sys::file_information(self.as_handle())?.dwVolumeSerialNumber;
+        cache.scan(device as u64, self, |file| {
+            native_segments(file, 0..u64::MAX)?.collect()
+        })
+    }
+
+    fn block_size(&self) -> Result<u64, ScanError> {
+        sys::cluster_size(self.as_handle())
+    }
+
+    fn is_sparse(&mut self) -> Result<bool, ScanError> {
+        is_sparse(self.as_handle())
+    }
+
+    fn allocation_info(&mut self) -> Result<AllocationInfo, ScanError> {
+        let handle = self.as_handle();
+        let attributes = sys::file_information(handle)?.dwFileAttributes;
+        let standard = sys::standard_information(handle)?;
+        Ok(AllocationInfo {
+            len: standard.end_of_file,
+            allocated_size: standard.allocation_size,
+            sparse: attributes & FILE_ATTRIBUTE_SPARSE_FILE != 0,
+            compressed: attributes & FILE_ATTRIBUTE_COMPRESSED != 0,
+            encrypted: attributes & FILE_ATTRIBUTE_ENCRYPTED != 0,
+        })
+    }
+
+    fn max_scannable_offset(&mut self) -> Result<u64, ScanError> {
+        // The file pointer is a LARGE_INTEGER, so this is as far as we can go
+        Ok(i64::MAX as u64)
     }
 }
 
-// Define some types
-#[repr(C)]
-#[derive(Clone, Copy)]
-struct FileZeroDataInformation {
-    offset: u64,
-    beyond_final_zero: u64,
+/// The segments of the file in `window`, falling back to reading the file
+/// if the filesystem can not tell us where the holes are
+pub(crate) fn segments(file: &File, window: Range<u64>) -> Result<ScanIter<'_>, ScanError> {
+    match native_segments(file, window.clone()) {
+        Err(ScanError::UnsupportedFileSystem) => {
+            let mut file = file;
+            Ok(ScanIter::from_vec(fallback::read_window(
+                &mut file, window,
+            )?))
+        }
+        result => result,
+    }
+}
+
+/// Asks the OS for the segments of the file in `window`, without falling
+/// back to reading it if the filesystem does not support that
+fn native_segments(file: &File, window: Range<u64>) -> Result<ScanIter<'_>, ScanError> {
+    // get the handle from the file
+    let handle = file.as_handle();
+    // Pipes can not be seeked, and consoles and other character devices have
+    // no segments to report
+    match sys::file_type(handle) {
+        FILE_TYPE_DISK => {}
+        FILE_TYPE_PIPE => return Err(ScanError::NotSeekable),
+        _ => return Err(ScanError::UnsupportedFileType),
+    }
+    // The allocated ranges of encrypted files are not to be trusted
+    let attributes = sys::file_information(handle)?.dwFileAttributes;
+    if attributes & FILE_ATTRIBUTE_ENCRYPTED != 0 {
+        return Err(ScanError::Encrypted);
+    }
+    // Get the length before doing anything
+    let metadata = file.metadata()?;
+    if metadata.is_dir() {
+        return Err(ScanError::UnsupportedFileType);
+    }
+    let len = metadata.len();
+    let start = window.start;
+    let end = window.end.min(len);
+    // First check for an empty window
+    if start >= end {
+        // Return nothing here, an empty window has no ranges
+        Ok(ScanIter::from_vec(vec![]))
+    } else if attributes & FILE_ATTRIBUTE_SPARSE_FILE != 0 {
+        // Get the allocated ranges a page at a time, if the whole window has
+        // been punched out there may be none at all and it is just one big
+        // hole
+        let ranges = AllocatedRanges {
+            handle,
+            buffer: Vec::with_capacity(RANGES_PER_CALL),
+            index: 0,
+            offset: start,
+            end,
+            more: true,
+        };
+        Ok(ScanIter::new(layout::allocated_to_segments(
+            ranges,
+            start..end,
+        )))
+    } else {
+        // NTFS writes zeros into any gap in a file that is not marked as
+        // sparse, so there can not be any holes to look for
+        Ok(ScanIter::from_vec(vec![Segment::data(start, end)]))
+    }
 }
 
-// Define some types
-#[repr(C)]
-#[derive(Clone, Copy)]
-struct FileAllocatedRange {
+/// How many ranges to ask for in each call, 16 KiB worth, which is allocated
+/// once and reused for every page of a scan
+const RANGES_PER_CALL: usize = 1024;
+
+/// The portions of a window of a file that contain data, asked for from the
+/// OS a page at a time
+struct AllocatedRanges<'a> {
+    handle: BorrowedHandle<'a>,
+    /// The last page of ranges we were given
+    buffer: Vec<FileAllocatedRange>,
+    /// The next range in the buffer to return
+    index: usize,
+    /// Where to ask for the next page from
     offset: u64,
-    length: u64,
+    /// The end of the window
+    end: u64,
+    /// Whether there are any pages left to ask for
+    more: bool,
 }
 
-/// Get the portions of a file that contain data
-fn get_allocated_ranges(
-    handle: RawHandle,
-    size: u64,
-) -> Result<Vec<FileAllocatedRange>, ScanError> {
-    let mut ranges = Vec::with_capacity(1024);
-
-    unsafe {
-        // Check the returned value
-        // FIXME: WIll error if the user provides a massive file with too many ranges
-        // Really need to check for MORE_DATA and do a loop
-        let returned_bytes = device_io_control(
-            handle,
-            FSCTL_QUERY_ALLOCATED_RANGES,
-            &FileAllocatedRange {
-                offset: 0,
-                length: size,
-            },
-            ranges.as_mut_ptr(),
-            ranges.capacity() * std::mem::size_of::<FileAllocatedRange>(),
-        )?;
-
-        ranges.set_len(returned_bytes / std::mem::size_of::<FileAllocatedRange>());
-    };
-
-    Ok(ranges)
+impl AllocatedRanges<'_> {
+    /// Fill the buffer with the next page of ranges
+    fn next_page(&mut self) -> Result<(), ScanError> {
+        let buffer = &mut self.buffer;
+        let more = sys::query_allocated_ranges(self.handle, self.offset..self.end, buffer)?;
+        self.index = 0;
+
+        // If the buffer filled up, carry on asking from the end of the last
+        // range we were given
+        match buffer.last() {
+            Some(last) if more => {
+                let next = last.offset.saturating_add(last.length);
+                // Stop once the ranges reach the end, or stop making progress
+                self.more = next > self.offset && next < self.end;
+                self.offset = next;
+            }
+            _ => self.more = false,
+        }
+        Ok(())
+    }
 }
 
-/// a wrapper round
-unsafe fn device_io_control<Q: Sized, R: Sized>(
-    handle: RawHandle,
-    control_code: DWORD,
-    query: &Q,
-    result: *mut R,
-    capacity: usize,
-) -> Result<usize, ScanError> {
-    let mut returned_bytes: DWORD = 0;
-
-    let ret = DeviceIoControl(
-        handle as _,
-        control_code,
-        query as *const _ as LPVOID,
-        std::mem::size_of::<Q>() as DWORD,
-        result as LPVOID,
-        capacity as DWORD,
-        &mut returned_bytes,
-        std::ptr::null_mut(),
-    );
-
-    if ret == 0 {
-        return Err(std::io::Error::last_os_error().into());
-    }
-
-    Ok(returned_bytes as usize)
+impl Iterator for AllocatedRanges<'_> {
+    type Item = Result<Range<u64>, ScanError>;
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        loop {
+            if let Some(range) = self.buffer.get(self.index) {
+                self.index += 1;
+                return Some(Ok(range.offset..range.offset.saturating_add(range.length)));
+            }
+            if !self.more {
+                return None;
+            }
+            if let Err(e) = self.next_page() {
+                self.more = false;
+                return Some(Err(e));
+            }
+        }
+    }
 }
 
 /// Check if the file is sparse
 ///
 /// This will allow us to skip the nonsense and return a single range if it isn't
-fn is_sparse(handle: RawHandle) -> Result<bool, ScanError> {
-    // Create a space for the file_info to go
-    let mut file_info: MaybeUninit<BY_HANDLE_FILE_INFORMATION> = MaybeUninit::zeroed();
-    // Make the call
-    let ret = unsafe { GetFileInformationByHandle(handle as _, file_info.as_mut_ptr()) };
-    // Check for an error and indicate if there was one
-    if ret == 0 {
-        return Err(std::io::Error::last_os_error().into());
-    }
-    // Now that we have the file info, unwrap it, we would have returned by now if it was still uninitialized
-    let file_info = unsafe { file_info.assume_init() };
+fn is_sparse(handle: BorrowedHandle<'_>) -> Result<bool, ScanError> {
+    let file_info = sys::file_information(handle)?;
     Ok(file_info.dwFileAttributes & FILE_ATTRIBUTE_SPARSE_FILE != 0)
 }
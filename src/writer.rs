@@ -0,0 +1,333 @@
+//! Writing files that leave holes where the data is zeros
+use super::*;
+
+use std::io::{ErrorKind, Write};
+
+/// How much is buffered before it is passed on, rounded down to whole blocks
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Wraps a destination so that blocks of zeros written to it are skipped over
+/// by seeking, leaving holes, rather than written out
+///
+/// This is the other half of [`SparseReader`], so copying a file with
+/// `std::io::copy` into one of these gives a sparse file even if there is no
+/// layout for where the source's holes are. What is written is buffered and
+/// split into blocks of `block_size` bytes, counted from where the writing
+/// starts, no matter how it was split up between calls to `write`. Each run
+/// of blocks that are all zeros is seeked past, and each run of blocks with
+/// data in them is written out with a single write, so it is best to use the
+/// [`block_size`](SparseFile::block_size) of the destination.
+///
+/// Skipping a block only leaves a hole if nothing has been written there
+/// before, so the destination should be empty, or truncated, from where the
+/// writing starts. On Windows the destination also has to be marked as
+/// sparse first, see [`drill_hole`](SparseFile::drill_hole).
+///
+/// Seeking past the end of a file does not make it any longer, so zeros at
+/// the end are only written out by [`finish`](SparseWriter::finish), or by
+/// `flush`, which write the last byte of them.
+/// ```
+/// # use drill_press::*;
+/// # use std::io::{Cursor, Write};
+/// let mut writer = SparseWriter::new(Cursor::new(Vec::new()), 4);
+/// writer.write_all(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0]).unwrap();
+/// let contents = writer.finish().unwrap().into_inner();
+/// assert_eq!(contents, vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0]);
+/// ```
+#[derive(Debug)]
+pub struct SparseWriter<W> {
+    inner: W,
+    block_size: usize,
+    /// What has been written to us but not passed on yet
+    buf: Vec<u8>,
+    /// How far into the output the start of `buf` is
+    pos: u64,
+    /// How many zeros before `buf` have not been seeked past yet
+    zeros: u64,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    /// Creates a writer that leaves a hole in `inner` for every block of
+    /// `block_size` zeros written to it
+    ///
+    /// A `block_size` of zero is taken as one.
+    pub fn new(inner: W, block_size: u64) -> Self {
+        let block_size = usize::try_from(block_size).unwrap_or(usize::MAX).max(1);
+        SparseWriter {
+            inner,
+            block_size,
+            buf: Vec::new(),
+            pos: 0,
+            zeros: 0,
+        }
+    }
+
+    /// Passes on everything that has been written, including any zeros at the
+    /// end, and gets back the underlying writer
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if writing to the underlying writer fails
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.pass_on(true)?;
+        self.write_zeros()?;
+        Ok(self.inner)
+    }
+
+    /// Gets back the underlying writer without passing on anything that is
+    /// still buffered, including any zeros at the end, so it is short by
+    /// however much that was
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Passes on the whole blocks in the buffer, and the part of a block at
+    /// the end too if `all` is set
+    ///
+    /// Whatever is passed on is taken out of the buffer, even if this fails
+    /// part of the way through.
+    fn pass_on(&mut self, all: bool) -> std::io::Result<()> {
+        let mut done = 0;
+        let result = self.pass_on_from(all, &mut done);
+        self.buf.drain(..done);
+        self.pos += done as u64;
+        result
+    }
+
+    /// Does the work of [`pass_on`](SparseWriter::pass_on), counting how much
+    /// of the buffer has been passed on in `done`
+    fn pass_on_from(&mut self, all: bool, done: &mut usize) -> std::io::Result<()> {
+        loop {
+            let (end, zeros) = self.next_run(*done, all);
+            if end == *done {
+                return Ok(());
+            }
+            if zeros {
+                self.zeros += (end - *done) as u64;
+                *done = end;
+                continue;
+            }
+
+            self.skip_zeros()?;
+            while *done < end {
+                match self.inner.write(&self.buf[*done..end]) {
+                    Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                    Ok(n) => *done += n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    /// Finds where the run of blocks in the buffer that starts at `start`
+    /// ends, and whether they are all zeros
+    ///
+    /// A block that was only partly passed on, because a write was cut short,
+    /// is always data. The part of a block at the end of the buffer is left
+    /// out unless `all` is set.
+    fn next_run(&self, start: usize, all: bool) -> (usize, bool) {
+        let block_size = self.block_size as u64;
+        let mut end = start;
+        let mut run_zeros = None;
+        while end < self.buf.len() {
+            let offset = self.pos + end as u64;
+            let block_end = end.saturating_add((block_size - offset % block_size) as usize);
+            if block_end > self.buf.len() && !all {
+                break;
+            }
+            let block = &self.buf[end..block_end.min(self.buf.len())];
+            let zeros = offset % block_size == 0 && block.iter().all(|x| *x == 0);
+            match run_zeros {
+                Some(run_zeros) if run_zeros != zeros => break,
+                _ => run_zeros = Some(zeros),
+            }
+            end += block.len();
+        }
+        (end, run_zeros.unwrap_or(false))
+    }
+
+    /// Catches the inner writer up with the zeros that have been passed on,
+    /// seeking past them and writing the last byte of them so the file is as
+    /// long as it should be
+    fn write_zeros(&mut self) -> std::io::Result<()> {
+        if self.zeros == 0 {
+            return Ok(());
+        }
+        self.zeros -= 1;
+        self.skip_zeros()?;
+        self.inner.write_all(&[0])
+    }
+
+    /// Seeks past the zeros that have been passed on
+    fn skip_zeros(&mut self) -> std::io::Result<()> {
+        if self.zeros == 0 {
+            return Ok(());
+        }
+        let offset = i64::try_from(self.zeros).map_err(|_| {
+            std::io::Error::new(ErrorKind::InvalidInput, "run of zeros is too long")
+        })?;
+        self.inner.seek(SeekFrom::Current(offset))?;
+        self.zeros = 0;
+        Ok(())
+    }
+}
+
+/// How much to buffer for blocks of `block_size`, at least one block
+fn buffer_size(block_size: usize) -> usize {
+    (BUFFER_SIZE / block_size).max(1).saturating_mul(block_size)
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let capacity = buffer_size(self.block_size);
+        if self.buf.len() >= capacity {
+            self.pass_on(false)?;
+        }
+        // Nothing from `buf` has been taken until now, so an error above
+        // leaves it to be written again
+        let taken = buf.len().min(capacity.saturating_sub(self.buf.len()));
+        self.buf.extend_from_slice(&buf[..taken]);
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.pass_on(true)?;
+        self.write_zeros()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Records each write and seek made to it
+    #[derive(Default)]
+    struct Recorder {
+        cursor: Cursor<Vec<u8>>,
+        calls: Vec<(bool, u64)>,
+        /// How many bytes can be written before writes fail
+        fail_after: Option<usize>,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let buf = match self.fail_after {
+                Some(0) => return Err(ErrorKind::Other.into()),
+                Some(left) => {
+                    let buf = &buf[..buf.len().min(left)];
+                    self.fail_after = Some(left - buf.len());
+                    buf
+                }
+                None => buf,
+            };
+            self.calls.push((true, buf.len() as u64));
+            self.cursor.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Recorder {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            let before = self.cursor.position();
+            let after = self.cursor.seek(pos)?;
+            self.calls.push((false, after - before));
+            Ok(after)
+        }
+    }
+
+    #[test]
+    fn zeros_across_writes() {
+        let mut writer = SparseWriter::new(Recorder::default(), 2);
+        for buf in [
+            &[1, 0][..],
+            &[0],
+            &[0, 2, 0],
+            &[0, 0],
+            &[0, 3],
+            &[],
+            &[0, 0, 0],
+        ] {
+            writer.write_all(buf).unwrap();
+        }
+        let recorder = writer.finish().unwrap();
+        assert_eq!(recorder.cursor.position(), 13);
+        assert_eq!(
+            recorder.cursor.into_inner(),
+            vec![1, 0, 0, 0, 2, 0, 0, 0, 0, 3, 0, 0, 0]
+        );
+        // Blocks are classified whole, so a zero in a block with data in it
+        // is written, and neighbouring blocks are passed on together
+        assert_eq!(
+            recorder.calls,
+            vec![
+                (true, 2),
+                (false, 2),
+                (true, 2),
+                (false, 2),
+                (true, 2),
+                (false, 2),
+                (true, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn one_write_per_run() {
+        let mut writer = SparseWriter::new(Recorder::default(), 4);
+        let mut contents = vec![1; 12];
+        contents.extend([0; 8]);
+        contents.extend([2; 4]);
+        // Split up so that the writes do not line up with the blocks
+        for piece in contents.chunks(3) {
+            writer.write_all(piece).unwrap();
+        }
+        let recorder = writer.finish().unwrap();
+        assert_eq!(recorder.calls, vec![(true, 12), (false, 8), (true, 4)]);
+        assert_eq!(recorder.cursor.into_inner(), contents);
+    }
+
+    #[test]
+    fn failed_writes() {
+        let recorder = Recorder {
+            fail_after: Some(6),
+            ..Recorder::default()
+        };
+        let mut writer = SparseWriter::new(recorder, 4);
+        let capacity = buffer_size(4);
+        assert_eq!(writer.write(&vec![1; capacity + 10]).unwrap(), capacity);
+
+        // The buffer is full, so this has to pass it on first, which fails
+        // without taking any of the new bytes
+        assert!(writer.write(&[2; 10]).is_err());
+        assert_eq!(writer.buf.len(), capacity - 6);
+        assert_eq!(writer.pos, 6);
+
+        // Carrying on picks up from the byte that was not written, with the
+        // rest of its block still written as data
+        writer.inner.fail_after = None;
+        writer.write_all(&[0; 8]).unwrap();
+        let recorder = writer.finish().unwrap();
+        let mut expected = vec![1; capacity];
+        expected.extend([0; 8]);
+        assert_eq!(recorder.cursor.into_inner(), expected);
+    }
+
+    #[test]
+    fn only_zeros() {
+        let mut writer = SparseWriter::new(Cursor::new(Vec::new()), 1);
+        writer.write_all(&[0; 10]).unwrap();
+        assert!(writer.into_inner().into_inner().is_empty());
+
+        let mut writer = SparseWriter::new(Cursor::new(Vec::new()), 0);
+        writer.write_all(&[0; 10]).unwrap();
+        writer.flush().unwrap();
+        writer.write_all(&[0; 5]).unwrap();
+        assert_eq!(writer.finish().unwrap().into_inner(), vec![0; 15]);
+    }
+}